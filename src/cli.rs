@@ -0,0 +1,357 @@
+use std::ops::{Range, RangeInclusive};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use rust_boi::{IllegalOpcodeMode, Model};
+use rust_boi::headless::{ExitCondition, HeadlessOptions, RunLimit};
+
+pub const USAGE: &str = "usage: rust_boi [OPTIONS] <ROM>
+       rust_boi disassemble [--bank N] [--start ADDR] [--end ADDR] [--symbols FILE] <ROM>
+
+options:
+    --bootrom FILE            Bootrom to run before the cartridge
+    --open-bootrom            Run the bundled open source bootrom
+                              Without either, boot is skipped and the cartridge
+                              starts at 0x0100 in the post-boot state
+    --model MODEL             DMG0, DMG, MGB, CGB, SGB or SGB2 (default: picked from the ROM header)
+    --headless                Run without a window and exit when the run limit is
+                              reached, which is the only mode until there's a window
+    --trace FILE              Log the registers before every instruction from 0x0100
+                              on, in the Gameboy Doctor format
+    --trace-pc START-END      Only trace instructions at START to END, inclusive
+    --trace-cycles START-END  Only trace from clock cycle START until END
+    --trace-symbols           End each trace line with the label PC is at, such as
+                              ' ; Main.loop+3', which breaks diffs with other traces
+    --profile FILE            Write the hottest routines and addresses and the CPU
+                              use per frame to FILE when emulation ends
+    --profile-stacks FILE     Write cycles per call stack to FILE in the collapsed
+                              format flame graph tools read
+    --check-stack             Keep a shadow call stack and report returns that don't
+                              go back to their caller or happen with SP moved
+    --stack-region START-END  Also report SP leaving START to END, inclusive
+                              (implies --check-stack)
+    --symbols FILE            RGBDS, No$gmb or BGB symbol file with labels for the
+                              debuggers and disassembly (default: the ROM's path
+                              with a .sym extension, when there is one)
+    --debug                   Start paused in the interactive debugger, reading
+                              commands from standard input ('help' lists them)
+    --gdb PORT                Start paused and wait for a GDB remote protocol
+                              client to connect to 127.0.0.1:PORT
+    --illegal-opcodes MODE    'error' stops with a diagnostic (default), 'lockup'
+                              hangs the CPU like the hardware does
+    --save-dir DIR            Directory for save files and states
+    --load-state FILE         Resume from a save state, relative to the save directory
+    --save-state FILE         Write a save state when emulation ends
+    -h, --help                Print this message
+
+headless options:
+    --frames N | --cycles N | --seconds S
+                              How long to run (default: 60 frames)
+    --screenshot FILE         Write the final frame as a PNG
+    --serial-out FILE         Write everything sent over the serial port
+    --memory-dump FILE        Write a hex dump of RAM and IO registers
+    --expect-serial TEXT      Fail unless the serial output contains TEXT
+    --expect-memory ADDR=VAL  Fail unless memory at ADDR holds VAL
+    --record-movie FILE       Record the buttons held each frame, and state hashes
+    --play-movie FILE         Play back a movie from power on, for as long as it
+                              lasts, failing if it desyncs. BK2 input logs and
+                              VBM movies are read too
+
+disassemble options:
+    --bank N                  ROM bank to list (default: every bank)
+    --start ADDR, --end ADDR  Address range to list, as the CPU sees the bank
+                              (0x0000-0x3fff for bank 0, 0x4000-0x7fff otherwise)
+
+exit status:
+    0 on success, 1 when a headless exit condition fails, 2 on a usage error,
+    3 when the ROM or bootrom can't be loaded and 4 when emulation stops on an
+    error such as an illegal opcode";
+
+/// Window and audio options, turned away until there's a window or audio
+/// output for them to set up.
+const UNSUPPORTED: [&str; 5] = ["--windowed", "--scale", "--no-audio", "--sample-rate", "--volume"];
+
+pub enum Boot {
+    Skip,
+    Open,
+    File(PathBuf),
+}
+
+pub struct Options {
+    pub rom: PathBuf,
+    pub boot: Boot,
+    pub model: Option<Model>,
+    pub trace: Option<PathBuf>,
+    pub trace_pc: Option<RangeInclusive<u16>>,
+    pub trace_cycles: Option<Range<u64>>,
+    pub trace_symbols: bool,
+    pub profile: Option<PathBuf>,
+    pub profile_stacks: Option<PathBuf>,
+    pub check_stack: bool,
+    pub stack_region: Option<RangeInclusive<u16>>,
+    pub symbols: Option<PathBuf>,
+    pub debug: bool,
+    pub gdb_port: Option<u16>,
+    pub illegal_opcodes: IllegalOpcodeMode,
+    pub save_dir: Option<PathBuf>,
+    pub load_state: Option<PathBuf>,
+    pub save_state: Option<PathBuf>,
+    pub run: HeadlessOptions,
+    pub play_movie: Option<PathBuf>,
+}
+
+pub struct DisassembleOptions {
+    pub rom: PathBuf,
+    pub bank: Option<usize>,
+    pub start: Option<u16>,
+    pub end: Option<u16>,
+    pub symbols: Option<PathBuf>,
+}
+
+pub enum Command {
+    Run(Box<Options>),
+    Disassemble(DisassembleOptions),
+    Help,
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value '{}' for {}", value, flag))
+}
+
+fn parse_u16(flag: &str, value: &str) -> Result<u16, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).map_err(|_| format!("Invalid value '{}' for {}", value, flag)),
+        None => parse_value(flag, value),
+    }
+}
+
+fn split_range(flag: &str, value: &str) -> Result<(String, String), String> {
+    match value.split_once('-') {
+        Some((start, end)) => Ok((start.to_string(), end.to_string())),
+        None => Err(format!("Expected START-END for {}, got '{}'", flag, value)),
+    }
+}
+
+fn parse_memory_condition(flag: &str, value: &str) -> Result<ExitCondition, String> {
+    let mut parts = value.splitn(2, '=');
+    let addr = parse_u16(flag, parts.next().unwrap_or(""))?;
+    let expected = parts.next()
+        .ok_or_else(|| format!("Expected ADDR=VALUE for {}, got '{}'", flag, value))
+        .and_then(|expected| parse_u16(flag, expected))?;
+    if expected > 0xff {
+        return Err(format!("Value {:#x} for {} doesn't fit in a byte", expected, flag));
+    }
+    Ok(ExitCondition::MemoryEquals(addr, expected as u8))
+}
+
+fn parse_disassemble<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut rom = None;
+    let mut options = DisassembleOptions { rom: PathBuf::new(), bank: None, start: None, end: None, symbols: None };
+    while let Some(arg) = args.next() {
+        let flag = arg.as_str();
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", flag));
+        match flag {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--bank" => options.bank = Some(parse_value(flag, &value()?)?),
+            "--start" => options.start = Some(parse_u16(flag, &value()?)?),
+            "--end" => options.end = Some(parse_u16(flag, &value()?)?),
+            "--symbols" => options.symbols = Some(value()?.into()),
+            _ if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ if rom.is_some() => return Err(format!("Unexpected argument '{}', only one ROM can be given", flag)),
+            _ => rom = Some(PathBuf::from(flag)),
+        }
+    }
+    options.rom = rom.ok_or_else(|| "No ROM given".to_string())?;
+    Ok(Command::Disassemble(options))
+}
+
+/// Parses the arguments that follow the program name.
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.peekable();
+    if args.peek().map(String::as_str) == Some("disassemble") {
+        args.next();
+        return parse_disassemble(args);
+    }
+
+    let mut rom = None;
+    let mut options = Options {
+        rom: PathBuf::new(),
+        boot: Boot::Skip,
+        model: None,
+        trace: None,
+        trace_pc: None,
+        trace_cycles: None,
+        trace_symbols: false,
+        profile: None,
+        profile_stacks: None,
+        check_stack: false,
+        stack_region: None,
+        symbols: None,
+        debug: false,
+        gdb_port: None,
+        illegal_opcodes: IllegalOpcodeMode::Error,
+        save_dir: None,
+        load_state: None,
+        save_state: None,
+        run: HeadlessOptions {
+            limit: RunLimit::Frames(60),
+            screenshot: None,
+            serial_output: None,
+            memory_dump: None,
+            exit_conditions: Vec::new(),
+            record_movie: None,
+            movie: None,
+        },
+        play_movie: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let flag = arg.as_str();
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", flag));
+        match flag {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--bootrom" => options.boot = Boot::File(value()?.into()),
+            "--open-bootrom" => options.boot = Boot::Open,
+            "--model" => options.model = Some(value()?.parse()?),
+            "--headless" => {}
+            "--trace" => options.trace = Some(value()?.into()),
+            "--trace-pc" => {
+                let (start, end) = split_range(flag, &value()?)?;
+                options.trace_pc = Some(parse_u16(flag, &start)?..=parse_u16(flag, &end)?);
+            }
+            "--trace-cycles" => {
+                let (start, end) = split_range(flag, &value()?)?;
+                options.trace_cycles = Some(parse_value(flag, &start)?..parse_value(flag, &end)?);
+            }
+            "--trace-symbols" => options.trace_symbols = true,
+            "--profile" => options.profile = Some(value()?.into()),
+            "--profile-stacks" => options.profile_stacks = Some(value()?.into()),
+            "--check-stack" => options.check_stack = true,
+            "--stack-region" => {
+                let (start, end) = split_range(flag, &value()?)?;
+                options.stack_region = Some(parse_u16(flag, &start)?..=parse_u16(flag, &end)?);
+                options.check_stack = true;
+            }
+            "--symbols" => options.symbols = Some(value()?.into()),
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb_port = Some(parse_value(flag, &value()?)?),
+            "--illegal-opcodes" => {
+                options.illegal_opcodes = match value()?.as_str() {
+                    "error" => IllegalOpcodeMode::Error,
+                    "lockup" => IllegalOpcodeMode::Lockup,
+                    other => return Err(format!("Unknown illegal opcode mode '{}', expected error or lockup", other)),
+                };
+            }
+            "--save-dir" => options.save_dir = Some(value()?.into()),
+            "--load-state" => options.load_state = Some(value()?.into()),
+            "--save-state" => options.save_state = Some(value()?.into()),
+            "--frames" => options.run.limit = RunLimit::Frames(parse_value(flag, &value()?)?),
+            "--cycles" => options.run.limit = RunLimit::Cycles(parse_value(flag, &value()?)?),
+            "--seconds" => options.run.limit = RunLimit::Seconds(parse_value(flag, &value()?)?),
+            "--screenshot" => options.run.screenshot = Some(value()?.into()),
+            "--serial-out" => options.run.serial_output = Some(value()?.into()),
+            "--memory-dump" => options.run.memory_dump = Some(value()?.into()),
+            "--expect-serial" => options.run.exit_conditions.push(ExitCondition::SerialContains(value()?)),
+            "--expect-memory" => options.run.exit_conditions.push(parse_memory_condition(flag, &value()?)?),
+            "--record-movie" => options.run.record_movie = Some(value()?.into()),
+            "--play-movie" => options.play_movie = Some(value()?.into()),
+            _ if UNSUPPORTED.contains(&flag) => {
+                return Err(format!("'{}' isn't supported, as there's no window or audio output yet", flag));
+            }
+            _ if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ if rom.is_some() => return Err(format!("Unexpected argument '{}', only one ROM can be given", flag)),
+            _ => rom = Some(PathBuf::from(flag)),
+        }
+    }
+
+    options.rom = rom.ok_or_else(|| "No ROM given".to_string())?;
+    if let Some(save_dir) = &options.save_dir {
+        options.load_state = options.load_state.map(|path| save_dir.join(path));
+        options.save_state = options.save_state.map(|path| save_dir.join(path));
+    }
+    Ok(Command::Run(Box::new(options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_run(args: &[&str]) -> Options {
+        match parse_args(args) {
+            Ok(Command::Run(options)) => *options,
+            Ok(_) => panic!("{:?} didn't parse to a run", args),
+            Err(e) => panic!("{:?}: {}", args, e),
+        }
+    }
+
+    fn parse_error(args: &[&str]) -> String {
+        match parse_args(args) {
+            Err(e) => e,
+            Ok(_) => panic!("{:?} parsed", args),
+        }
+    }
+
+    #[test]
+    fn run_options() {
+        let options = parse_run(&["--model", "CGB", "--open-bootrom", "--stack-region", "0xc000-0xdfff",
+                                  "--trace-pc", "0x100-0x1ff", "--trace-symbols", "--frames", "10", "--expect-memory", "0xc000=0x42",
+                                  "--save-dir", "saves", "--save-state", "end.state", "game.gb"]);
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+        assert!(matches!(options.boot, Boot::Open));
+        assert_eq!(options.model, Some(Model::Cgb));
+        assert!(options.check_stack);
+        assert_eq!(options.stack_region, Some(0xc000..=0xdfff));
+        assert_eq!(options.trace_pc, Some(0x100..=0x1ff));
+        assert!(options.trace_symbols);
+        assert!(matches!(options.run.limit, RunLimit::Frames(10)));
+        assert!(matches!(options.run.exit_conditions[..], [ExitCondition::MemoryEquals(0xc000, 0x42)]));
+        assert_eq!(options.save_state, Some(PathBuf::from("saves").join("end.state")));
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse_run(&["--headless", "game.gb"]);
+        assert!(matches!(options.boot, Boot::Skip));
+        assert_eq!(options.model, None);
+        assert!(!options.check_stack);
+        assert!(matches!(options.run.limit, RunLimit::Frames(60)));
+        assert!(matches!(options.illegal_opcodes, IllegalOpcodeMode::Error));
+    }
+
+    #[test]
+    fn disassemble_options() {
+        match parse_args(&["disassemble", "--bank", "2", "--start", "0x4000", "game.gb"]) {
+            Ok(Command::Disassemble(options)) => {
+                assert_eq!(options.rom, PathBuf::from("game.gb"));
+                assert_eq!((options.bank, options.start, options.end), (Some(2), Some(0x4000), None));
+            }
+            _ => panic!("disassemble didn't parse"),
+        }
+        assert!(matches!(parse_args(&["--frames", "1", "--help"]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn window_and_audio_options_are_rejected() {
+        for args in [&["--windowed"][..], &["--scale", "2"], &["--no-audio"], &["--sample-rate", "44100"], &["--volume", "50"]] {
+            let error = parse_error(&[args, &["game.gb"]].concat());
+            assert!(error.contains("no window or audio"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert_eq!(parse_error(&[]), "No ROM given");
+        assert_eq!(parse_error(&["a.gb", "b.gb"]), "Unexpected argument 'b.gb', only one ROM can be given");
+        assert_eq!(parse_error(&["--bogus", "game.gb"]), "Unknown option '--bogus'");
+        assert_eq!(parse_error(&["game.gb", "--frames"]), "Missing value for --frames");
+        assert_eq!(parse_error(&["--frames", "ten", "game.gb"]), "Invalid value 'ten' for --frames");
+        assert!(parse_error(&["--trace-pc", "0x100", "game.gb"]).starts_with("Expected START-END"));
+        assert!(parse_error(&["--expect-memory", "0xc000=0x100", "game.gb"]).contains("doesn't fit in a byte"));
+        assert!(parse_error(&["--illegal-opcodes", "ignore", "game.gb"]).starts_with("Unknown illegal opcode mode"));
+        assert!(parse_error(&["--model", "GBA", "game.gb"]).contains("GBA"));
+    }
+}
@@ -0,0 +1,376 @@
+use super::cpu::{Cpu, CpuState};
+use super::error::EmulatorError;
+use super::memory::Memory;
+use super::model::Model;
+use super::ppu::Ppu;
+use super::savestate::{StateReader, StateWriter};
+
+/// BESS (Best Effort Save State) is the save state format SameBoy and
+/// several other emulators share. It sits at the end of a file behind each
+/// emulator's own data and is found through an 8 byte footer: the offset of
+/// its first block, then "BESS". Blocks are `id:[u8; 4] length:u32 content`
+/// and large buffers such as RAM are stored elsewhere in the file, referred
+/// to by size and offset.
+///
+/// Only the NAME, INFO, CORE, SGB and END blocks are written. Without an
+/// MBC, RTC or cartridge RAM there's nothing to put in MBC or RTC blocks, so
+/// those are ignored when reading, along with anything else unknown. BESS
+/// has no PPU timing beyond LY, so a loaded state starts at the beginning
+/// of that line.
+const FOOTER_MAGIC: &[u8; 4] = b"BESS";
+const NAME: &str = concat!("rust_boi v", env!("CARGO_PKG_VERSION"));
+const CORE_MAJOR_VERSION: u16 = 1;
+const CORE_MINOR_VERSION: u16 = 1;
+
+const TITLE: std::ops::Range<usize> = 0x134..0x144;
+const GLOBAL_CHECKSUM: std::ops::Range<usize> = 0x14e..0x150;
+const HIGH_RAM_SIZE: usize = 0x7f;
+const LY: usize = 0x44;
+const BANK: usize = 0x50;
+
+fn model_id(model: Model) -> &'static [u8; 4] {
+    match model {
+        Model::Dmg0 => b"GD0 ",
+        Model::Dmg => b"GDB ",
+        Model::Mgb => b"GM  ",
+        Model::Sgb => b"SN  ",
+        Model::Sgb2 => b"S2  ",
+        Model::Cgb => b"CCE ",
+    }
+}
+
+/// Whether `data` ends in a BESS footer.
+pub fn is_bess(data: &[u8]) -> bool {
+    data.ends_with(FOOTER_MAGIC) && data.len() >= 8
+}
+
+fn buffer(state: &mut StateWriter, bytes: &[u8]) -> (u32, u32) {
+    let offset = state.position() as u32;
+    state.raw(bytes);
+    (bytes.len() as u32, offset)
+}
+
+/// Appends the machine in BESS format, buffers first and the footer last.
+pub fn save(state: &mut StateWriter, model: Model, cpu: &Cpu, memory: &Memory) {
+    let [main_ram, video_ram, oam, io, high_ram] = memory.ram();
+    let rom = memory.rom();
+    let ram = buffer(state, main_ram);
+    let vram = buffer(state, video_ram);
+    let oam = buffer(state, oam);
+    let hram = buffer(state, &high_ram[..HIGH_RAM_SIZE]);
+    let sgb = memory.sgb().map(|sgb| {
+        let buffers: Vec<(u32, u32)> = sgb.bess_buffers().iter().map(|bytes| buffer(state, bytes)).collect();
+        (buffers, sgb.bess_multiplayer())
+    });
+
+    let first_block = state.position() as u32;
+    state.section(b"NAME", |state| state.raw(NAME.as_bytes()));
+    state.section(b"INFO", |state| {
+        state.raw(&rom[TITLE]);
+        state.raw(&rom[GLOBAL_CHECKSUM]);
+    });
+    state.section(b"CORE", |state| {
+        state.u16(CORE_MAJOR_VERSION);
+        state.u16(CORE_MINOR_VERSION);
+        state.raw(model_id(model));
+        let registers = cpu.registers();
+        for value in [registers.pc, registers.af(), registers.bc(), registers.de(), registers.hl(), registers.sp].iter() {
+            state.u16(*value);
+        }
+        state.bool(registers.ime);
+        state.u8(high_ram[HIGH_RAM_SIZE]);
+        // A locked up CPU has no BESS equivalent and is saved as running
+        state.u8(match cpu.state() {
+            CpuState::Halted => 1,
+            CpuState::Stopped => 2,
+            _ => 0,
+        });
+        state.u8(0);
+        state.raw(io);
+        // Cartridge RAM and the CGB palettes aren't emulated
+        for (size, offset) in [ram, vram, (0, 0), oam, hram, (0, 0), (0, 0)].iter() {
+            state.u32(*size);
+            state.u32(*offset);
+        }
+    });
+    if let Some((buffers, multiplayer)) = sgb {
+        state.section(b"SGB ", |state| {
+            // RAM palettes and attribute files aren't kept once applied
+            let layout = [buffers[0], buffers[1], buffers[2], buffers[3], (0, 0), buffers[4], (0, 0)];
+            for (size, offset) in layout.iter() {
+                state.u32(*size);
+                state.u32(*offset);
+            }
+            state.u8(multiplayer);
+        });
+    }
+    state.section(b"END ", |_| {});
+
+    state.u32(first_block);
+    state.raw(FOOTER_MAGIC);
+}
+
+/// Finds a buffer a block refers to.
+fn resolve<'a>(data: &'a [u8], block: &mut StateReader) -> Result<&'a [u8], EmulatorError> {
+    let size = block.u32()? as usize;
+    let offset = block.u32()? as usize;
+    match data.get(offset..offset.saturating_add(size)) {
+        Some(buffer) => Ok(buffer),
+        None => Err(block.error("buffer lies outside the file")),
+    }
+}
+
+/// Copies the start of `buffer` into `region`. The CGB has more RAM than
+/// this emulator maps, so only the banks it can see are restored.
+fn restore(region: &mut [u8], buffer: &[u8], block: &StateReader) -> Result<(), EmulatorError> {
+    if buffer.len() < region.len() {
+        return Err(block.error(&format!("buffer of {} bytes, expected {}", buffer.len(), region.len())));
+    }
+    region.copy_from_slice(&buffer[..region.len()]);
+    Ok(())
+}
+
+/// Loads the BESS part of `data`, written by this or any other emulator,
+/// into the given components. States from another ROM or model family are
+/// rejected.
+pub fn load(data: &[u8], model: Model, cpu: &mut Cpu, memory: &mut Memory, ppu: &mut Ppu) -> Result<(), EmulatorError> {
+    let footer = &data[data.len() - 8..data.len() - 4];
+    let first_block = u32::from_le_bytes([footer[0], footer[1], footer[2], footer[3]]) as usize;
+    let mut blocks = StateReader::new("BESS", data.get(first_block..data.len() - 8).unwrap_or(&[]));
+    if blocks.is_empty() {
+        return Err(blocks.error("no blocks"));
+    }
+
+    let mut found_core = false;
+    loop {
+        let id = blocks.take(4)?;
+        let length = blocks.u32()? as usize;
+        let name = std::str::from_utf8(id).unwrap_or("unknown").trim_end();
+        let mut block = StateReader::new(name, blocks.take(length)?);
+        match id {
+            b"END " => break,
+            b"INFO" => {
+                let rom = memory.rom();
+                let title = block.take(TITLE.len())?;
+                let checksum = block.take(GLOBAL_CHECKSUM.len())?;
+                if title != &rom[TITLE] || checksum != &rom[GLOBAL_CHECKSUM] {
+                    return Err(block.error("taken from another ROM"));
+                }
+            }
+            b"CORE" => {
+                load_core(data, &mut block, model, cpu, memory, ppu)?;
+                found_core = true;
+            }
+            b"SGB " => {
+                let buffers = [
+                    resolve(data, &mut block)?, // Border tiles
+                    resolve(data, &mut block)?, // Border tilemap
+                    resolve(data, &mut block)?, // Border palettes
+                    resolve(data, &mut block)?, // Colorization palettes
+                ];
+                resolve(data, &mut block)?; // RAM palettes
+                let attributes = resolve(data, &mut block)?;
+                resolve(data, &mut block)?; // Attribute files
+                let multiplayer = block.u8()?;
+                if let Some(sgb) = memory.sgb_mut() {
+                    let [tiles, map, border_palettes, palettes] = buffers;
+                    sgb.load_bess([tiles, map, border_palettes, palettes, attributes], multiplayer)
+                        .map_err(|reason| block.error(&reason))?;
+                }
+            }
+            _ => debug!("Skipping BESS block {}", name),
+        }
+    }
+
+    if !found_core {
+        return Err(blocks.error("no CORE block"));
+    }
+    Ok(())
+}
+
+fn load_core(data: &[u8], block: &mut StateReader, model: Model,
+             cpu: &mut Cpu, memory: &mut Memory, ppu: &mut Ppu) -> Result<(), EmulatorError> {
+    let major = block.u16()?;
+    block.u16()?; // Minor versions only add things at the end
+    if major != CORE_MAJOR_VERSION {
+        return Err(block.error(&format!("version {} isn't supported", major)));
+    }
+    let family = block.take(4)?[0];
+    if family != model_id(model)[0] {
+        return Err(block.error(&format!("taken on a different family of model, this machine is a {}", model)));
+    }
+
+    let mut registers = cpu.registers();
+    registers.pc = block.u16()?;
+    registers.set_af(block.u16()?);
+    registers.set_bc(block.u16()?);
+    registers.set_de(block.u16()?);
+    registers.set_hl(block.u16()?);
+    registers.sp = block.u16()?;
+    registers.ime = block.bool()?;
+    let ie = block.u8()?;
+    let state = match block.u8()? {
+        0 => CpuState::Running,
+        1 => CpuState::Halted,
+        2 => CpuState::Stopped,
+        _ => return Err(block.error("unknown execution state")),
+    };
+    block.u8()?;
+    let io = block.take(0x80)?;
+    let ram = resolve(data, block)?;
+    let vram = resolve(data, block)?;
+    resolve(data, block)?; // Cartridge RAM
+    let oam = resolve(data, block)?;
+    let hram = resolve(data, block)?;
+
+    registers.halted = state == CpuState::Halted;
+    cpu.set_registers(&registers);
+    cpu.set_state(state);
+
+    let [main_ram, video_ram, oam_region, mapped_io, high_ram] = memory.ram_mut();
+    restore(main_ram, ram, block)?;
+    restore(video_ram, vram, block)?;
+    restore(oam_region, oam, block)?;
+    restore(&mut high_ram[..HIGH_RAM_SIZE], hram, block)?;
+    high_ram[HIGH_RAM_SIZE] = ie;
+    mapped_io.copy_from_slice(io);
+    memory.set_bootrom_mapped(io[BANK] & 0x01 == 0);
+    ppu.set_line(io[LY]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14e..0x150].copy_from_slice(&[0x12, 0x34]);
+        rom
+    }
+
+    /// A machine with something other than zeros everywhere a state covers.
+    fn machine() -> (Cpu, Memory) {
+        let mut cpu = Cpu::new();
+        let mut registers = cpu.registers();
+        registers.pc = 0x0213;
+        registers.sp = 0xdff0;
+        registers.set_af(0x12b0);
+        registers.set_bc(0x3456);
+        registers.set_de(0x789a);
+        registers.set_hl(0xbcde);
+        registers.ime = true;
+        registers.halted = true;
+        cpu.set_registers(&registers);
+        cpu.set_state(CpuState::Halted);
+
+        let mut memory = Memory::new(rom(b"BESS TEST"), None, false).unwrap();
+        for (i, region) in memory.ram_mut().iter_mut().enumerate() {
+            for (j, byte) in region.iter_mut().enumerate() {
+                *byte = (i * 37 + j * 11) as u8;
+            }
+        }
+        // Keep the bootrom unmapped, as there isn't one
+        memory.ram_mut()[3][BANK] = 0x01;
+        (cpu, memory)
+    }
+
+    fn save_state(model: Model, cpu: &Cpu, memory: &Memory) -> Vec<u8> {
+        let mut state = StateWriter::new(0, 0, model);
+        save(&mut state, model, cpu, memory);
+        state.finish()
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize
+    }
+
+    #[test]
+    fn round_trips() {
+        let (cpu, memory) = machine();
+        let data = save_state(Model::Dmg, &cpu, &memory);
+        assert!(is_bess(&data));
+
+        let mut loaded = (Cpu::new(), Memory::new(rom(b"BESS TEST"), None, false).unwrap(), Ppu::new());
+        load(&data, Model::Mgb, &mut loaded.0, &mut loaded.1, &mut loaded.2).unwrap();
+        let mut expected = cpu.registers();
+        expected.cycles = loaded.0.registers().cycles;
+        assert_eq!(loaded.0.registers(), expected);
+        assert_eq!(loaded.0.state(), CpuState::Halted);
+        assert_eq!(loaded.1.ram(), memory.ram());
+        assert!(!loaded.1.bootrom_mapped());
+    }
+
+    #[test]
+    fn footer_points_at_the_blocks() {
+        let (cpu, memory) = machine();
+        let data = save_state(Model::Dmg, &cpu, &memory);
+        assert_eq!(&data[data.len() - 4..], b"BESS");
+
+        let mut offset = u32_at(&data, data.len() - 8);
+        let mut blocks = Vec::new();
+        while offset < data.len() - 8 {
+            let length = u32_at(&data, offset + 4);
+            blocks.push((&data[offset..offset + 4], length, offset + 8));
+            offset += 8 + length;
+        }
+        assert_eq!(offset, data.len() - 8);
+        let ids: Vec<&[u8]> = blocks.iter().map(|(id, _, _)| *id).collect();
+        assert_eq!(ids, [&b"NAME"[..], b"INFO", b"CORE", b"END "]);
+
+        let (_, length, name) = blocks[0];
+        assert_eq!(&data[name..name + length], NAME.as_bytes());
+        let (_, length, info) = blocks[1];
+        let rom = rom(b"BESS TEST");
+        assert_eq!(&data[info..info + length], [&rom[TITLE], &rom[GLOBAL_CHECKSUM]].concat());
+        let (_, length, core) = blocks[2];
+        assert_eq!(length, 0xd0);
+        assert_eq!(&data[core + 4..core + 8], b"GDB ");
+        assert_eq!(blocks[3].1, 0);
+
+        // RAM, VRAM, cartridge RAM, OAM and HRAM buffers, in that order
+        let buffer = |index: usize| {
+            let at = core + 0x98 + index * 8;
+            let (size, offset) = (u32_at(&data, at), u32_at(&data, at + 4));
+            &data[offset..offset + size]
+        };
+        let [main_ram, video_ram, oam, _, high_ram] = memory.ram();
+        assert_eq!(buffer(0), main_ram);
+        assert_eq!(buffer(1), video_ram);
+        assert!(buffer(2).is_empty());
+        assert_eq!(buffer(3), oam);
+        assert_eq!(buffer(4), &high_ram[..HIGH_RAM_SIZE]);
+    }
+
+    #[test]
+    fn other_roms_and_families_are_rejected() {
+        let (cpu, memory) = machine();
+        let data = save_state(Model::Dmg, &cpu, &memory);
+
+        let mut other = (Cpu::new(), Memory::new(rom(b"OTHER GAME"), None, false).unwrap(), Ppu::new());
+        assert!(load(&data, Model::Dmg, &mut other.0, &mut other.1, &mut other.2).is_err());
+
+        let mut cgb = (Cpu::new(), Memory::new(rom(b"BESS TEST"), None, false).unwrap(), Ppu::new());
+        assert!(load(&data, Model::Cgb, &mut cgb.0, &mut cgb.1, &mut cgb.2).is_err());
+    }
+
+    #[test]
+    fn damaged_states_are_rejected() {
+        let (cpu, memory) = machine();
+        let data = save_state(Model::Dmg, &cpu, &memory);
+        let mut fresh = (Cpu::new(), Memory::new(rom(b"BESS TEST"), None, false).unwrap(), Ppu::new());
+
+        // A footer pointing past the blocks
+        let mut broken = data.clone();
+        let end = broken.len() - 8;
+        broken[end..end + 4].copy_from_slice(&(end as u32).to_le_bytes());
+        assert!(load(&broken, Model::Dmg, &mut fresh.0, &mut fresh.1, &mut fresh.2).is_err());
+
+        // A block running past the end
+        let mut broken = data.clone();
+        let first = u32_at(&data, end);
+        broken[first + 4..first + 8].copy_from_slice(&0xffffu32.to_le_bytes());
+        assert!(load(&broken, Model::Dmg, &mut fresh.0, &mut fresh.1, &mut fresh.2).is_err());
+    }
+}
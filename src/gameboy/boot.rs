@@ -0,0 +1,189 @@
+use super::cartridge::Header;
+use super::cpu::Cpu;
+use super::memory::DMG_BOOTROM_SIZE;
+use super::model::Model;
+
+/// Our own replacement for the DMG bootrom, free to redistribute. It skips
+/// the logo and the header checks, clears VRAM, sets up the LCD and leaves
+/// the registers the way the DMG bootrom does.
+const OPEN_BOOTROM_PROGRAM: [u8; 38] = [
+    0x31, 0xfe, 0xff, // LD SP, $fffe
+    0xaf,             // XOR A
+    0x21, 0xff, 0x9f, // LD HL, $9fff
+    0x32,             // .clear_vram: LD (HL-), A
+    0xcb, 0x7c,       // BIT 7, H
+    0x20, 0xfb,       // JR NZ, .clear_vram
+    0x3e, 0x80,       // LD A, $80
+    0xe0, 0x26,       // LDH ($26), A  ; NR52, sound on
+    0x3e, 0xfc,       // LD A, $fc
+    0xe0, 0x47,       // LDH ($47), A  ; BGP
+    0x3e, 0x91,       // LD A, $91
+    0xe0, 0x40,       // LDH ($40), A  ; LCDC, LCD and background on
+    0x01, 0xb0, 0x01, // LD BC, $01b0
+    0xc5,             // PUSH BC
+    0xf1,             // POP AF
+    0x01, 0x13, 0x00, // LD BC, $0013
+    0x11, 0xd8, 0x00, // LD DE, $00d8
+    0x21, 0x4d, 0x01, // LD HL, $014d
+];
+
+/// The last instruction sits at 0x00fe so that PC lands on 0x0100 right
+/// after the bootrom unmaps itself.
+const OPEN_BOOTROM_EXIT: [u8; 2] = [
+    0xe0, 0x50, // LDH ($50), A  ; A is $01
+];
+
+pub fn open_bootrom() -> Vec<u8> {
+    let mut bootrom = vec![0x00; DMG_BOOTROM_SIZE]; // Padded with NOPs
+    bootrom[..OPEN_BOOTROM_PROGRAM.len()].copy_from_slice(&OPEN_BOOTROM_PROGRAM);
+    bootrom[DMG_BOOTROM_SIZE - OPEN_BOOTROM_EXIT.len()..].copy_from_slice(&OPEN_BOOTROM_EXIT);
+    bootrom
+}
+
+/// IO registers as the DMG bootrom leaves them, as offsets from 0xff00.
+const DMG_IO: [(usize, u8); 35] = [
+    (0x00, 0xcf), // P1
+    (0x01, 0x00), // SB
+    (0x02, 0x7e), // SC
+    (0x04, 0xab), // DIV
+    (0x05, 0x00), // TIMA
+    (0x06, 0x00), // TMA
+    (0x07, 0xf8), // TAC
+    (0x0f, 0xe1), // IF
+    (0x10, 0x80), // NR10
+    (0x11, 0xbf), // NR11
+    (0x12, 0xf3), // NR12
+    (0x13, 0xff), // NR13
+    (0x14, 0xbf), // NR14
+    (0x16, 0x3f), // NR21
+    (0x17, 0x00), // NR22
+    (0x18, 0xff), // NR23
+    (0x19, 0xbf), // NR24
+    (0x1a, 0x7f), // NR30
+    (0x1b, 0xff), // NR31
+    (0x1c, 0x9f), // NR32
+    (0x1d, 0xff), // NR33
+    (0x1e, 0xbf), // NR34
+    (0x20, 0xff), // NR41
+    (0x21, 0x00), // NR42
+    (0x22, 0x00), // NR43
+    (0x23, 0xbf), // NR44
+    (0x24, 0x77), // NR50
+    (0x25, 0xf3), // NR51
+    (0x26, 0xf1), // NR52
+    (0x40, 0x91), // LCDC
+    (0x41, 0x85), // STAT
+    (0x46, 0xff), // DMA
+    (0x47, 0xfc), // BGP
+    (0x4d, 0xff), // KEY1
+    (0x50, 0x01), // BANK, bootrom unmapped
+];
+
+/// Registers that only exist on the CGB and read back differently there.
+const CGB_IO: [(usize, u8); 9] = [
+    (0x02, 0x7f), // SC
+    (0x46, 0x00), // DMA
+    (0x4d, 0x7e), // KEY1
+    (0x4f, 0xfe), // VBK
+    (0x55, 0xff), // HDMA5
+    (0x56, 0x3e), // RP
+    (0x6c, 0xfe), // OPRI
+    (0x70, 0xf8), // SVBK
+    (0x04, 0x00), // DIV
+];
+
+/// The CPU as the bootrom for `model` leaves it when it jumps to 0x0100.
+/// A CGB running a cartridge without CGB support ends up in DMG
+/// compatibility mode, which has its own set of values.
+pub fn post_boot_cpu(model: Model, header: &Header) -> Cpu {
+    // The DMG and MGB bootroms leave H and C set from the header checksum
+    let checksum_flags = if header.checksum == 0 { 0x80 } else { 0xb0 };
+    let (af, bc, de, hl) = match model {
+        Model::Dmg0 => (0x0100, 0xff13, 0x00c1, 0x8403),
+        Model::Dmg => (0x0100 | checksum_flags, 0x0013, 0x00d8, 0x014d),
+        Model::Mgb => (0xff00 | checksum_flags, 0x0013, 0x00d8, 0x014d),
+        Model::Sgb => (0x0100, 0x0014, 0x0000, 0xc060),
+        Model::Sgb2 => (0xff00, 0x0014, 0x0000, 0xc060),
+        Model::Cgb if !header.cgb_support => (0x1180, 0x0000, 0x0008, 0x007c),
+        Model::Cgb => (0x1180, 0x0000, 0xff56, 0x000d),
+    };
+    Cpu::with_registers(af, bc, de, hl, 0xfffe, 0x0100)
+}
+
+/// IO register values (offset from 0xff00, value) left by the bootrom.
+pub fn post_boot_io(model: Model) -> Vec<(usize, u8)> {
+    let mut registers = DMG_IO.to_vec();
+    let overrides: &[(usize, u8)] = match model {
+        Model::Dmg0 => &[(0x04, 0x18), (0x41, 0x81)],
+        Model::Dmg | Model::Mgb => &[],
+        Model::Sgb | Model::Sgb2 => &[(0x04, 0x00), (0x26, 0xf0)],
+        Model::Cgb => &CGB_IO,
+    };
+    registers.extend_from_slice(overrides);
+    registers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(cgb_support: bool, checksum: u8) -> Header {
+        Header { title: String::new(), cgb_support, sgb_support: false, cartridge_type: 0x00, checksum }
+    }
+
+    /// What reading `offset` gives back once every value has been written.
+    fn io(model: Model, offset: usize) -> u8 {
+        post_boot_io(model).iter().rev().find(|(register, _)| *register == offset).unwrap().1
+    }
+
+    #[test]
+    fn open_bootrom_unmaps_itself_from_its_last_bytes() {
+        let bootrom = open_bootrom();
+        assert_eq!(bootrom.len(), DMG_BOOTROM_SIZE);
+        assert_eq!(bootrom[..OPEN_BOOTROM_PROGRAM.len()], OPEN_BOOTROM_PROGRAM);
+        assert!(bootrom[OPEN_BOOTROM_PROGRAM.len()..0xfe].iter().all(|byte| *byte == 0x00));
+        assert_eq!(bootrom[0xfe..], [0xe0, 0x50]);
+    }
+
+    #[test]
+    fn post_boot_registers_depend_on_the_model() {
+        let cases = [
+            (Model::Dmg0, false, (0x0100, 0xff13, 0x00c1, 0x8403)),
+            (Model::Dmg, false, (0x01b0, 0x0013, 0x00d8, 0x014d)),
+            (Model::Mgb, false, (0xffb0, 0x0013, 0x00d8, 0x014d)),
+            (Model::Sgb, false, (0x0100, 0x0014, 0x0000, 0xc060)),
+            (Model::Sgb2, false, (0xff00, 0x0014, 0x0000, 0xc060)),
+            (Model::Cgb, false, (0x1180, 0x0000, 0x0008, 0x007c)),
+            (Model::Cgb, true, (0x1180, 0x0000, 0xff56, 0x000d)),
+        ];
+        for (model, cgb_support, expected) in cases {
+            let registers = post_boot_cpu(model, &header(cgb_support, 0x42)).registers();
+            assert_eq!((registers.af(), registers.bc(), registers.de(), registers.hl()), expected, "{}", model);
+            assert_eq!((registers.sp, registers.pc), (0xfffe, 0x0100), "{}", model);
+        }
+    }
+
+    #[test]
+    fn a_zero_header_checksum_clears_h_and_c_on_dmg_and_mgb() {
+        assert_eq!(post_boot_cpu(Model::Dmg, &header(false, 0x00)).registers().af(), 0x0180);
+        assert_eq!(post_boot_cpu(Model::Mgb, &header(false, 0x00)).registers().af(), 0xff80);
+        assert_eq!(post_boot_cpu(Model::Sgb, &header(false, 0x00)).registers().af(), 0x0100);
+    }
+
+    #[test]
+    fn post_boot_io_overrides_the_dmg_values() {
+        for model in [Model::Dmg0, Model::Dmg, Model::Mgb, Model::Sgb, Model::Sgb2, Model::Cgb] {
+            assert_eq!(io(model, 0x50), 0x01, "{}", model);
+            assert_eq!(io(model, 0x40), 0x91, "{}", model);
+        }
+        assert_eq!(io(Model::Dmg0, 0x04), 0x18);
+        assert_eq!(io(Model::Dmg0, 0x41), 0x81);
+        assert_eq!(io(Model::Dmg, 0x04), 0xab);
+        assert_eq!(io(Model::Dmg, 0x41), 0x85);
+        assert_eq!(io(Model::Dmg, 0x4d), 0xff);
+        assert_eq!(post_boot_io(Model::Mgb), post_boot_io(Model::Dmg));
+        assert_eq!(post_boot_io(Model::Sgb2), post_boot_io(Model::Sgb));
+        assert_eq!((io(Model::Sgb, 0x04), io(Model::Sgb, 0x26)), (0x00, 0xf0));
+        assert_eq!((io(Model::Cgb, 0x04), io(Model::Cgb, 0x4d), io(Model::Cgb, 0x70)), (0x00, 0x7e, 0xf8));
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// How many problems are kept between calls to `take_problems`; more are
+/// only counted.
+const MAX_PROBLEMS: usize = 100;
+
+/// A routine the CPU is in, entered with CALL or RST or by an interrupt.
+/// `sp` is the stack pointer just after the return address was pushed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Frame {
+    pub call_site: u16,
+    pub target: u16,
+    pub return_addr: u16,
+    pub sp: u16,
+    pub interrupt: bool,
+}
+
+/// Something the program did to its stack that a well behaved call and
+/// return wouldn't. `pc` is the instruction that did it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StackProblem {
+    /// A return went somewhere other than where the routine was called
+    /// from, `expected` being `None` when no call was made to return from
+    UnexpectedReturn { pc: u16, expected: Option<u16>, actual: u16 },
+    /// A routine returned to its caller with SP somewhere other than where
+    /// the return address was pushed, so it was moved by hand
+    StackMoved { pc: u16, expected: u16, actual: u16 },
+    /// SP moved outside the region set aside for the stack
+    OutOfRegion { pc: u16, sp: u16 },
+}
+
+impl fmt::Display for StackProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackProblem::UnexpectedReturn { pc, expected: Some(expected), actual } =>
+                write!(f, "{:#06x} returned to {:#06x} instead of {:#06x}", pc, actual, expected),
+            StackProblem::UnexpectedReturn { pc, expected: None, actual } =>
+                write!(f, "{:#06x} returned to {:#06x} without a call to return from", pc, actual),
+            StackProblem::StackMoved { pc, expected, actual } =>
+                write!(f, "{:#06x} returned with SP at {:#06x} instead of {:#06x}", pc, actual, expected),
+            StackProblem::OutOfRegion { pc, sp } =>
+                write!(f, "{:#06x} left SP at {:#06x}, outside the stack region", pc, sp),
+        }
+    }
+}
+
+/// A shadow of the call stack kept by the CPU as it calls, dispatches
+/// interrupts and returns, which says what routine the CPU is in and can
+/// catch a program corrupting its stack. When checking, each problem is
+/// logged as a warning as it's found, and the first ones are kept for
+/// `take_problems`.
+#[derive(Clone, Debug)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    checking: bool,
+    region: Option<RangeInclusive<u16>>,
+    outside: bool,
+    problems: Vec<StackProblem>,
+    dropped: usize,
+}
+
+impl CallStack {
+    /// Tracks calls, also checking SP stays within `region` if there is one.
+    pub fn new(region: Option<RangeInclusive<u16>>) -> Self {
+        CallStack { frames: Vec::new(), checking: true, region, outside: false, problems: Vec::new(), dropped: 0 }
+    }
+
+    /// Only tracks calls, for when what matters is where the CPU is rather
+    /// than whether the program uses its stack properly.
+    pub fn tracking() -> Self {
+        CallStack { checking: false, ..CallStack::new(None) }
+    }
+
+    /// The routines entered and not yet returned from, outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// How many problems found since the last `take_problems` weren't kept.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Hands over the problems kept since the last call.
+    pub fn take_problems(&mut self) -> Vec<StackProblem> {
+        self.dropped = 0;
+        std::mem::take(&mut self.problems)
+    }
+
+    /// Forgets the frames, which no longer mean anything once execution
+    /// jumps elsewhere, e.g. when a state is loaded.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn enter(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /// Records a return by the instruction at `pc` that popped `addr` with
+    /// SP at `sp` beforehand. Returning past frames, as routines that drop
+    /// their return address do, takes the frames in between with it.
+    pub fn leave(&mut self, pc: u16, addr: u16, sp: u16) {
+        let frame = match self.frames.pop() {
+            Some(frame) => frame,
+            None => return self.report(StackProblem::UnexpectedReturn { pc, expected: None, actual: addr }),
+        };
+        if frame.return_addr != addr {
+            self.report(StackProblem::UnexpectedReturn { pc, expected: Some(frame.return_addr), actual: addr });
+            if let Some(position) = self.frames.iter().rposition(|frame| frame.return_addr == addr) {
+                self.frames.truncate(position);
+            }
+        } else if frame.sp != sp {
+            self.report(StackProblem::StackMoved { pc, expected: frame.sp, actual: sp });
+        }
+    }
+
+    /// Checks SP after the instruction at `pc`, reporting when it first
+    /// moves outside the stack region.
+    pub fn check_sp(&mut self, pc: u16, sp: u16) {
+        let outside = self.region.as_ref().is_some_and(|region| !region.contains(&sp));
+        if outside && !self.outside {
+            self.report(StackProblem::OutOfRegion { pc, sp });
+        }
+        self.outside = outside;
+    }
+
+    fn report(&mut self, problem: StackProblem) {
+        if !self.checking {
+            return;
+        }
+        warn!("Stack problem: {}", problem);
+        if self.problems.len() < MAX_PROBLEMS {
+            self.problems.push(problem);
+        } else {
+            self.dropped += 1;
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use super::error::EmulatorError;
+
+const HEADER_END: usize = 0x0150;
+const TITLE: usize = 0x0134;
+const CGB_FLAG: usize = 0x0143;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const HEADER_CHECKSUM: usize = 0x014d;
+
+/// The parts of the cartridge header at 0x0100-0x014f the emulator cares about.
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub title: String,
+    pub cgb_support: bool,
+    pub sgb_support: bool,
+    pub cartridge_type: u8,
+    pub checksum: u8,
+}
+
+impl Header {
+    /// Parses and validates the header the same way the bootrom would,
+    /// rejecting cartridges that need a memory bank controller.
+    pub fn parse(rom: &[u8]) -> Result<Self, EmulatorError> {
+        if rom.len() < HEADER_END {
+            return Err(EmulatorError::InvalidRom(
+                format!("{} bytes is too small to hold a cartridge header", rom.len())));
+        }
+
+        let checksum = rom[TITLE..HEADER_CHECKSUM].iter()
+            .fold(0u8, |acc, byte| acc.wrapping_sub(*byte).wrapping_sub(1));
+        if checksum != rom[HEADER_CHECKSUM] {
+            return Err(EmulatorError::InvalidRom(format!(
+                "header checksum is {:#04x} but the header adds up to {:#04x}",
+                rom[HEADER_CHECKSUM], checksum)));
+        }
+
+        let cartridge_type = rom[CARTRIDGE_TYPE];
+        if cartridge_type != 0x00 {
+            return Err(EmulatorError::UnsupportedCartridge(cartridge_type));
+        }
+
+        let title = rom[TITLE..CGB_FLAG].iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| *byte as char)
+            .collect();
+
+        Ok(Header {
+            title,
+            cgb_support: rom[CGB_FLAG] & 0x80 == 0x80,
+            sgb_support: rom[SGB_FLAG] == 0x03,
+            cartridge_type,
+            checksum,
+        })
+    }
+}
@@ -1,6 +1,129 @@
+use super::callstack::{CallStack, Frame};
+use super::error::EmulatorError;
 use super::memory::Memory;
+use super::savestate::{StateReader, StateWriter};
 use super::utils::bit_is_set;
 
+/// What happens when the CPU fetches one of the 11 undefined opcodes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IllegalOpcodeMode {
+    /// Stop emulation with an `EmulatorError::IllegalOpcode`, for debugging
+    Error,
+    /// Lock up like the hardware does: the CPU stops executing and ignores
+    /// interrupts while the rest of the machine keeps running
+    Lockup,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CpuState {
+    Running,
+    /// Waiting in HALT for an interrupt to be requested
+    Halted,
+    /// Waiting in STOP's low power mode for a joypad line to go low, or for
+    /// the CGB speed switch to finish
+    Stopped,
+    /// Hung on an illegal opcode until the machine is reset
+    Locked { pc: u16, opcode: u8 },
+}
+
+const IF: usize = 0x0f;
+const IE: u16 = 0xffff;
+
+/// A snapshot of the CPU: registers, the interrupt master enable, whether
+/// it's sitting in HALT and how many clock cycles it has run for.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+    pub halted: bool,
+    pub cycles: u64,
+}
+
+impl Registers {
+    pub fn af(&self) -> u16 {
+        ((self.a as u16) << 8) | self.f as u16
+    }
+
+    pub fn bc(&self) -> u16 {
+        ((self.b as u16) << 8) | self.c as u16
+    }
+
+    pub fn de(&self) -> u16 {
+        ((self.d as u16) << 8) | self.e as u16
+    }
+
+    pub fn hl(&self) -> u16 {
+        ((self.h as u16) << 8) | self.l as u16
+    }
+
+    /// The low nibble of F doesn't exist on the hardware and is dropped.
+    pub fn set_af(&mut self, value: u16) {
+        self.a = (value >> 8) as u8;
+        self.f = value as u8 & 0xf0;
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        self.b = (value >> 8) as u8;
+        self.c = value as u8;
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        self.d = (value >> 8) as u8;
+        self.e = value as u8;
+    }
+
+    pub fn set_hl(&mut self, value: u16) {
+        self.h = (value >> 8) as u8;
+        self.l = value as u8;
+    }
+
+    pub fn zero(&self) -> bool {
+        bit_is_set(self.f, 7)
+    }
+
+    pub fn subtract(&self) -> bool {
+        bit_is_set(self.f, 6)
+    }
+
+    pub fn half_carry(&self) -> bool {
+        bit_is_set(self.f, 5)
+    }
+
+    pub fn carry(&self) -> bool {
+        bit_is_set(self.f, 4)
+    }
+
+    pub fn set_zero(&mut self, value: bool) {
+        self.set_flag(7, value);
+    }
+
+    pub fn set_subtract(&mut self, value: bool) {
+        self.set_flag(6, value);
+    }
+
+    pub fn set_half_carry(&mut self, value: bool) {
+        self.set_flag(5, value);
+    }
+
+    pub fn set_carry(&mut self, value: bool) {
+        self.set_flag(4, value);
+    }
+
+    fn set_flag(&mut self, bit: u8, value: bool) {
+        self.f = (self.f & !(1 << bit)) | ((value as u8) << bit);
+    }
+}
+
+#[derive(Clone)]
 pub struct Cpu {
     af: ComboRegister,
     bc: ComboRegister,
@@ -8,6 +131,14 @@ pub struct Cpu {
     hl: ComboRegister,
     sp: u16,
     pc: u16,
+    ime: bool,
+    ime_scheduled: bool,
+    state: CpuState,
+    cycles: u64,
+    illegal_opcode_mode: IllegalOpcodeMode,
+    call_stack: Option<CallStack>,
+    /// Set when LD B,B runs, which test ROMs and debuggers use as a marker
+    ld_b_b: bool,
 }
 
 impl Cpu {
@@ -19,15 +150,236 @@ impl Cpu {
             hl: ComboRegister::new(),
             sp: 0xfffe,
             pc: 0,
+            ime: false,
+            ime_scheduled: false,
+            state: CpuState::Running,
+            cycles: 0,
+            illegal_opcode_mode: IllegalOpcodeMode::Error,
+            call_stack: None,
+            ld_b_b: false,
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn state(&self) -> CpuState {
+        self.state
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.af.hi,
+            f: self.af.lo,
+            b: self.bc.hi,
+            c: self.bc.lo,
+            d: self.de.hi,
+            e: self.de.lo,
+            h: self.hl.hi,
+            l: self.hl.lo,
+            sp: self.sp,
+            pc: self.pc,
+            ime: self.ime,
+            halted: self.state == CpuState::Halted,
+            cycles: self.cycles,
+        }
+    }
+
+    /// Overwrites every register, IME, the HALT state and the cycle counter.
+    /// The low nibble of F doesn't exist on the hardware and always reads as
+    /// zero. A stopped or locked up CPU only changes state through `halted`
+    /// when it's set.
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.af.hi = registers.a;
+        self.af.lo = registers.f & 0xf0;
+        self.bc.hi = registers.b;
+        self.bc.lo = registers.c;
+        self.de.hi = registers.d;
+        self.de.lo = registers.e;
+        self.hl.hi = registers.h;
+        self.hl.lo = registers.l;
+        self.sp = registers.sp;
+        self.pc = registers.pc;
+        self.ime = registers.ime;
+        self.ime_scheduled = false;
+        self.cycles = registers.cycles;
+        if registers.halted {
+            self.state = CpuState::Halted;
+        } else if self.state == CpuState::Halted {
+            self.state = CpuState::Running;
+        }
+    }
+
+    pub fn set_state(&mut self, state: CpuState) {
+        self.state = state;
+    }
+
+    /// Leaves STOP mode.
+    pub fn resume(&mut self) {
+        if self.state == CpuState::Stopped {
+            self.state = CpuState::Running;
+        }
+    }
+
+    pub fn set_illegal_opcode_mode(&mut self, mode: IllegalOpcodeMode) {
+        self.illegal_opcode_mode = mode;
+    }
+
+    /// Starts or stops keeping a shadow call stack.
+    pub fn set_call_stack(&mut self, call_stack: Option<CallStack>) {
+        self.call_stack = call_stack;
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    pub fn call_stack_mut(&mut self) -> Option<&mut CallStack> {
+        self.call_stack.as_mut()
+    }
+
+    /// Whether LD B,B has run since the last call.
+    pub fn take_ld_b_b(&mut self) -> bool {
+        std::mem::take(&mut self.ld_b_b)
+    }
+
+    /// Creates a CPU with the given register values, e.g. the state the
+    /// bootrom leaves behind when it hands over to the cartridge.
+    pub fn with_registers(af: u16, bc: u16, de: u16, hl: u16, sp: u16, pc: u16) -> Self {
+        let mut cpu = Cpu::new();
+        cpu.af.set_combined(af);
+        cpu.bc.set_combined(bc);
+        cpu.de.set_combined(de);
+        cpu.hl.set_combined(hl);
+        cpu.sp = sp;
+        cpu.pc = pc;
+        cpu
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for register in [&self.af, &self.bc, &self.de, &self.hl].iter() {
+            state.u16(register.get_combined());
+        }
+        state.u16(self.sp);
+        state.u16(self.pc);
+        state.bool(self.ime);
+        state.bool(self.ime_scheduled);
+        match self.state {
+            CpuState::Running => state.u8(0),
+            CpuState::Halted => state.u8(1),
+            CpuState::Stopped => state.u8(2),
+            CpuState::Locked { pc, opcode } => {
+                state.u8(3);
+                state.u16(pc);
+                state.u8(opcode);
+            }
+        }
+        state.u64(self.cycles);
+    }
+
+    /// The illegal opcode mode is a setting rather than machine state and
+    /// is left alone.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmulatorError> {
+        for register in [&mut self.af, &mut self.bc, &mut self.de, &mut self.hl].iter_mut() {
+            register.set_combined(state.u16()?);
+        }
+        self.af.lo &= 0xf0;
+        self.sp = state.u16()?;
+        self.pc = state.u16()?;
+        self.ime = state.bool()?;
+        self.ime_scheduled = state.bool()?;
+        self.state = match state.u8()? {
+            0 => CpuState::Running,
+            1 => CpuState::Halted,
+            2 => CpuState::Stopped,
+            3 => CpuState::Locked { pc: state.u16()?, opcode: state.u8()? },
+            _ => return Err(state.error("unknown CPU state")),
+        };
+        self.cycles = state.u64()?;
+        Ok(())
+    }
+
+    /// Executes a single instruction, or dispatches a pending interrupt, and
+    /// returns how many clock cycles it took.
+    pub fn step(&mut self, memory: &mut Memory) -> Result<u32, EmulatorError> {
+        let pc = self.pc;
+        let cycles = match self.state {
+            CpuState::Stopped | CpuState::Locked { .. } => 4,
+            CpuState::Running | CpuState::Halted => match self.service_interrupt(memory) {
+                Some(cycles) => cycles,
+                None if self.state == CpuState::Halted => 4,
+                None => {
+                    // EI only takes effect after the instruction following it
+                    let enable_ime = self.ime_scheduled;
+                    let cycles = self.execute(memory)?;
+                    if enable_ime && self.ime_scheduled {
+                        self.ime = true;
+                        self.ime_scheduled = false;
+                    }
+                    cycles
+                }
+            },
+        };
+        self.cycles += cycles as u64;
+        if let Some(call_stack) = self.call_stack.as_mut() {
+            call_stack.check_sp(pc, self.sp);
+        }
+        Ok(cycles)
+    }
+
+    /// Whether the next `step` executes an instruction, rather than
+    /// dispatching an interrupt or idling in HALT, STOP or a lockup.
+    pub fn executes_next(&self, memory: &mut Memory) -> bool {
+        let pending = memory.io_register(IF) & memory.peek_u8(IE) & 0x1f != 0;
+        match self.state {
+            CpuState::Running => !(pending && self.ime),
+            CpuState::Halted => pending && !self.ime,
+            _ => false,
+        }
+    }
+
+    /// Wakes the CPU from HALT when any enabled interrupt is requested and,
+    /// with IME set, jumps to the handler of the highest priority one.
+    fn service_interrupt(&mut self, memory: &mut Memory) -> Option<u32> {
+        let requested = memory.io_register(IF);
+        let pending = requested & memory.peek_u8(IE) & 0x1f;
+        if pending == 0 {
+            return None;
+        }
+        if self.state == CpuState::Halted {
+            self.state = CpuState::Running;
         }
+        if !self.ime {
+            return None;
+        }
+
+        let interrupt = pending.trailing_zeros() as u16;
+        self.ime = false;
+        self.ime_scheduled = false;
+        memory.set_io_register(IF, requested & !(1 << interrupt));
+        let current_pc = self.pc;
+        self.push_stack_u16(memory, current_pc);
+        self.pc = 0x0040 + interrupt * 8;
+        if let Some(call_stack) = self.call_stack.as_mut() {
+            call_stack.enter(Frame { call_site: current_pc, target: self.pc, return_addr: current_pc, sp: self.sp, interrupt: true });
+        }
+        Some(20)
     }
 
-    pub fn step(&mut self, memory: &mut Memory) {
+    fn execute(&mut self, memory: &mut Memory) -> Result<u32, EmulatorError> {
         let opcode_addr = self.pc;
         let opcode = self.read_u8_at_pc(memory);
-        debug!("{:#x} opcode: {:#x}", opcode_addr, opcode);
+        let mut cycles = OPCODE_CYCLES[opcode as usize] as u32;
         match opcode {
-            0xaf | 0xa8 | 0xa9 | 0xaa | 0xab | 0xac | 0xad | 0xae => { // XOR a
+            0x00 => {} // NOP
+            0x10 => { // STOP
+                // STOP is encoded as 0x10 0x00 and the second byte is skipped
+                self.pc = self.pc.wrapping_add(1);
+                memory.set_io_register(0x04, 0); // Entering STOP resets DIV
+                self.state = CpuState::Stopped;
+            }
+            0xa8..=0xaf => { // XOR a
                 self.af.hi ^= self.get_register_value(memory, opcode);
                 let new_zero = self.af.hi == 0x00;
                 self.af.set_flag_lo(Flags::Zero(new_zero));
@@ -37,14 +389,14 @@ impl Cpu {
             }
             0x32 => { // LDD (HL-), A
                 memory.write_u8(self.hl.get_combined(), self.af.hi);
-                let new_hl = self.hl.get_combined()-1;
+                let new_hl = self.hl.get_combined().wrapping_sub(1);
                 self.hl.set_combined(new_hl);
             }
             0xcb => { // Special multibyte instructions
                 let special_op = self.read_u8_at_pc(memory);
-                debug!("Special opcode: {:#x}", special_op);
+                cycles = CB_OPCODE_CYCLES[special_op as usize] as u32;
                 match special_op {
-                    0x40 ... 0x7f => { // BIT b, r operations
+                    0x40..=0x7f => { // BIT b, r operations
                         let bit_to_check = (special_op - 0x40) / 0x08;
                         let register = self.get_register_value(memory, special_op);
                         self.af.set_flag_lo(Flags::Zero(!bit_is_set(register, bit_to_check)));
@@ -57,7 +409,11 @@ impl Cpu {
                         self.bc.hi |= self.af.check_flag_low(Flags::Carry(true)) as u8;
                         self.af.set_flag_lo(Flags::Carry(carry_bit));
                     }
-                    _ => panic!("Unknown special opcode: {:#x}", special_op)
+                    _ => return Err(EmulatorError::UnimplementedOpcode {
+                        pc: opcode_addr,
+                        opcode: special_op,
+                        prefixed: true,
+                    })
                 }
             }
             0x17 => { // RL A
@@ -70,26 +426,24 @@ impl Cpu {
                 let offset = self.read_i8_at_pc(memory);
                 match (opcode >> 3) & 0x03 {
                     0x0 => { // NZ
-                        if self.af.check_flag_low(Flags::Zero(true)) { return; }
+                        if self.af.check_flag_low(Flags::Zero(true)) { return Ok(cycles); }
                     }
                     0x1 => { // Z
-                        if self.af.check_flag_low(Flags::Zero(false)) { return; }
+                        if self.af.check_flag_low(Flags::Zero(false)) { return Ok(cycles); }
                     }
                     0x2 => { // NC
-                        if self.af.check_flag_low(Flags::Carry(true)) { return; }
+                        if self.af.check_flag_low(Flags::Carry(true)) { return Ok(cycles); }
                     }
-                    0x3 => { // C
-                        if self.af.check_flag_low(Flags::Carry(false)) { return; }
+                    _ => { // C
+                        if self.af.check_flag_low(Flags::Carry(false)) { return Ok(cycles); }
                     }
-                    _ => { panic!("Invalid Jump opcode: {:#x}", opcode); }
                 }
-                debug!("Jumping by offset: '{:#x}'", offset);
-                self.pc = (self.pc as i16 + offset as i16) as u16;
+                self.pc = self.pc.wrapping_add(offset as u16);
+                cycles += 4;
             }
             0x18 => { // JR n
                 let offset = self.read_i8_at_pc(memory);
-                debug!("Jumping by offset: '{:#x}'", offset);
-                self.pc = (self.pc as i16 + offset as i16) as u16;
+                self.pc = self.pc.wrapping_add(offset as u16);
             }
             0x01 => { // LD BC, nn
                 let value = self.read_u16_at_pc(memory);
@@ -124,13 +478,13 @@ impl Cpu {
             0x22 => { // LDI (HL), A
                 let address = self.hl.get_combined();
                 memory.write_u8(address, self.af.hi);
-                self.hl.set_combined(address + 1);
+                self.hl.set_combined(address.wrapping_add(1));
              }
             0x0e => { self.bc.lo = self.read_u8_at_pc(memory) } // LD C, n
             0x1e => { self.de.lo = self.read_u8_at_pc(memory) } // LD E, n
             0x2e => { self.hl.lo = self.read_u8_at_pc(memory) } // LD L, n
             0x3e => { self.af.hi = self.read_u8_at_pc(memory) } // LD A, n
-            0x40 => { self.bc.hi = self.bc.hi; } // LD B, B
+            0x40 => { self.ld_b_b = true; } // LD B, B
             0x41 => { self.bc.hi = self.bc.lo; } // LD B, C
             0x42 => { self.bc.hi = self.de.hi; } // LD B, D
             0x43 => { self.bc.hi = self.de.lo; } // LD B, E
@@ -139,7 +493,7 @@ impl Cpu {
             0x46 => { self.bc.hi = memory.read_u8(self.hl.get_combined()); } // LD B, (HL)
             0x47 => { self.bc.hi = self.af.hi; } // LD B, A
             0x48 => { self.bc.lo = self.bc.hi; } // LD C, B
-            0x49 => { self.bc.lo = self.bc.lo; } // LD C, C
+            0x49 => {} // LD C, C
             0x4a => { self.bc.lo = self.de.hi; } // LD C, D
             0x4b => { self.bc.lo = self.de.lo; } // LD C, E
             0x4c => { self.bc.lo = self.hl.hi; } // LD C, H
@@ -148,7 +502,7 @@ impl Cpu {
             0x4f => { self.bc.lo = self.af.hi; } // LD C, A
             0x50 => { self.de.hi = self.bc.hi; } // LD D, B
             0x51 => { self.de.hi = self.bc.lo; } // LD D, C
-            0x52 => { self.de.hi = self.de.hi; } // LD D, D
+            0x52 => {} // LD D, D
             0x53 => { self.de.hi = self.de.lo; } // LD D, E
             0x54 => { self.de.hi = self.hl.hi; } // LD D, H
             0x55 => { self.de.hi = self.hl.lo; } // LD D, L
@@ -157,7 +511,7 @@ impl Cpu {
             0x58 => { self.de.lo = self.bc.hi; } // LD E, B
             0x59 => { self.de.lo = self.bc.lo; } // LD E, C
             0x5a => { self.de.lo = self.de.hi; } // LD E, D
-            0x5b => { self.de.lo = self.de.lo; } // LD E, E
+            0x5b => {} // LD E, E
             0x5c => { self.de.lo = self.hl.hi; } // LD E, H
             0x5d => { self.de.lo = self.hl.lo; } // LD E, L
             0x5e => { self.de.lo = memory.read_u8(self.hl.get_combined()); } // LD E, (HL)
@@ -166,7 +520,7 @@ impl Cpu {
             0x61 => { self.hl.hi = self.bc.lo; } // LD H, C
             0x62 => { self.hl.hi = self.de.hi; } // LD H, D
             0x63 => { self.hl.hi = self.de.lo; } // LD H, E
-            0x64 => { self.hl.hi = self.hl.hi; } // LD H, H
+            0x64 => {} // LD H, H
             0x65 => { self.hl.hi = self.hl.lo; } // LD H, L
             0x66 => { self.hl.hi = memory.read_u8(self.hl.get_combined()); } // LD H, (HL)
             0x67 => { self.hl.hi = self.af.hi; } // LD H, A
@@ -175,7 +529,7 @@ impl Cpu {
             0x6a => { self.hl.lo = self.de.hi; } // LD L, D
             0x6b => { self.hl.lo = self.de.lo; } // LD L, E
             0x6c => { self.hl.lo = self.hl.hi; } // LD L, H
-            0x6d => { self.hl.lo = self.hl.lo; } // LD L, L
+            0x6d => {} // LD L, L
             0x6e => { self.hl.lo = memory.read_u8(self.hl.get_combined()); } // LD L, (HL)
             0x6f => { self.hl.lo = self.af.hi; } // LD L, A
             0x70 => { memory.write_u8(self.hl.get_combined(), self.bc.hi); } // LD (HL), B
@@ -184,7 +538,7 @@ impl Cpu {
             0x73 => { memory.write_u8(self.hl.get_combined(), self.de.lo); } // LD (HL), E
             0x74 => { memory.write_u8(self.hl.get_combined(), self.hl.hi); } // LD (HL), H
             0x75 => { memory.write_u8(self.hl.get_combined(), self.hl.lo); } // LD (HL), L
-            0x76 => { panic!("HALT not implemented"); } // TODO HALT
+            0x76 => { self.state = CpuState::Halted; } // HALT
             0x77 => { memory.write_u8(self.hl.get_combined(), self.af.hi); } // LD (HL), A
             0x78 => { self.af.hi = self.bc.hi; } // LD A, B
             0x79 => { self.af.hi = self.bc.lo; } // LD A, C
@@ -193,34 +547,36 @@ impl Cpu {
             0x7c => { self.af.hi = self.hl.hi; } // LD A, H
             0x7d => { self.af.hi = self.hl.lo; } // LD A, L
             0x7e => { self.af.hi = memory.read_u8(self.hl.get_combined()); } // LD A, (HL)
-            0x7f => { self.af.hi = self.af.hi; } // LD A, A
+            0x7f => {} // LD A, A
             0xc4 | 0xcc | 0xd4 | 0xdc => { // CALL cc, nn
                 let jump_to_addr = self.read_u16_at_pc(memory);
                 match (opcode >> 3) & 0x03 {
                     0x0 => { // NZ
-                        if self.af.check_flag_low(Flags::Zero(true)) { return; }
+                        if self.af.check_flag_low(Flags::Zero(true)) { return Ok(cycles); }
                     }
                     0x1 => { // Z
-                        if self.af.check_flag_low(Flags::Zero(false)) { return; }
+                        if self.af.check_flag_low(Flags::Zero(false)) { return Ok(cycles); }
                     }
                     0x2 => { // NC
-                        if self.af.check_flag_low(Flags::Carry(true)) { return; }
+                        if self.af.check_flag_low(Flags::Carry(true)) { return Ok(cycles); }
                     }
-                    0x3 => { // C
-                        if self.af.check_flag_low(Flags::Carry(false)) { return; }
+                    _ => { // C
+                        if self.af.check_flag_low(Flags::Carry(false)) { return Ok(cycles); }
                     }
-                    _ => { panic!("Invalid Call opcode: {:#x}", opcode); }
                 }
-                debug!("jumping to: {:#x}", jump_to_addr);
-                self.call(memory, jump_to_addr);
+                self.call(memory, opcode_addr, jump_to_addr);
+                cycles += 12;
             }
             0xcd => { // CALL nn
                 let jump_to_addr = self.read_u16_at_pc(memory);
-                self.call(memory, jump_to_addr);
+                self.call(memory, opcode_addr, jump_to_addr);
+            }
+            0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => { // RST n
+                self.call(memory, opcode_addr, (opcode & 0x38) as u16);
             }
             0x23 => { // INC HL
                 let half_carry_bit = self.hl.get_combined() & 0x0800 == 0x0800;
-                let new_hl = self.hl.get_combined() + 1;
+                let new_hl = self.hl.get_combined().wrapping_add(1);
                 self.hl.set_combined(new_hl);
                 self.af.set_flag_lo(Flags::Zero(new_hl == 0));
                 self.af.set_flag_lo(Flags::Subtract(false));
@@ -229,7 +585,7 @@ impl Cpu {
             }
             0x13 => { // INC DE
                 let half_carry_bit = self.de.get_combined() & 0x0800 == 0x0800;
-                let new_de = self.de.get_combined() + 1;
+                let new_de = self.de.get_combined().wrapping_add(1);
                 self.de.set_combined(new_de);
                 self.af.set_flag_lo(Flags::Zero(new_de == 0));
                 self.af.set_flag_lo(Flags::Subtract(false));
@@ -286,11 +642,41 @@ impl Cpu {
                 let value = self.pop_stack_u16(memory);
                 self.bc.set_combined(value);
             }
+            0xf1 => { // POP AF
+                let value = self.pop_stack_u16(memory);
+                self.af.set_combined(value & 0xfff0);
+            }
             0xc9 => { // RET
-                let addr = self.pop_stack_u16(memory);
-                self.pc = addr;
+                self.ret(memory, opcode_addr);
             }
-            0xbf | 0xb8 | 0xb9 | 0xba | 0xbb | 0xbc | 0xbd | 0xbe => { // CP n
+            0xc0 | 0xc8 | 0xd0 | 0xd8 => { // RET cc
+                match (opcode >> 3) & 0x03 {
+                    0x0 => { // NZ
+                        if self.af.check_flag_low(Flags::Zero(true)) { return Ok(cycles); }
+                    }
+                    0x1 => { // Z
+                        if self.af.check_flag_low(Flags::Zero(false)) { return Ok(cycles); }
+                    }
+                    0x2 => { // NC
+                        if self.af.check_flag_low(Flags::Carry(true)) { return Ok(cycles); }
+                    }
+                    _ => { // C
+                        if self.af.check_flag_low(Flags::Carry(false)) { return Ok(cycles); }
+                    }
+                }
+                self.ret(memory, opcode_addr);
+                cycles += 12;
+            }
+            0xd9 => { // RETI
+                self.ret(memory, opcode_addr);
+                self.ime = true;
+            }
+            0xf3 => { // DI
+                self.ime = false;
+                self.ime_scheduled = false;
+            }
+            0xfb => { self.ime_scheduled = true; } // EI
+            0xb8..=0xbf => { // CP n
                 let compared_register = self.get_register_value(memory, opcode);
                 let half_carry_bit = (self.af.hi & 0x08) == 0;
                 let af_hi = self.af.hi;
@@ -310,8 +696,17 @@ impl Cpu {
                 self.af.set_flag_lo(Flags::HalfCarry(half_carry_bit && ((res & 0x08) == 0x08)));
                 self.af.set_flag_lo(Flags::Carry(res >= af_hi));
             }
-            _ => panic!("Unknown opcode: {:#x}", opcode)
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                if self.illegal_opcode_mode == IllegalOpcodeMode::Error {
+                    return Err(EmulatorError::IllegalOpcode { pc: opcode_addr, opcode });
+                }
+                warn!("CPU locked up on illegal opcode {:#04x} at {:#06x}", opcode, opcode_addr);
+                self.state = CpuState::Locked { pc: opcode_addr, opcode };
+                cycles = 4;
+            }
+            _ => return Err(EmulatorError::UnimplementedOpcode { pc: opcode_addr, opcode, prefixed: false })
         }
+        Ok(cycles)
     }
 
     fn get_register_value(&mut self, memory: &mut Memory, opcode: u8) -> u8 {
@@ -324,14 +719,25 @@ impl Cpu {
             0x05 => self.hl.lo,
             0x06 => memory.read_u8(self.hl.get_combined()),
             0x07 => self.af.hi,
-            _ => panic!("How the fuck did you break modulus?")
+            _ => unreachable!()
         }
     }
 
-    fn call(&mut self, memory: &mut Memory, jump_to_addr: u16) {
+    fn call(&mut self, memory: &mut Memory, call_site: u16, jump_to_addr: u16) {
         let current_pc = self.pc;
         self.push_stack_u16(memory, current_pc);
         self.pc = jump_to_addr;
+        if let Some(call_stack) = self.call_stack.as_mut() {
+            call_stack.enter(Frame { call_site, target: jump_to_addr, return_addr: current_pc, sp: self.sp, interrupt: false });
+        }
+    }
+
+    fn ret(&mut self, memory: &mut Memory, opcode_addr: u16) {
+        let sp = self.sp;
+        self.pc = self.pop_stack_u16(memory);
+        if let Some(call_stack) = self.call_stack.as_mut() {
+            call_stack.leave(opcode_addr, self.pc, sp);
+        }
     }
 
     fn push_stack_u16(&mut self, memory: &mut Memory, value: u16) {
@@ -347,33 +753,76 @@ impl Cpu {
 
     fn push_stack_u8(&mut self, memory: &mut Memory, value: u8) {
         memory.write_u8(self.sp, value);
-        self.sp -= 1;
+        self.sp = self.sp.wrapping_sub(1);
     }
 
     fn pop_stack_u8(&mut self, memory: &mut Memory) -> u8 {
-        self.sp += 1;
+        self.sp = self.sp.wrapping_add(1);
         memory.read_u8(self.sp)
     }
 
     fn read_u8_at_pc(&mut self, memory: &mut Memory) -> u8 {
         let current_pc = self.pc;
-        self.pc += 1;
-        return memory.read_u8(current_pc);
+        self.pc = self.pc.wrapping_add(1);
+        memory.read_u8(current_pc)
     }
 
     fn read_i8_at_pc(&mut self, memory: &mut Memory) -> i8 {
         let current_pc = self.pc;
-        self.pc += 1;
-        return memory.read_u8(current_pc) as i8;
+        self.pc = self.pc.wrapping_add(1);
+        memory.read_u8(current_pc) as i8
     }
 
     fn read_u16_at_pc(&mut self, memory: &mut Memory) -> u16 {
         let current_pc = self.pc;
-        self.pc += 2;
-        return memory.read_u16(current_pc);
+        self.pc = self.pc.wrapping_add(2);
+        memory.read_u16(current_pc)
     }
 }
 
+/// Clock cycles per opcode. Conditional jumps, calls and returns are listed
+/// with their not-taken timing; `step` adds the difference when they're taken.
+const OPCODE_CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x00
+    4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 0x10
+    8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 0x20
+    8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 0x30
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x40
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x50
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x60
+    8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 0x70
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x80
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x90
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0xa0
+    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0xb0
+    8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  4, 12, 24,  8, 16, // 0xc0
+    8, 12, 12,  0, 12, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16, // 0xd0
+   12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16, // 0xe0
+   12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16, // 0xf0
+];
+
+/// Clock cycles for opcodes following the 0xcb prefix, including the prefix.
+const CB_OPCODE_CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x00
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x10
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x20
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x30
+    8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 0x40
+    8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 0x50
+    8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 0x60
+    8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 0x70
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x80
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x90
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xa0
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xb0
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xc0
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xd0
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xe0
+    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xf0
+];
+
 enum Flags {
     Zero(bool),
     Subtract(bool),
@@ -381,6 +830,7 @@ enum Flags {
     Carry(bool)
 }
 
+#[derive(Clone)]
 struct ComboRegister {
     hi: u8,
     lo: u8,
@@ -395,8 +845,8 @@ impl ComboRegister {
     }
 
     fn get_combined(&self) -> u16 {
-        return ((self.hi as u16) << 8) |
-               ((self.lo as u16) & 0x00ff);
+        ((self.hi as u16) << 8) |
+        ((self.lo as u16) & 0x00ff)
     }
 
     fn set_combined(&mut self, combined: u16) {
@@ -434,3 +884,112 @@ impl ComboRegister {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CPU at 0x0100 with `code` there, and memory without a bootrom.
+    fn machine(code: &[u8]) -> (Cpu, Memory) {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        (Cpu::with_registers(0x01b0, 0x0013, 0x00d8, 0x014d, 0xfffe, 0x0100), Memory::new(rom, None, false).unwrap())
+    }
+
+    #[test]
+    fn illegal_opcodes_are_errors_by_default() {
+        for opcode in [0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd] {
+            let (mut cpu, mut memory) = machine(&[opcode]);
+            match cpu.step(&mut memory) {
+                Err(EmulatorError::IllegalOpcode { pc: 0x0100, opcode: found }) => assert_eq!(found, opcode),
+                result => panic!("{:#04x}: {:?}", opcode, result),
+            }
+            assert_eq!(cpu.state(), CpuState::Running);
+        }
+    }
+
+    #[test]
+    fn lockups_idle_four_cycles_at_a_time_and_ignore_interrupts() {
+        let (mut cpu, mut memory) = machine(&[0xdd]);
+        cpu.set_illegal_opcode_mode(IllegalOpcodeMode::Lockup);
+        assert_eq!(cpu.step(&mut memory).unwrap(), 4);
+        assert_eq!(cpu.state(), CpuState::Locked { pc: 0x0100, opcode: 0xdd });
+
+        cpu.ime = true;
+        memory.poke_u8(0xffff, 0x1f);
+        memory.set_io_register(IF, 0x1f);
+        assert!(!cpu.executes_next(&mut memory));
+        let (pc, sp, cycles) = (cpu.pc(), cpu.sp, cpu.registers().cycles);
+        for _ in 0..10 {
+            assert_eq!(cpu.step(&mut memory).unwrap(), 4);
+        }
+        assert_eq!((cpu.pc(), cpu.sp, cpu.registers().cycles), (pc, sp, cycles + 40));
+        assert_eq!(memory.io_register(IF), 0x1f);
+        assert!(cpu.ime);
+        assert_eq!(cpu.state(), CpuState::Locked { pc: 0x0100, opcode: 0xdd });
+    }
+
+    #[test]
+    fn stop_skips_its_second_byte_and_resets_div() {
+        let (mut cpu, mut memory) = machine(&[0x10, 0x00, 0x00]);
+        memory.set_io_register(0x04, 0xab);
+        assert_eq!(cpu.step(&mut memory).unwrap(), 4);
+        assert_eq!((cpu.pc(), cpu.state()), (0x0102, CpuState::Stopped));
+        assert_eq!(memory.io_register(0x04), 0);
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), 4);
+        assert_eq!((cpu.pc(), cpu.state()), (0x0102, CpuState::Stopped));
+    }
+
+    #[test]
+    fn register_pairs_split_into_bytes() {
+        let mut registers = Registers::default();
+        registers.set_af(0x12ff);
+        registers.set_bc(0x3456);
+        registers.set_de(0x789a);
+        registers.set_hl(0xbcde);
+        assert_eq!((registers.a, registers.f), (0x12, 0xf0));
+        assert_eq!((registers.b, registers.c, registers.d, registers.e), (0x34, 0x56, 0x78, 0x9a));
+        assert_eq!((registers.h, registers.l), (0xbc, 0xde));
+        assert_eq!((registers.af(), registers.bc(), registers.de(), registers.hl()), (0x12f0, 0x3456, 0x789a, 0xbcde));
+    }
+
+    #[test]
+    fn flags_are_the_top_nibble_of_f() {
+        let mut registers = Registers::default();
+        registers.set_af(0x000f);
+        assert_eq!(registers.f, 0);
+        for (bit, set) in [(7, Registers::set_zero as fn(&mut Registers, bool)), (6, Registers::set_subtract),
+                           (5, Registers::set_half_carry), (4, Registers::set_carry)] {
+            set(&mut registers, true);
+            assert_eq!(registers.f, 1 << bit);
+            assert_eq!((registers.zero(), registers.subtract(), registers.half_carry(), registers.carry()),
+                       (bit == 7, bit == 6, bit == 5, bit == 4));
+            set(&mut registers, false);
+            assert_eq!(registers.f, 0);
+        }
+    }
+
+    #[test]
+    fn set_registers_masks_f_and_only_changes_halt_state() {
+        let mut cpu = Cpu::new();
+        let mut registers = cpu.registers();
+        registers.f = 0xff;
+        registers.sp = 0xdffe;
+        registers.pc = 0x0150;
+        registers.ime = true;
+        registers.halted = true;
+        registers.cycles = 1234;
+        cpu.set_registers(&registers);
+        assert_eq!(cpu.registers(), Registers { f: 0xf0, ..registers });
+        assert_eq!(cpu.state(), CpuState::Halted);
+
+        registers.halted = false;
+        cpu.set_registers(&registers);
+        assert_eq!(cpu.state(), CpuState::Running);
+
+        cpu.set_state(CpuState::Stopped);
+        cpu.set_registers(&registers);
+        assert_eq!(cpu.state(), CpuState::Stopped);
+    }
+}
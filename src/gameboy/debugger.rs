@@ -0,0 +1,982 @@
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::cpu::{CpuState, Registers};
+use super::disasm::Instruction;
+use super::gameboy::Gameboy;
+use super::symbols::Symbols;
+use super::watchpoint::{WatchHit, WatchKind, Watchpoint};
+
+/// How long a command runs between checks for a request to pause, one
+/// frame's worth of cycles
+const POLL_CYCLES: u64 = 70224;
+
+pub const HELP: &str = "commands:
+    s, step [N]                   Execute N instructions (default 1), stepping into calls
+    n, next [N]                   Like step, but runs calls and RSTs until they return
+    finish, out                   Run until the current routine returns
+    c, continue                   Run until a breakpoint, an error or a lockup
+    b, break ADDR [if COND]       Break before executing ADDR, or BANK:ADDR for a ROM bank
+    b, break opcode OP [if COND]  Break before any instruction with opcode OP, e.g. 0x76 or 0xcb37
+    w, watch [KIND] ADDR[-END] [== VALUE]
+                                  Stop after an instruction touches ADDR, or ADDR to END.
+                                  KIND is read, write or change (default), and with a
+                                  VALUE it only stops when that value is read or written
+    delete N                      Remove breakpoint or watchpoint N
+    breaks                        List breakpoints and watchpoints
+    r, regs                       Show registers and flags
+    l, list [ADDR] [N]            Disassemble N instructions from ADDR, or around PC
+    x ADDR [LEN]                  Show LEN bytes of memory (default 16)
+    set ADDR VALUE                Write a byte to memory
+    set REG VALUE                 Change a register or flag, e.g. 'set hl 0xc000' or 'set zf 1'
+    bt, backtrace                 Show the call stack
+    h, help                       Show this message
+    q, quit                       Leave the debugger
+
+Numbers are decimal unless written as 0x1f or $1f, and labels from a symbol
+file can be used as addresses. Conditions compare registers (a-l, af, bc, de,
+hl, sp, pc), flags (zf, nf, hf, cf), ime, memory ([0xc000], [hl], [wCount])
+and numbers with == != < <= > >=, combined with && and ||. An empty line
+repeats the last command. With stack checking on, running also stops after
+an instruction that misuses the stack. Ctrl-C pauses a command that's running.";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Register {
+    A, F, B, C, D, E, H, L,
+    Af, Bc, De, Hl, Sp, Pc,
+    Zf, Nf, Hf, Cf, Ime,
+}
+
+impl Register {
+    fn parse(name: &str) -> Option<Self> {
+        let register = match name {
+            "a" => Register::A,
+            "f" => Register::F,
+            "b" => Register::B,
+            "c" => Register::C,
+            "d" => Register::D,
+            "e" => Register::E,
+            "h" => Register::H,
+            "l" => Register::L,
+            "af" => Register::Af,
+            "bc" => Register::Bc,
+            "de" => Register::De,
+            "hl" => Register::Hl,
+            "sp" => Register::Sp,
+            "pc" => Register::Pc,
+            "zf" => Register::Zf,
+            "nf" => Register::Nf,
+            "hf" => Register::Hf,
+            "cf" => Register::Cf,
+            "ime" => Register::Ime,
+            _ => return None,
+        };
+        Some(register)
+    }
+
+    fn get(self, registers: &Registers) -> u16 {
+        match self {
+            Register::A => registers.a as u16,
+            Register::F => registers.f as u16,
+            Register::B => registers.b as u16,
+            Register::C => registers.c as u16,
+            Register::D => registers.d as u16,
+            Register::E => registers.e as u16,
+            Register::H => registers.h as u16,
+            Register::L => registers.l as u16,
+            Register::Af => registers.af(),
+            Register::Bc => registers.bc(),
+            Register::De => registers.de(),
+            Register::Hl => registers.hl(),
+            Register::Sp => registers.sp,
+            Register::Pc => registers.pc,
+            Register::Zf => registers.zero() as u16,
+            Register::Nf => registers.subtract() as u16,
+            Register::Hf => registers.half_carry() as u16,
+            Register::Cf => registers.carry() as u16,
+            Register::Ime => registers.ime as u16,
+        }
+    }
+
+    fn set(self, registers: &mut Registers, value: u16) -> Result<(), String> {
+        let limit = match self {
+            Register::Af | Register::Bc | Register::De | Register::Hl | Register::Sp | Register::Pc => 0xffff,
+            Register::Zf | Register::Nf | Register::Hf | Register::Cf | Register::Ime => 1,
+            _ => 0xff,
+        };
+        if value > limit {
+            return Err(format!("{:#x} doesn't fit in {:?}", value, self));
+        }
+        let (byte, flag) = (value as u8, value == 1);
+        match self {
+            Register::A => registers.a = byte,
+            Register::F => registers.f = byte & 0xf0,
+            Register::B => registers.b = byte,
+            Register::C => registers.c = byte,
+            Register::D => registers.d = byte,
+            Register::E => registers.e = byte,
+            Register::H => registers.h = byte,
+            Register::L => registers.l = byte,
+            Register::Af => registers.set_af(value),
+            Register::Bc => registers.set_bc(value),
+            Register::De => registers.set_de(value),
+            Register::Hl => registers.set_hl(value),
+            Register::Sp => registers.sp = value,
+            Register::Pc => registers.pc = value,
+            Register::Zf => registers.set_zero(flag),
+            Register::Nf => registers.set_subtract(flag),
+            Register::Hf => registers.set_half_carry(flag),
+            Register::Cf => registers.set_carry(flag),
+            Register::Ime => registers.ime = flag,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Register(Register),
+    Value(u16),
+    Memory(Box<Operand>),
+}
+
+impl Operand {
+    fn evaluate(&self, gameboy: &mut Gameboy) -> u16 {
+        match self {
+            Operand::Register(register) => register.get(&gameboy.registers()),
+            Operand::Value(value) => *value,
+            Operand::Memory(addr) => {
+                let addr = addr.evaluate(gameboy);
+                gameboy.read_memory(addr) as u16
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A breakpoint condition such as `a == 0x3 && [hl] != 0`.
+#[derive(Clone, Debug)]
+enum Condition {
+    Compare(Operand, Comparison, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    fn parse(text: &str, symbols: Option<&Symbols>) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, position: 0, symbols };
+        let condition = parser.or()?;
+        match parser.next() {
+            None => Ok(condition),
+            Some(token) => Err(format!("Unexpected '{}' in condition", token)),
+        }
+    }
+
+    fn evaluate(&self, gameboy: &mut Gameboy) -> bool {
+        match self {
+            Condition::Compare(left, comparison, right) => {
+                let (left, right) = (left.evaluate(gameboy), right.evaluate(gameboy));
+                match comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    Comparison::GreaterOrEqual => left >= right,
+                }
+            }
+            Condition::And(left, right) => left.evaluate(gameboy) && right.evaluate(gameboy),
+            Condition::Or(left, right) => left.evaluate(gameboy) || right.evaluate(gameboy),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let word = |c: char| c.is_ascii_alphanumeric() || "$_.@#".contains(c);
+        if c.is_whitespace() {
+            i += 1;
+        } else if word(c) {
+            let start = i;
+            while i < chars.len() && word(chars[i]) {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            let pair: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            if ["==", "!=", "<=", ">=", "&&", "||"].contains(&pair.as_str()) {
+                tokens.push(pair);
+                i += 2;
+            } else if "<>[]".contains(c) {
+                tokens.push(c.to_string());
+                i += 1;
+            } else {
+                return Err(format!("Unexpected '{}' in condition", c));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over condition tokens, with && binding tighter than ||.
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+    symbols: Option<&'a Symbols>,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.tokens.get(self.position)?;
+        self.position += 1;
+        Some(token)
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.and()?;
+        while self.peek() == Some("||") {
+            self.next();
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.comparison()?;
+        while self.peek() == Some("&&") {
+            self.next();
+            condition = Condition::And(Box::new(condition), Box::new(self.comparison()?));
+        }
+        Ok(condition)
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        let left = self.operand()?;
+        let comparison = match self.next() {
+            Some("==") => Comparison::Equal,
+            Some("!=") => Comparison::NotEqual,
+            Some("<") => Comparison::Less,
+            Some("<=") => Comparison::LessOrEqual,
+            Some(">") => Comparison::Greater,
+            Some(">=") => Comparison::GreaterOrEqual,
+            Some(token) => return Err(format!("Expected a comparison, got '{}'", token)),
+            None => return Err("Expected a comparison".to_string()),
+        };
+        Ok(Condition::Compare(left, comparison, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some("[") => {
+                let addr = self.operand()?;
+                match self.next() {
+                    Some("]") => Ok(Operand::Memory(Box::new(addr))),
+                    _ => Err("Expected ']'".to_string()),
+                }
+            }
+            Some(token) => match Register::parse(token) {
+                Some(register) => Ok(Operand::Register(register)),
+                None => parse_address(self.symbols, token).map(Operand::Value),
+            },
+            None => Err("Condition ends too early".to_string()),
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let (digits, radix) = match text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        Some(hex) => (hex, 16),
+        None => (text, 10),
+    };
+    u16::from_str_radix(digits, radix).map_err(|_| format!("Invalid number '{}'", text))
+}
+
+/// Parses a number or the name of a label.
+fn parse_address(symbols: Option<&Symbols>, text: &str) -> Result<u16, String> {
+    parse_number(text).or_else(|_| match symbols.and_then(|symbols| symbols.lookup(text)) {
+        Some((_, addr)) => Ok(addr),
+        None => Err(format!("'{}' is neither a number nor a known label", text)),
+    })
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Location {
+    /// `bank` is only checked when given
+    Address { bank: Option<usize>, addr: u16 },
+    /// The second byte of 0xcb prefixed opcodes is in `prefixed`
+    Opcode { opcode: u8, prefixed: Option<u8> },
+}
+
+struct Breakpoint {
+    id: usize,
+    location: Location,
+    condition: Option<Condition>,
+    description: String,
+}
+
+struct Watch {
+    id: usize,
+    watchpoint: Watchpoint,
+    description: String,
+}
+
+/// Pauses and steps a `Gameboy` on behalf of a user typing commands. It
+/// drives `Gameboy::step` itself, so nothing is checked while the machine
+/// runs without one, and has the CPU track calls for `bt`, `next` and
+/// `finish`.
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watches: Vec<Watch>,
+    next_id: usize,
+    last_command: String,
+    interrupt: Arc<AtomicBool>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            next_id: 1,
+            last_command: String::new(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A flag that pauses the command running once it's set, and is
+    /// cleared when that happens. Setting it is all a Ctrl-C handler needs
+    /// to do.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Runs one command, writing its output to `out`. Returns false once
+    /// the user asks to quit.
+    pub fn execute<W: Write>(&mut self, gameboy: &mut Gameboy, line: &str, out: &mut W) -> io::Result<bool> {
+        gameboy.track_calls();
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let args: Vec<&str> = words.collect();
+        let count = |args: &[&str]| match args.first().map_or(Ok(1), |count| parse_number(count)) {
+            Ok(0) => Err("The count has to be at least 1".to_string()),
+            count => count.map(u64::from),
+        };
+
+        let result = match command {
+            "s" | "step" => count(&args).map(|count| {
+                let mut remaining = count;
+                self.run(gameboy, out, move |_, _| {
+                    remaining -= 1;
+                    remaining == 0
+                }).map(drop)
+            }),
+            "n" | "next" => count(&args).map(|count| {
+                for _ in 0..count {
+                    if !self.next(gameboy, out)? {
+                        break;
+                    }
+                }
+                Ok(())
+            }),
+            "finish" | "out" => Ok(self.finish(gameboy, out)),
+            "c" | "continue" => Ok(self.run(gameboy, out, |_, gameboy| {
+                matches!(gameboy.cpu_state(), CpuState::Locked { .. })
+            }).map(drop)),
+            "b" | "break" => self.add_breakpoint(gameboy, &args, out),
+            "w" | "watch" => self.add_watchpoint(gameboy, &args, out),
+            "delete" => self.delete(gameboy, &args, out),
+            "breaks" => Ok(self.list_breakpoints(out)),
+            "r" | "regs" => Ok(show_registers(gameboy, out)),
+            "l" | "list" => self.list(gameboy, &args, out),
+            "x" => examine(gameboy, &args, out),
+            "set" => set(gameboy, &args),
+            "bt" | "backtrace" => Ok(self.backtrace(gameboy, out)),
+            "h" | "help" => Ok(writeln!(out, "{}", HELP)),
+            "q" | "quit" => return Ok(false),
+            _ => Err(format!("Unknown command '{}', try 'help'", command)),
+        };
+        match result {
+            Ok(output) => output?,
+            Err(message) => writeln!(out, "error: {}", message)?,
+        }
+        Ok(true)
+    }
+
+    /// Steps until `done` says so, a breakpoint is reached, emulation fails
+    /// or the user pauses, then shows where execution stopped. Returns
+    /// whether it was `done` that stopped it. The first instruction always
+    /// runs so that execution can leave a breakpoint.
+    fn run<W: Write, F>(&mut self, gameboy: &mut Gameboy, out: &mut W, mut done: F) -> io::Result<bool>
+        where F: FnMut(&Debugger, &Gameboy) -> bool
+    {
+        let mut poll_at = gameboy.cycles() + POLL_CYCLES;
+        let mut finished = false;
+        loop {
+            if let Err(e) = gameboy.step() {
+                writeln!(out, "error: {}", e)?;
+                break;
+            }
+            if let Some(hit) = gameboy.take_watch_hit() {
+                self.show_watch_hit(&hit, out)?;
+                break;
+            }
+            let problems = gameboy.take_stack_problems();
+            if !problems.is_empty() {
+                for problem in problems {
+                    writeln!(out, "Stack problem: {}", problem)?;
+                }
+                break;
+            }
+            if let Some(id) = self.breakpoint_hit(gameboy) {
+                writeln!(out, "Breakpoint {}", id)?;
+                break;
+            }
+            if done(self, gameboy) {
+                finished = true;
+                break;
+            }
+            if gameboy.cycles() >= poll_at {
+                poll_at = gameboy.cycles() + POLL_CYCLES;
+                if self.interrupt.swap(false, Ordering::SeqCst) {
+                    writeln!(out, "Paused")?;
+                    break;
+                }
+            }
+        }
+        if let CpuState::Locked { pc, opcode } = gameboy.cpu_state() {
+            writeln!(out, "CPU locked up on illegal opcode {:#04x} at {:#06x}", opcode, pc)?;
+        }
+        self.show_location(gameboy, out)?;
+        Ok(finished)
+    }
+
+    /// Steps over calls and RSTs by running until the call stack is back
+    /// to where it was, which also covers calls that aren't taken.
+    fn next<W: Write>(&mut self, gameboy: &mut Gameboy, out: &mut W) -> io::Result<bool> {
+        let depth = gameboy.call_depth();
+        self.run(gameboy, out, move |_, gameboy| gameboy.call_depth() <= depth)
+    }
+
+    fn finish<W: Write>(&mut self, gameboy: &mut Gameboy, out: &mut W) -> io::Result<()> {
+        let depth = gameboy.call_depth();
+        if depth == 0 {
+            return writeln!(out, "error: No calls have been seen since the debugger started, so there's nothing to return from");
+        }
+        self.run(gameboy, out, move |_, gameboy| gameboy.call_depth() < depth).map(drop)
+    }
+
+    fn breakpoint_hit(&self, gameboy: &mut Gameboy) -> Option<usize> {
+        let pc = gameboy.registers().pc;
+        for breakpoint in &self.breakpoints {
+            let matches = match breakpoint.location {
+                Location::Address { bank, addr } => {
+                    addr == pc && bank.is_none_or(|bank| bank == gameboy.bank_at(pc))
+                }
+                Location::Opcode { opcode, prefixed } => {
+                    gameboy.read_memory(pc) == opcode
+                        && prefixed.is_none_or(|prefixed| gameboy.read_memory(pc.wrapping_add(1)) == prefixed)
+                }
+            };
+            if matches && breakpoint.condition.as_ref().is_none_or(|condition| condition.evaluate(gameboy)) {
+                return Some(breakpoint.id);
+            }
+        }
+        None
+    }
+
+    fn add_breakpoint<W: Write>(&mut self, gameboy: &Gameboy, args: &[&str], out: &mut W) -> Result<io::Result<()>, String> {
+        let (location, rest) = match args {
+            ["opcode", opcode, rest @ ..] => {
+                let opcode = parse_number(opcode)?;
+                let location = match opcode {
+                    0x00..=0xff => Location::Opcode { opcode: opcode as u8, prefixed: None },
+                    0xcb00..=0xcbff => Location::Opcode { opcode: 0xcb, prefixed: Some(opcode as u8) },
+                    _ => return Err(format!("{:#x} isn't an opcode", opcode)),
+                };
+                (location, rest)
+            }
+            [addr, rest @ ..] => (parse_location(gameboy.symbols(), addr)?, rest),
+            [] => return Err("Expected an address or 'opcode OP'".to_string()),
+        };
+        let condition = match rest {
+            [] => None,
+            ["if", ..] if rest.len() > 1 => Some(Condition::parse(&rest[1..].join(" "), gameboy.symbols())?),
+            _ => return Err(format!("Expected 'if CONDITION', got '{}'", rest.join(" "))),
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let description = args.join(" ");
+        let output = writeln!(out, "Breakpoint {}: {}", id, description);
+        self.breakpoints.push(Breakpoint { id, location, condition, description });
+        Ok(output)
+    }
+
+    fn add_watchpoint<W: Write>(&mut self, gameboy: &mut Gameboy, args: &[&str], out: &mut W) -> Result<io::Result<()>, String> {
+        let (kind, rest) = match args.split_first() {
+            Some((&"read", rest)) => (WatchKind::Read, rest),
+            Some((&"write", rest)) => (WatchKind::Write, rest),
+            Some((&"change", rest)) => (WatchKind::Change, rest),
+            _ => (WatchKind::Change, args),
+        };
+        let (addresses, value) = match rest {
+            [addresses] => (addresses, None),
+            [addresses, "==", value] => {
+                let value = parse_number(value)?;
+                if value > 0xff {
+                    return Err(format!("{:#x} doesn't fit in a byte", value));
+                }
+                (addresses, Some(value as u8))
+            }
+            _ => return Err("Expected 'watch [read|write|change] ADDR[-END] [== VALUE]'".to_string()),
+        };
+        let (start, end) = match addresses.split_once('-') {
+            Some((start, end)) => (parse_address(gameboy.symbols(), start)?, parse_address(gameboy.symbols(), end)?),
+            None => (parse_address(gameboy.symbols(), addresses)?, parse_address(gameboy.symbols(), addresses)?),
+        };
+        if end < start {
+            return Err(format!("Range {} ends before it starts", addresses));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let description = format!("{} {}", kind, rest.join(" "));
+        let output = writeln!(out, "Watchpoint {}: {}", id, description);
+        let watchpoint = Watchpoint { addresses: start..=end, kind, value };
+        gameboy.add_watchpoint(watchpoint.clone());
+        self.watches.push(Watch { id, watchpoint, description });
+        Ok(output)
+    }
+
+    fn show_watch_hit<W: Write>(&self, hit: &WatchHit, out: &mut W) -> io::Result<()> {
+        let watch = self.watches.iter()
+            .find(|watch| watch.watchpoint.matches(hit.kind, hit.addr, hit.old, hit.new));
+        match watch {
+            Some(watch) => writeln!(out, "Watchpoint {}: {}", watch.id, hit),
+            None => writeln!(out, "Watchpoint: {}", hit),
+        }
+    }
+
+    fn delete<W: Write>(&mut self, gameboy: &mut Gameboy, args: &[&str], out: &mut W) -> Result<io::Result<()>, String> {
+        let id = match args {
+            [id] => parse_number(id)? as usize,
+            _ => return Err("Expected a breakpoint or watchpoint number".to_string()),
+        };
+        if let Some(position) = self.watches.iter().position(|watch| watch.id == id) {
+            let watch = self.watches.remove(position);
+            gameboy.remove_watchpoint(&watch.watchpoint);
+            return Ok(writeln!(out, "Deleted watchpoint {}", id));
+        }
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        if self.breakpoints.len() == count {
+            return Err(format!("No breakpoint or watchpoint {}", id));
+        }
+        Ok(writeln!(out, "Deleted breakpoint {}", id))
+    }
+
+    fn list_breakpoints<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.breakpoints.is_empty() && self.watches.is_empty() {
+            return writeln!(out, "No breakpoints or watchpoints");
+        }
+        let mut entries: Vec<(usize, &str, &str)> = self.breakpoints.iter()
+            .map(|breakpoint| (breakpoint.id, "breakpoint", breakpoint.description.as_str()))
+            .chain(self.watches.iter().map(|watch| (watch.id, "watchpoint", watch.description.as_str())))
+            .collect();
+        entries.sort();
+        for (id, kind, description) in entries {
+            writeln!(out, "{:>3}  {:<10}  {}", id, kind, description)?;
+        }
+        Ok(())
+    }
+
+    fn show_location<W: Write>(&self, gameboy: &mut Gameboy, out: &mut W) -> io::Result<()> {
+        let pc = gameboy.registers().pc;
+        let instruction = gameboy.disassemble(pc);
+        let line = format_instruction(gameboy, &instruction);
+        match gameboy.symbols().and_then(|symbols| symbols.describe(gameboy.bank_at(pc), pc)) {
+            Some(name) => writeln!(out, "=> {}  ; in {}", line, name),
+            None => writeln!(out, "=> {}", line),
+        }
+    }
+
+    /// Lists `N` instructions from `ADDR`, or a few either side of PC.
+    fn list<W: Write>(&self, gameboy: &mut Gameboy, args: &[&str], out: &mut W) -> Result<io::Result<()>, String> {
+        let pc = gameboy.registers().pc;
+        let (start, count) = match args {
+            [] => {
+                let before = instructions_before(gameboy, pc, 3);
+                (before.first().map_or(pc, |instruction| instruction.addr), before.len() + 6)
+            }
+            [addr] => (parse_address(gameboy.symbols(), addr)?, 10),
+            [addr, count] => (parse_address(gameboy.symbols(), addr)?, parse_number(count)? as usize),
+            _ => return Err("Expected 'list [ADDR] [N]'".to_string()),
+        };
+
+        let mut addr = start;
+        let mut output = Ok(());
+        for _ in 0..count {
+            let instruction = gameboy.disassemble(addr);
+            let marker = if addr == pc { "=>" } else { "  " };
+            if let Some(name) = gameboy.symbols().and_then(|symbols| symbols.name_at(gameboy.bank_at(addr), addr)) {
+                output = output.and_then(|_| writeln!(out, "   {}:", name));
+            }
+            output = output.and_then(|_| writeln!(out, "{} {}", marker, format_instruction(gameboy, &instruction)));
+            addr = addr.wrapping_add(instruction.length as u16);
+        }
+        Ok(output)
+    }
+
+    fn backtrace<W: Write>(&self, gameboy: &mut Gameboy, out: &mut W) -> io::Result<()> {
+        writeln!(out, "#0  {}", describe(gameboy, gameboy.registers().pc))?;
+        let frames = gameboy.call_stack().map(|call_stack| call_stack.frames().to_vec()).unwrap_or_default();
+        for (i, frame) in frames.iter().rev().enumerate() {
+            let entered = if frame.interrupt { "interrupt" } else { "called" };
+            writeln!(out, "#{}  {}  {} {}, returns to {}", i + 1, describe(gameboy, frame.call_site),
+                     entered, describe(gameboy, frame.target), describe(gameboy, frame.return_addr))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `ADDR`, `BANK:ADDR` or a label. Labels in switchable ROM only
+/// match in their own bank.
+fn parse_location(symbols: Option<&Symbols>, text: &str) -> Result<Location, String> {
+    if let Some((bank, addr)) = text.split_once(':') {
+        return Ok(Location::Address { bank: Some(parse_number(bank)? as usize), addr: parse_number(addr)? });
+    }
+    if let Ok(addr) = parse_number(text) {
+        return Ok(Location::Address { bank: None, addr });
+    }
+    match symbols.and_then(|symbols| symbols.lookup(text)) {
+        Some((bank, addr)) if (0x4000..0x8000).contains(&addr) => Ok(Location::Address { bank: Some(bank), addr }),
+        Some((_, addr)) => Ok(Location::Address { bank: None, addr }),
+        None => Err(format!("'{}' is neither an address nor a known label", text)),
+    }
+}
+
+/// `addr` in hex, followed by the label it's in when there is one.
+fn describe(gameboy: &Gameboy, addr: u16) -> String {
+    match gameboy.symbols().and_then(|symbols| symbols.describe(gameboy.bank_at(addr), addr)) {
+        Some(name) => format!("{:04x} <{}>", addr, name),
+        None => format!("{:04x}", addr),
+    }
+}
+
+fn format_instruction(gameboy: &mut Gameboy, instruction: &Instruction) -> String {
+    let bytes: Vec<String> = (0..instruction.length as u16)
+        .map(|i| format!("{:02x}", gameboy.read_memory(instruction.addr.wrapping_add(i))))
+        .collect();
+    let text = match gameboy.symbols() {
+        Some(symbols) => symbols.symbolize(&instruction.text, gameboy.bank_at(0x4000)),
+        None => instruction.text.clone(),
+    };
+    format!("{:02x}:{:04x}  {:<8}  {}", gameboy.bank_at(instruction.addr), instruction.addr, bytes.join(" "), text)
+}
+
+/// Finds up to `count` instructions that end right at `addr`. Code can't be
+/// decoded backwards reliably, so this looks for the earliest start that
+/// lines up with `addr` when decoded forwards.
+fn instructions_before(gameboy: &mut Gameboy, addr: u16, count: usize) -> Vec<Instruction> {
+    for start in addr.saturating_sub(count as u16 * 3)..addr {
+        let mut instructions = Vec::new();
+        let mut current = Some(start);
+        while let Some(at) = current.filter(|&at| at < addr) {
+            let instruction = gameboy.disassemble(at);
+            // Running off the end of memory can't line up with `addr`
+            current = at.checked_add(instruction.length as u16);
+            instructions.push(instruction);
+        }
+        if current == Some(addr) {
+            let skip = instructions.len().saturating_sub(count);
+            return instructions.split_off(skip);
+        }
+    }
+    Vec::new()
+}
+
+fn show_registers<W: Write>(gameboy: &Gameboy, out: &mut W) -> io::Result<()> {
+    let registers = gameboy.registers();
+    let flag = |set: bool, name: char| if set { name } else { '-' };
+    writeln!(out, " a: {:02x}  f: {:02x} [{}{}{}{}]  bc: {:04x}  de: {:04x}  hl: {:04x}",
+             registers.a, registers.f,
+             flag(registers.zero(), 'Z'), flag(registers.subtract(), 'N'),
+             flag(registers.half_carry(), 'H'), flag(registers.carry(), 'C'),
+             registers.bc(), registers.de(), registers.hl())?;
+    writeln!(out, "sp: {:04x}  pc: {:04x}  ime: {}  state: {:?}  cycles: {}",
+             registers.sp, registers.pc, registers.ime as u8, gameboy.cpu_state(), registers.cycles)
+}
+
+fn examine<W: Write>(gameboy: &mut Gameboy, args: &[&str], out: &mut W) -> Result<io::Result<()>, String> {
+    let (start, length) = match args {
+        [addr] => (parse_address(gameboy.symbols(), addr)?, 16),
+        [addr, length] => (parse_address(gameboy.symbols(), addr)?, parse_number(length)?),
+        _ => return Err("Expected 'x ADDR [LEN]'".to_string()),
+    };
+    let bytes: Vec<u8> = (0..length).map(|i| gameboy.read_memory(start.wrapping_add(i))).collect();
+    let mut output = Ok(());
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        output = output.and_then(|_| writeln!(out, "{:04x}: {}", start.wrapping_add(i as u16 * 16), hex.join(" ")));
+    }
+    Ok(output)
+}
+
+fn set(gameboy: &mut Gameboy, args: &[&str]) -> Result<io::Result<()>, String> {
+    let (target, value) = match args {
+        [target, value] => (*target, parse_number(value)?),
+        _ => return Err("Expected 'set ADDR VALUE' or 'set REG VALUE'".to_string()),
+    };
+    match Register::parse(target) {
+        Some(register) => {
+            let mut registers = gameboy.registers();
+            register.set(&mut registers, value)?;
+            gameboy.set_registers(&registers);
+        }
+        None => {
+            let addr = parse_address(gameboy.symbols(), target)?;
+            if value > 0xff {
+                return Err(format!("{:#x} doesn't fit in a byte", value));
+            }
+            gameboy.write_memory(addr, value as u8);
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Reads commands from `input` until it ends or the user quits. Setting
+/// `interrupt`, as a Ctrl-C handler would, pauses the command running.
+pub fn run_repl<R: BufRead, W: Write>(gameboy: &mut Gameboy, input: R, out: &mut W,
+                                      interrupt: Arc<AtomicBool>) -> io::Result<()> {
+    let mut debugger = Debugger { interrupt, ..Debugger::new() };
+    writeln!(out, "Type 'help' for a list of commands")?;
+    debugger.show_location(gameboy, out)?;
+    write!(out, "(rust_boi) ")?;
+    out.flush()?;
+    for line in input.lines() {
+        // A Ctrl-C at the prompt has nothing to pause
+        debugger.interrupt.store(false, Ordering::SeqCst);
+        if !debugger.execute(gameboy, &line?, out)? {
+            break;
+        }
+        write!(out, "(rust_boi) ")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ROM with `code` at 0x0100, plus `routine` at 0x0150.
+    fn gameboy(code: &[u8], routine: &[u8]) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        rom[0x150..0x150 + routine.len()].copy_from_slice(routine);
+        rom[0x14d] = rom[0x134..0x14d].iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+        Gameboy::new(rom, None, None).unwrap()
+    }
+
+    fn execute(debugger: &mut Debugger, gameboy: &mut Gameboy, line: &str) -> String {
+        let mut out = Vec::new();
+        assert!(debugger.execute(gameboy, line, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn interrupt_pauses_a_command_that_never_finishes() {
+        // jr $0100, forever
+        let mut gameboy = gameboy(&[0x18, 0xfe], &[]);
+        let mut debugger = Debugger::new();
+        debugger.interrupt_flag().store(true, Ordering::SeqCst);
+        let output = execute(&mut debugger, &mut gameboy, "continue");
+        assert!(output.starts_with("Paused\n=> 00:0100"), "{}", output);
+        assert!(!debugger.interrupt_flag().load(Ordering::SeqCst));
+
+        // di, halt with nothing to wake it
+        let mut gameboy = self::gameboy(&[0xf3, 0x76], &[]);
+        debugger.interrupt_flag().store(true, Ordering::SeqCst);
+        let output = execute(&mut debugger, &mut gameboy, "c");
+        assert!(output.starts_with("Paused"), "{}", output);
+        assert_eq!(gameboy.cpu_state(), CpuState::Halted);
+    }
+
+    #[test]
+    fn next_stops_at_a_breakpoint() {
+        // call $0150, jr $0100 calling nop, ret
+        let mut gameboy = gameboy(&[0xcd, 0x50, 0x01, 0x18, 0xfb], &[0x00, 0xc9]);
+        let mut debugger = Debugger::new();
+        execute(&mut debugger, &mut gameboy, "break 0x151");
+        let output = execute(&mut debugger, &mut gameboy, "next 5");
+        assert_eq!(output, "Breakpoint 1\n=> 00:0151  c9        ret\n");
+        assert_eq!(gameboy.registers().pc, 0x151);
+
+        execute(&mut debugger, &mut gameboy, "delete 1");
+        let output = execute(&mut debugger, &mut gameboy, "next 3");
+        assert_eq!(output.lines().count(), 3, "{}", output);
+        assert_eq!(gameboy.registers().pc, 0x103);
+    }
+
+    fn symbols() -> Symbols {
+        Symbols::parse("00:0150 Routine\n02:4000 Banked\nc0:c000 wCount\n").unwrap()
+    }
+
+    #[test]
+    fn conditions_bind_and_tighter_than_or() {
+        let mut gameboy = gameboy(&[], &[]);
+        let mut registers = gameboy.registers();
+        registers.a = 3;
+        registers.set_hl(0xc000);
+        registers.set_zero(false);
+        gameboy.set_registers(&registers);
+        gameboy.write_memory(0xc000, 0x42);
+
+        let holds = |gameboy: &mut Gameboy, text: &str| Condition::parse(text, Some(&symbols())).unwrap().evaluate(gameboy);
+        assert!(holds(&mut gameboy, "a == 0x3"));
+        assert!(holds(&mut gameboy, "a==$3&&[hl]==66"));
+        assert!(holds(&mut gameboy, "[wCount] >= 0x42 && hl != 0 && zf == 0"));
+        assert!(holds(&mut gameboy, "zf == 1 && a == 3 || [0xc000] > 0x41"));
+        assert!(!holds(&mut gameboy, "[0xc000] > 0x41 && zf == 1"));
+        assert!(!holds(&mut gameboy, "a < 3 || a > 3"));
+        assert!(holds(&mut gameboy, "a <= 3 && [[hl]] == 0"));
+    }
+
+    #[test]
+    fn malformed_conditions_are_rejected() {
+        let error = |text: &str| Condition::parse(text, None).unwrap_err();
+        assert_eq!(error("a =="), "Condition ends too early");
+        assert_eq!(error("a = 3"), "Unexpected '=' in condition");
+        assert_eq!(error("a 3"), "Expected a comparison, got '3'");
+        assert_eq!(error("[hl == 1"), "Expected ']'");
+        assert_eq!(error("a == 3 b"), "Unexpected 'b' in condition");
+        assert_eq!(error("a == wCount"), "'wCount' is neither a number nor a known label");
+    }
+
+    #[test]
+    fn locations_take_a_bank_from_the_text_or_the_label() {
+        let symbols = symbols();
+        let location = |text: &str| parse_location(Some(&symbols), text);
+        assert!(matches!(location("0x150"), Ok(Location::Address { bank: None, addr: 0x150 })));
+        assert!(matches!(location("$150"), Ok(Location::Address { bank: None, addr: 0x150 })));
+        assert!(matches!(location("2:0x4010"), Ok(Location::Address { bank: Some(2), addr: 0x4010 })));
+        assert!(matches!(location("Routine"), Ok(Location::Address { bank: None, addr: 0x150 })));
+        assert!(matches!(location("Banked"), Ok(Location::Address { bank: Some(2), addr: 0x4000 })));
+        assert!(matches!(location("wCount"), Ok(Location::Address { bank: None, addr: 0xc000 })));
+        assert_eq!(location("Missing").unwrap_err(), "'Missing' is neither an address nor a known label");
+        assert_eq!(location("x:0x4000").unwrap_err(), "Invalid number 'x'");
+    }
+
+    #[test]
+    fn opcode_breakpoints() {
+        let mut gameboy = gameboy(&[], &[]);
+        let mut debugger = Debugger::new();
+        assert_eq!(execute(&mut debugger, &mut gameboy, "break opcode 0x76"), "Breakpoint 1: opcode 0x76\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "b opcode 0xcb37 if a == 0"), "Breakpoint 2: opcode 0xcb37 if a == 0\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "break opcode 0x100"), "error: 0x100 isn't an opcode\n");
+        assert!(matches!(debugger.breakpoints[0].location, Location::Opcode { opcode: 0x76, prefixed: None }));
+        assert!(matches!(debugger.breakpoints[1].location, Location::Opcode { opcode: 0xcb, prefixed: Some(0x37) }));
+        assert!(debugger.breakpoints[1].condition.is_some());
+    }
+
+    #[test]
+    fn conditional_breakpoints_only_stop_when_the_condition_holds() {
+        // call $0150, jr $0100 calling inc a, ret
+        let mut gameboy = gameboy(&[0xcd, 0x50, 0x01, 0x18, 0xfb], &[0x3c, 0xc9]);
+        let mut registers = gameboy.registers();
+        registers.a = 0;
+        gameboy.set_registers(&registers);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(execute(&mut debugger, &mut gameboy, "break 0x150 if a == 0x3"), "Breakpoint 1: 0x150 if a == 0x3\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "break 0x150 unless a == 0x3"),
+                   "error: Expected 'if CONDITION', got 'unless a == 0x3'\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "break 0x150 if"), "error: Expected 'if CONDITION', got 'if'\n");
+        let output = execute(&mut debugger, &mut gameboy, "continue");
+        assert_eq!(output, "Breakpoint 1\n=> 00:0150  3c        inc a\n");
+        assert_eq!(gameboy.registers().a, 3);
+    }
+
+    #[test]
+    fn set_changes_registers_flags_and_memory() {
+        let mut gameboy = gameboy(&[], &[]);
+        let mut debugger = Debugger::new();
+        gameboy.set_symbols(symbols());
+        for line in ["set hl 0xc000", "set a 7", "set f 0xff", "set cf 0", "set 0xc001 0x42", "set wCount $99"] {
+            assert_eq!(execute(&mut debugger, &mut gameboy, line), "");
+        }
+        let registers = gameboy.registers();
+        assert_eq!((registers.hl(), registers.a, registers.f), (0xc000, 7, 0xe0));
+        assert_eq!(gameboy.read_memory(0xc000), 0x99);
+        assert_eq!(gameboy.read_memory(0xc001), 0x42);
+
+        assert_eq!(execute(&mut debugger, &mut gameboy, "set a 0x100"), "error: 0x100 doesn't fit in A\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "set zf 2"), "error: 0x2 doesn't fit in Zf\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "set 0xc000 0x100"), "error: 0x100 doesn't fit in a byte\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "set a"), "error: Expected 'set ADDR VALUE' or 'set REG VALUE'\n");
+        assert_eq!(gameboy.registers().a, 7);
+    }
+
+    #[test]
+    fn examine_shows_sixteen_bytes_a_line() {
+        let mut gameboy = gameboy(&[], &[]);
+        let mut debugger = Debugger::new();
+        for i in 0..20 {
+            gameboy.write_memory(0xc000 + i, i as u8);
+        }
+        assert_eq!(execute(&mut debugger, &mut gameboy, "x 0xc000 4"), "c000: 00 01 02 03\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "x 0xc002 18"),
+                   "c002: 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f 10 11\nc012: 12 13\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "x 0xc000").lines().count(), 1);
+        assert_eq!(execute(&mut debugger, &mut gameboy, "x"), "error: Expected 'x ADDR [LEN]'\n");
+    }
+
+    #[test]
+    fn delete_removes_breakpoints_and_watchpoints_by_number() {
+        let mut gameboy = gameboy(&[], &[]);
+        let mut debugger = Debugger::new();
+        execute(&mut debugger, &mut gameboy, "break 0x150");
+        execute(&mut debugger, &mut gameboy, "watch write 0xc000-0xc00f");
+        execute(&mut debugger, &mut gameboy, "break 0x160");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "breaks"),
+                   "  1  breakpoint  0x150\n  2  watchpoint  write 0xc000-0xc00f\n  3  breakpoint  0x160\n");
+
+        assert_eq!(execute(&mut debugger, &mut gameboy, "delete 2"), "Deleted watchpoint 2\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "delete 1"), "Deleted breakpoint 1\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "delete 1"), "error: No breakpoint or watchpoint 1\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "delete"), "error: Expected a breakpoint or watchpoint number\n");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "breaks"), "  3  breakpoint  0x160\n");
+        execute(&mut debugger, &mut gameboy, "delete 3");
+        assert_eq!(execute(&mut debugger, &mut gameboy, "breaks"), "No breakpoints or watchpoints\n");
+    }
+}
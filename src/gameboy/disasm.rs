@@ -0,0 +1,273 @@
+use std::io::{self, Write};
+
+use super::symbols::Symbols;
+
+pub const BANK_SIZE: usize = 0x4000;
+
+const R: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const RP: [&str; 4] = ["bc", "de", "hl", "sp"];
+const RP2: [&str; 4] = ["bc", "de", "hl", "af"];
+const CC: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add a,", "adc a,", "sub a,", "sbc a,", "and a,", "xor a,", "or a,", "cp a,"];
+const ROT: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const ACCUMULATOR_OPS: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+/// One decoded instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    /// Length in bytes, including the 0xcb prefix
+    pub length: u8,
+    /// RGBDS syntax, e.g. `ld a, [hl+]` or `jr nz, $0150`
+    pub text: String,
+    /// Where a jump, call or RST goes, when that's known without running it
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    pub fn is_call(&self) -> bool {
+        self.text.starts_with("call") || self.text.starts_with("rst")
+    }
+
+    /// RET, conditional or not, and RETI.
+    pub fn is_return(&self) -> bool {
+        self.text.starts_with("ret")
+    }
+}
+
+/// Decodes the instruction at `addr`, whose bytes start `bytes`. Only as
+/// many bytes as the instruction is long are looked at. The 11 opcodes the
+/// SM83 doesn't define come out as `db`.
+pub fn decode(addr: u16, bytes: [u8; 3]) -> Instruction {
+    let [opcode, low, high] = bytes;
+    let n16 = u16::from(low) | (u16::from(high) << 8);
+    let relative = addr.wrapping_add(2).wrapping_add(low as i8 as u16);
+    let (x, y, z) = ((opcode >> 6) as usize, ((opcode >> 3) & 0x07) as usize, (opcode & 0x07) as usize);
+    let (p, q) = (y >> 1, y & 0x01);
+
+    let (length, text, target) = match (x, z) {
+        (0, 0) => match y {
+            0 => (1, "nop".to_string(), None),
+            1 => (3, format!("ld [${:04x}], sp", n16), None),
+            2 => (2, "stop".to_string(), None),
+            3 => (2, format!("jr ${:04x}", relative), Some(relative)),
+            _ => (2, format!("jr {}, ${:04x}", CC[y - 4], relative), Some(relative)),
+        },
+        (0, 1) if q == 0 => (3, format!("ld {}, ${:04x}", RP[p], n16), None),
+        (0, 1) => (1, format!("add hl, {}", RP[p]), None),
+        (0, 2) => {
+            let pointer = ["[bc]", "[de]", "[hl+]", "[hl-]"][p];
+            let text = if q == 0 { format!("ld {}, a", pointer) } else { format!("ld a, {}", pointer) };
+            (1, text, None)
+        }
+        (0, 3) => (1, format!("{} {}", if q == 0 { "inc" } else { "dec" }, RP[p]), None),
+        (0, 4) => (1, format!("inc {}", R[y]), None),
+        (0, 5) => (1, format!("dec {}", R[y]), None),
+        (0, 6) => (2, format!("ld {}, ${:02x}", R[y], low), None),
+        (0, _) => (1, ACCUMULATOR_OPS[y].to_string(), None),
+        (1, _) if y == 6 && z == 6 => (1, "halt".to_string(), None),
+        (1, _) => (1, format!("ld {}, {}", R[y], R[z]), None),
+        (2, _) => (1, format!("{} {}", ALU[y], R[z]), None),
+        (3, 0) => match y {
+            0..=3 => (1, format!("ret {}", CC[y]), None),
+            4 => (2, format!("ldh [$ff{:02x}], a", low), None),
+            5 => (2, format!("add sp, {}", low as i8), None),
+            6 => (2, format!("ldh a, [$ff{:02x}]", low), None),
+            _ => (2, format!("ld hl, sp + {}", low as i8), None),
+        },
+        (3, 1) if q == 0 => (1, format!("pop {}", RP2[p]), None),
+        (3, 1) => (1, ["ret", "reti", "jp hl", "ld sp, hl"][p].to_string(), None),
+        (3, 2) => match y {
+            0..=3 => (3, format!("jp {}, ${:04x}", CC[y], n16), Some(n16)),
+            4 => (1, "ldh [c], a".to_string(), None),
+            5 => (3, format!("ld [${:04x}], a", n16), None),
+            6 => (1, "ldh a, [c]".to_string(), None),
+            _ => (3, format!("ld a, [${:04x}]", n16), None),
+        },
+        (3, 3) => match y {
+            0 => (3, format!("jp ${:04x}", n16), Some(n16)),
+            1 => (2, decode_prefixed(low), None),
+            6 => (1, "di".to_string(), None),
+            7 => (1, "ei".to_string(), None),
+            _ => (1, format!("db ${:02x}", opcode), None),
+        },
+        (3, 4) if y < 4 => (3, format!("call {}, ${:04x}", CC[y], n16), Some(n16)),
+        (3, 5) if q == 0 => (1, format!("push {}", RP2[p]), None),
+        (3, 5) if p == 0 => (3, format!("call ${:04x}", n16), Some(n16)),
+        (3, 6) => (2, format!("{} ${:02x}", ALU[y], low), None),
+        (3, 7) => (1, format!("rst ${:02x}", y * 8), Some(y as u16 * 8)),
+        _ => (1, format!("db ${:02x}", opcode), None),
+    };
+
+    Instruction { addr, length, text, target }
+}
+
+fn decode_prefixed(opcode: u8) -> String {
+    let (x, y, z) = ((opcode >> 6) as usize, ((opcode >> 3) & 0x07) as usize, (opcode & 0x07) as usize);
+    match x {
+        0 => format!("{} {}", ROT[y], R[z]),
+        1 => format!("bit {}, {}", y, R[z]),
+        2 => format!("res {}, {}", y, R[z]),
+        _ => format!("set {}, {}", y, R[z]),
+    }
+}
+
+/// How many 16KB banks `rom` has, counting a partial last one.
+pub fn bank_count(rom: &[u8]) -> usize {
+    rom.len().div_ceil(BANK_SIZE)
+}
+
+/// The CPU address range `bank` is seen at: bank 0 is always at
+/// 0x0000-0x3fff and the others get switched in at 0x4000-0x7fff.
+pub fn bank_addresses(bank: usize) -> (u16, u16) {
+    if bank == 0 { (0x0000, 0x3fff) } else { (0x4000, 0x7fff) }
+}
+
+/// Writes a listing of `bank` of `rom` from `start` to `end`, inclusive,
+/// one instruction per line with its bank, address and bytes:
+///
+/// ```text
+/// 00:0150  c3 50 01  jp $0150
+/// 01:4000  3e 01     ld a, $01
+/// ```
+///
+/// Instructions never run past the end of the bank, since whatever comes
+/// next depends on the bank mapped then; bytes cut off there come out as `db`.
+/// With `symbols`, labels get a line of their own and addresses that have
+/// one are replaced by it.
+pub fn write_listing<W: Write>(out: &mut W, rom: &[u8], bank: usize, start: u16, end: u16,
+                               symbols: Option<&Symbols>) -> io::Result<()> {
+    let (bank_start, bank_end) = bank_addresses(bank);
+    let offset = bank * BANK_SIZE;
+    let byte_at = |addr: u16| rom.get(offset + (addr - bank_start) as usize).cloned();
+
+    let mut addr = start.max(bank_start);
+    let end = end.min(bank_end);
+    while addr <= end {
+        let opcode = match byte_at(addr) {
+            Some(opcode) => opcode,
+            None => break,
+        };
+        let length = decode(addr, [opcode, 0, 0]).length as u16;
+        let bytes: Option<Vec<u8>> = (0..length)
+            .map(|i| addr.checked_add(i).filter(|next| *next <= bank_end).and_then(byte_at))
+            .collect();
+        let (bytes, instruction) = match bytes {
+            Some(bytes) => {
+                let mut padded = [0; 3];
+                padded[..bytes.len()].copy_from_slice(&bytes);
+                let instruction = decode(addr, padded);
+                (bytes, instruction)
+            }
+            None => (vec![opcode], Instruction { addr, length: 1, text: format!("db ${:02x}", opcode), target: None }),
+        };
+
+        let mut text = instruction.text;
+        if let Some(symbols) = symbols {
+            if let Some(name) = symbols.name_at(bank, addr) {
+                writeln!(out, "{}:", name)?;
+            }
+            // Code in bank 0 can't know what's mapped at 0x4000, so it's
+            // taken to be bank 1
+            text = symbols.symbolize(&text, bank.max(1));
+        }
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        writeln!(out, "{:02x}:{:04x}  {:<8}  {}", bank, addr, hex.join(" "), text)?;
+        addr = match addr.checked_add(instruction.length as u16) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ILLEGAL: [u8; 11] = [0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd];
+
+    fn text(bytes: &[u8]) -> String {
+        let mut padded = [0; 3];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        decode(0x0150, padded).text
+    }
+
+    #[test]
+    fn illegal_opcodes_are_db() {
+        for opcode in 0..=0xffu8 {
+            let instruction = decode(0, [opcode, 0, 0]);
+            if ILLEGAL.contains(&opcode) {
+                assert_eq!(instruction.text, format!("db ${:02x}", opcode));
+                assert_eq!(instruction.length, 1);
+            } else {
+                assert!(!instruction.text.starts_with("db"), "{:#04x} decoded as {}", opcode, instruction.text);
+            }
+        }
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(text(&[0x00]), "nop");
+        assert_eq!(text(&[0x08, 0x00, 0xc0]), "ld [$c000], sp");
+        assert_eq!(text(&[0x21, 0x34, 0x12]), "ld hl, $1234");
+        assert_eq!(text(&[0x2a]), "ld a, [hl+]");
+        assert_eq!(text(&[0x36, 0x42]), "ld [hl], $42");
+        assert_eq!(text(&[0x76]), "halt");
+        assert_eq!(text(&[0x7e]), "ld a, [hl]");
+        assert_eq!(text(&[0xae]), "xor a, [hl]");
+        assert_eq!(text(&[0xe0, 0x44]), "ldh [$ff44], a");
+        assert_eq!(text(&[0xe8, 0xfe]), "add sp, -2");
+        assert_eq!(text(&[0xf8, 0x05]), "ld hl, sp + 5");
+        assert_eq!(text(&[0xf1]), "pop af");
+        assert_eq!(text(&[0xd9]), "reti");
+        assert_eq!(text(&[0xfe, 0x90]), "cp a, $90");
+        assert_eq!(decode(0, [0xfa, 0x00, 0xc0]).length, 3);
+    }
+
+    #[test]
+    fn prefixed_instructions() {
+        assert_eq!(text(&[0xcb, 0x00]), "rlc b");
+        assert_eq!(text(&[0xcb, 0x37]), "swap a");
+        assert_eq!(text(&[0xcb, 0x3e]), "srl [hl]");
+        assert_eq!(text(&[0xcb, 0x7c]), "bit 7, h");
+        assert_eq!(text(&[0xcb, 0x86]), "res 0, [hl]");
+        assert_eq!(text(&[0xcb, 0xff]), "set 7, a");
+        assert_eq!(decode(0, [0xcb, 0x11, 0]).length, 2);
+    }
+
+    #[test]
+    fn targets() {
+        let target = |addr: u16, bytes: [u8; 3]| decode(addr, bytes).target;
+        // Relative jumps count from the end of the instruction
+        assert_eq!(target(0x0150, [0x18, 0xfe, 0]), Some(0x0150));
+        assert_eq!(target(0x0150, [0x20, 0x10, 0]), Some(0x0162));
+        assert_eq!(target(0x0150, [0x38, 0x80, 0]), Some(0x00d2));
+        assert_eq!(target(0xfffe, [0x18, 0x01, 0]), Some(0x0001));
+        assert_eq!(target(0x0001, [0x18, 0xf0, 0]), Some(0xfff3));
+        assert_eq!(text(&[0x28, 0x05]), "jr z, $0157");
+
+        assert_eq!(target(0, [0xc3, 0x50, 0x01]), Some(0x0150));
+        assert_eq!(target(0, [0xcd, 0x00, 0x40]), Some(0x4000));
+        assert_eq!(target(0, [0xdc, 0x00, 0x40]), Some(0x4000));
+        assert_eq!(target(0, [0xef, 0, 0]), Some(0x28));
+        assert_eq!(target(0, [0xe9, 0, 0]), None);
+
+        assert!(decode(0, [0xc4, 0, 0]).is_call() && decode(0, [0xff, 0, 0]).is_call());
+        assert!(decode(0, [0xc8, 0, 0]).is_return() && decode(0, [0xd9, 0, 0]).is_return());
+        assert!(!decode(0, [0xc3, 0, 0]).is_call() && !decode(0, [0xc3, 0, 0]).is_return());
+    }
+
+    #[test]
+    fn listing_stops_at_the_end_of_the_bank() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x3ffe..0x4002].copy_from_slice(&[0x00, 0xc3, 0x3e, 0x01]);
+        let mut out = Vec::new();
+        write_listing(&mut out, &rom, 0, 0x3ffe, 0xffff, None).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "00:3ffe  00        nop\n00:3fff  c3        db $c3\n");
+
+        let mut out = Vec::new();
+        write_listing(&mut out, &rom, 1, 0x4000, 0x4001, None).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "01:4000  3e 01     ld a, $01\n");
+    }
+}
@@ -0,0 +1,38 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum EmulatorError {
+    InvalidRom(String),
+    InvalidBootrom(String),
+    /// A save state that's damaged or was taken from another ROM or model
+    InvalidSaveState(String),
+    UnsupportedCartridge(u8),
+    /// One of the 11 opcodes the SM83 doesn't define
+    IllegalOpcode { pc: u16, opcode: u8 },
+    /// A real instruction the CPU doesn't emulate yet. `prefixed` is set
+    /// for opcodes following 0xcb.
+    UnimplementedOpcode { pc: u16, opcode: u8, prefixed: bool },
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::InvalidRom(reason) => write!(f, "Invalid ROM: {}", reason),
+            EmulatorError::InvalidBootrom(reason) => write!(f, "Invalid bootrom: {}", reason),
+            EmulatorError::InvalidSaveState(reason) => write!(f, "Invalid save state: {}", reason),
+            EmulatorError::UnsupportedCartridge(kind) => {
+                write!(f, "Unsupported cartridge type {:#04x}, only ROM ONLY (0x00) cartridges are supported", kind)
+            }
+            EmulatorError::IllegalOpcode { pc, opcode } => {
+                write!(f, "Illegal opcode {:#04x} at {:#06x}", opcode, pc)
+            }
+            EmulatorError::UnimplementedOpcode { pc, opcode, prefixed } => {
+                let prefix = if *prefixed { "0xcb " } else { "" };
+                write!(f, "Unimplemented opcode {}{:#04x} at {:#06x}", prefix, opcode, pc)
+            }
+        }
+    }
+}
+
+impl Error for EmulatorError {}
@@ -1,22 +1,764 @@
-use super::cpu::Cpu;
-use super::memory::Memory;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use super::bess;
+use super::boot;
+use super::callstack::{CallStack, StackProblem};
+use super::cartridge::Header;
+use super::cpu::{Cpu, CpuState, IllegalOpcodeMode, Registers};
+use super::disasm::{self, Instruction};
+use super::error::EmulatorError;
+use super::memory::{Button, Memory};
+use super::model::Model;
+use super::rewind::{Input, Rewind};
+use super::run::{RunSummary, StopReason};
+use super::savestate::{self, SaveState, StateWriter};
+use super::ppu::{Ppu, SCREEN_WIDTH, SCREEN_HEIGHT};
+use super::profiler::Profiler;
+use super::sgb::{SGB_WIDTH, SGB_HEIGHT};
+use super::symbols::Symbols;
+use super::trace::Trace;
+use super::utils::{bit_is_set, crc32};
+use super::watchpoint::{WatchHit, Watchpoint};
+
+const DMG_SHADES: [u32; 4] = [0xffffff, 0xaaaaaa, 0x555555, 0x000000];
+const KEY1: usize = 0x4d;
+/// The CPU is paused for 2050 machine cycles while the CGB changes speed
+const SPEED_SWITCH_CYCLES: u32 = 2050 * 4;
+
+/// Called with the registers after every LD B,B; returns whether to stop.
+type LdBBHook = Box<dyn FnMut(&Registers) -> bool + Send>;
 
 pub struct Gameboy {
+    header: Header,
+    rom_crc: u32,
+    bootrom_crc: Option<u32>,
+    model: Model,
     cpu: Cpu,
-    memory: Memory
+    memory: Memory,
+    ppu: Ppu,
+    cycles: u64,
+    breakpoints: HashSet<u16>,
+    watch_hit: Option<WatchHit>,
+    ld_b_b_hook: Option<LdBBHook>,
+    ld_b_b_stop: Option<u16>,
+    rewind: Option<Rewind>,
+    symbols: Option<Symbols>,
+    trace: Option<Trace>,
+    profiler: Option<Profiler>,
 }
 
 impl Gameboy {
-    pub fn new(rom: Vec<u8>, bootrom: Vec<u8>) -> Self {
-        Gameboy {
-            cpu: Cpu::new(),
-            memory: Memory::new(rom, bootrom)
+    /// Builds a machine for `rom`. Without an explicit `model` the one the
+    /// cartridge header asks for is used; SGB features are only switched on
+    /// when both the model and the header support them. Without a `bootrom`
+    /// the machine starts at 0x0100 in the state the model's bootrom leaves.
+    pub fn new(rom: Vec<u8>, bootrom: Option<Vec<u8>>, model: Option<Model>) -> Result<Self, EmulatorError> {
+        let header = Header::parse(&rom)?;
+        let model = model.unwrap_or_else(|| Model::for_cartridge(&header));
+        let sgb_mode = model.is_sgb() && header.sgb_support;
+        let rom_crc = crc32(rom.iter());
+
+        let bootrom_crc = bootrom.as_ref().map(|bootrom| crc32(bootrom.iter()));
+        let skip_boot = bootrom.is_none();
+        let mut memory = Memory::new(rom, bootrom, sgb_mode)?;
+        let cpu = if skip_boot {
+            for (register, value) in boot::post_boot_io(model) {
+                memory.set_io_register(register, value);
+            }
+            boot::post_boot_cpu(model, &header)
+        } else {
+            Cpu::new()
+        };
+
+        Ok(Gameboy {
+            header,
+            rom_crc,
+            bootrom_crc,
+            model,
+            cpu,
+            memory,
+            ppu: Ppu::new(),
+            cycles: 0,
+            breakpoints: HashSet::new(),
+            watch_hit: None,
+            ld_b_b_hook: None,
+            ld_b_b_stop: None,
+            rewind: None,
+            symbols: None,
+            trace: None,
+            profiler: None,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// CRC-32 of the whole ROM image.
+    pub fn rom_crc(&self) -> u32 {
+        self.rom_crc
+    }
+
+    /// CRC-32 of the bootrom, or `None` when boot was skipped.
+    pub fn bootrom_crc(&self) -> Option<u32> {
+        self.bootrom_crc
+    }
+
+    /// Executes one instruction, keeping the PPU in step with it.
+    /// Returns the number of clock cycles the CPU spent on it; in CGB double
+    /// speed mode the rest of the machine sees half as many.
+    pub fn step(&mut self) -> Result<u32, EmulatorError> {
+        if self.trace.is_some() {
+            self.trace_instruction();
+        }
+        let pc = self.cpu.pc();
+        let profiled = if self.profiler.is_some() { Some(self.profile_start()) } else { None };
+        let result = self.cpu.step(&mut self.memory);
+        if let Some((kind, addr, old, new)) = self.memory.take_watch_hit() {
+            self.watch_hit = Some(WatchHit { kind, addr, old, new, pc, bank: self.memory.bank_at(pc) });
+        }
+        let cycles = result?;
+        if self.cpu.take_ld_b_b() {
+            let registers = self.cpu.registers();
+            if self.ld_b_b_hook.as_mut().is_some_and(|hook| hook(&registers)) {
+                self.ld_b_b_stop = Some(pc);
+            }
+        }
+        if let Some(start) = profiled {
+            self.profile_step(start, cycles);
         }
+        if self.cpu.state() == CpuState::Stopped {
+            return Ok(self.stopped(cycles));
+        }
+
+        let elapsed = if self.double_speed() { cycles / 2 } else { cycles };
+        let entered_vblank = self.ppu.step(&mut self.memory, elapsed);
+        if entered_vblank {
+            if let Some(sgb) = self.memory.sgb_mut() {
+                sgb.composite(self.ppu.framebuffer());
+            }
+        }
+        self.cycles += elapsed as u64;
+
+        let frame = self.frames();
+        if entered_vblank && self.rewind.as_ref().is_some_and(|rewind| frame.is_multiple_of(rewind.interval())) {
+            let state = self.snapshot();
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.push(frame, state);
+            }
+        }
+        Ok(cycles)
+    }
+
+    /// The PC and how deep in calls the CPU is before a step, and whether
+    /// it runs an instruction.
+    fn profile_start(&mut self) -> (u16, usize, bool) {
+        (self.cpu.pc(), self.call_depth(), self.cpu.executes_next(&mut self.memory))
+    }
+
+    /// Hands a step to the profiler, with the calls and returns it made
+    /// taken from the CPU's call stack. A step that runs no instruction but
+    /// enters a routine is an interrupt being dispatched.
+    fn profile_step(&mut self, (pc, depth, executed): (u16, usize, bool), cycles: u32) {
+        let (frame, bank) = (self.frames(), self.memory.bank_at(pc));
+        let frames = self.cpu.call_stack().map_or(&[][..], CallStack::frames);
+        let entered = frames.get(depth..).and_then(|entered| entered.last())
+            .map(|entered| (entered.target, entered.interrupt, self.memory.bank_at(entered.target)));
+        let returns = depth.saturating_sub(frames.len());
+        let profiler = match self.profiler.as_mut() {
+            Some(profiler) => profiler,
+            None => return,
+        };
+
+        profiler.start_frame(frame);
+        if !executed {
+            match entered {
+                Some((vector, true, _)) => profiler.interrupt(vector, cycles),
+                _ => profiler.idle(cycles),
+            }
+            return;
+        }
+        profiler.instruction(bank, pc, cycles);
+        if let Some((target, _, target_bank)) = entered {
+            profiler.call(target_bank, target);
+        }
+        for _ in 0..returns {
+            profiler.ret();
+        }
+    }
+
+    fn trace_instruction(&mut self) {
+        if !self.cpu.executes_next(&mut self.memory) {
+            return;
+        }
+        let registers = self.cpu.registers();
+        let mut pc_memory = [0; 4];
+        for (i, byte) in pc_memory.iter_mut().enumerate() {
+            *byte = self.memory.peek_u8(registers.pc.wrapping_add(i as u16));
+        }
+        let (bank, cycles, bootrom_mapped) = (self.memory.bank_at(registers.pc), self.cycles, self.memory.bootrom_mapped());
+        if let Some(trace) = self.trace.as_mut() {
+            let logged = trace.log(&registers, pc_memory, bank, cycles, bootrom_mapped);
+            if let Err(e) = logged {
+                warn!("Stopping the trace, it can't be written: {}", e);
+                self.trace = None;
+            } else if trace.is_done(cycles) {
+                if let Err(e) = self.finish_trace() {
+                    warn!("Could not finish the trace: {}", e);
+                }
+            }
+        }
+    }
+
+    fn double_speed(&self) -> bool {
+        self.model == Model::Cgb && bit_is_set(self.memory.io_register(KEY1), 7)
+    }
+
+    /// STOP either performs a CGB speed switch armed through KEY1 or puts
+    /// the machine in low power mode, with the LCD off, until a joypad line
+    /// selected in P1 goes low.
+    fn stopped(&mut self, cycles: u32) -> u32 {
+        let key1 = self.memory.io_register(KEY1);
+        if self.model == Model::Cgb && bit_is_set(key1, 0) {
+            self.memory.set_io_register(KEY1, (key1 ^ 0x80) & 0xfe);
+            self.cpu.resume();
+            self.cycles += (cycles + SPEED_SWITCH_CYCLES) as u64;
+            return cycles + SPEED_SWITCH_CYCLES;
+        }
+
+        if self.memory.peek_u8(0xff00) & 0x0f != 0x0f {
+            self.cpu.resume();
+        }
+        self.ppu.idle(cycles);
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    /// Runs until the next VBlank starts.
+    pub fn run_frame(&mut self) -> RunSummary {
+        let frame = self.frames();
+        self.run_while(|gameboy| {
+            if gameboy.frames() != frame { Some(StopReason::FrameComplete) } else { None }
+        })
+    }
+
+    /// Runs for at least `cycles` clock cycles, stopping at the end of the
+    /// instruction that crosses the limit.
+    pub fn run_cycles(&mut self, cycles: u64) -> RunSummary {
+        let start = self.cycles;
+        self.run_while(|gameboy| {
+            if gameboy.cycles - start >= cycles { Some(StopReason::CyclesElapsed) } else { None }
+        })
+    }
+
+    /// Runs until `predicate` returns true. It's checked after every instruction.
+    pub fn run_until<F: FnMut(&Gameboy) -> bool>(&mut self, mut predicate: F) -> RunSummary {
+        self.run_while(|gameboy| {
+            if predicate(gameboy) { Some(StopReason::Predicate) } else { None }
+        })
+    }
+
+    /// Steps until `stop` gives a reason to stop, an error occurs, a
+    /// breakpoint is reached, a watchpoint fires or the LD B,B hook asks
+    /// to stop. The first instruction
+    /// always runs so that execution can resume from a breakpoint.
+    fn run_while<F: FnMut(&Gameboy) -> Option<StopReason>>(&mut self, mut stop: F) -> RunSummary {
+        let (start_cycles, start_frames) = (self.cycles, self.frames());
+        let mut first = true;
+        self.watch_hit = None;
+        self.ld_b_b_stop = None;
+        let stop = loop {
+            let pc = self.cpu.registers().pc;
+            if !first && self.breakpoints.contains(&pc) {
+                break StopReason::Breakpoint(pc);
+            }
+            first = false;
+            if let Err(e) = self.step() {
+                break StopReason::Error(e);
+            }
+            if let Some(hit) = self.watch_hit.take() {
+                break StopReason::Watchpoint(hit);
+            }
+            if let Some(pc) = self.ld_b_b_stop.take() {
+                break StopReason::LdBB(pc);
+            }
+            if let Some(reason) = stop(self) {
+                break reason;
+            }
+        };
+        RunSummary {
+            cycles: self.cycles - start_cycles,
+            frames: self.frames() - start_frames,
+            stop,
+        }
+    }
+
+    /// Snapshots the whole machine. Breakpoints, the illegal opcode mode and
+    /// the serial output aren't part of it. The state ends with a BESS copy
+    /// so that other emulators can load it too.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = self.native_state();
+        state.section(b"BESS", |state| bess::save(state, self.model, &self.cpu, &self.memory));
+        state.finish()
+    }
+
+    /// CRC-32 of the machine's state, for checking that two runs are still
+    /// in step.
+    pub fn state_hash(&self) -> u32 {
+        crc32(self.snapshot().iter())
+    }
+
+    /// A save state without the BESS copy, for keeping in memory.
+    fn snapshot(&self) -> Vec<u8> {
+        self.native_state().finish()
+    }
+
+    fn native_state(&self) -> StateWriter {
+        let mut state = StateWriter::new(self.rom_crc, self.header.checksum, self.model);
+        state.section(b"CPU ", |state| self.cpu.save_state(state));
+        state.section(b"MEM ", |state| self.memory.save_state(state));
+        state.section(b"PPU ", |state| self.ppu.save_state(state));
+        state.section(b"GB  ", |state| state.u64(self.cycles));
+        state
+    }
+
+    /// Restores a snapshot taken by `save_state`, or a BESS state from
+    /// another emulator. States from another ROM or model are rejected, and
+    /// the machine is left untouched when anything is wrong with the state.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), EmulatorError> {
+        if !data.starts_with(savestate::MAGIC) && bess::is_bess(data) {
+            let model = self.model;
+            return self.restore(|cpu, memory, ppu| bess::load(data, model, cpu, memory, ppu));
+        }
+
+        let state = SaveState::parse(data)?;
+        if state.rom_crc != self.rom_crc || state.header_checksum != self.header.checksum {
+            return Err(EmulatorError::InvalidSaveState(format!(
+                "taken from another ROM (CRC32 {:08x}, this one is {:08x})",
+                state.rom_crc, self.rom_crc)));
+        }
+        if state.model != self.model {
+            return Err(EmulatorError::InvalidSaveState(format!(
+                "taken on {}, this machine is a {}", state.model, self.model)));
+        }
+
+        let cycles = state.section(b"GB  ")?.u64()?;
+        self.restore(|cpu, memory, ppu| {
+            cpu.load_state(&mut state.section(b"CPU ")?)?;
+            memory.load_state(&mut state.section(b"MEM ")?)?;
+            ppu.load_state(&mut state.section(b"PPU ")?)
+        })?;
+        self.cycles = cycles;
+        Ok(())
+    }
+
+    /// Runs `load` on copies of the components and only keeps them if it succeeds.
+    fn restore<F>(&mut self, load: F) -> Result<(), EmulatorError>
+        where F: FnOnce(&mut Cpu, &mut Memory, &mut Ppu) -> Result<(), EmulatorError>
+    {
+        let (mut cpu, mut memory, mut ppu) = (self.cpu.clone(), self.memory.clone(), self.ppu.clone());
+        load(&mut cpu, &mut memory, &mut ppu)?;
+        if let Some(call_stack) = cpu.call_stack_mut() {
+            call_stack.clear();
+        }
+        self.cpu = cpu;
+        self.memory = memory;
+        self.ppu = ppu;
+        Ok(())
+    }
+
+    /// Runs up to the start of frame `target`, setting the buttons to each
+    /// of `inputs` once its clock cycle is reached, with everything that
+    /// watches execution switched off.
+    fn replay(&mut self, target: u64, inputs: Vec<Input>) -> Result<(), EmulatorError> {
+        let hook = self.ld_b_b_hook.take();
+        let trace = self.trace.take();
+        let profiler = self.profiler.take();
+        let call_stack = self.cpu.call_stack().cloned();
+        self.cpu.set_call_stack(None);
+        let watchpoints = self.memory.take_watchpoints();
+
+        let mut inputs = inputs.into_iter().peekable();
+        let mut result = Ok(());
+        while self.frames() < target {
+            while let Some(input) = inputs.next_if(|input| input.cycles <= self.cycles) {
+                self.memory.set_buttons(input.buttons);
+            }
+            if let Err(e) = self.step() {
+                result = Err(e);
+                break;
+            }
+        }
+
+        self.memory.set_watchpoints(watchpoints);
+        self.watch_hit = None;
+        self.ld_b_b_stop = None;
+        self.cpu.set_call_stack(call_stack.map(|mut call_stack| {
+            call_stack.clear();
+            call_stack
+        }));
+        self.ld_b_b_hook = hook;
+        self.trace = trace;
+        self.profiler = profiler;
+        result
+    }
+
+    /// Captures a state at the start of VBlank every `interval` frames so
+    /// that `rewind` can go back to it, keeping at most `budget` bytes of them.
+    pub fn enable_rewind(&mut self, interval: u64, budget: usize) {
+        self.rewind = Some(Rewind::new(interval, budget));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    /// Goes back `frames` frames, or as far as the rewind buffer reaches,
+    /// and returns how many frames it went back. The closest state before
+    /// the target is restored and the frames in between are run again,
+    /// changing the buttons when they changed the first time round. The
+    /// replay doesn't stop at breakpoints or watchpoints, call the LD B,B
+    /// hook, trace, profile or check the stack. The serial output isn't
+    /// rewound.
+    pub fn rewind(&mut self, frames: u64) -> Result<u64, EmulatorError> {
+        let now = self.frames();
+        let target = now.saturating_sub(frames);
+        // Taking the buffer out also keeps the replay from capturing states
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) if frames > 0 => rewind,
+            rewind => {
+                self.rewind = rewind;
+                return Ok(0);
+            }
+        };
+
+        let mut result = Ok(());
+        while let Some((frame, state)) = rewind.pop() {
+            if frame <= target || rewind.is_empty() {
+                result = self.load_state(&state);
+                // It's still in the past, so it stays available
+                rewind.push(frame, state);
+                break;
+            }
+        }
+        if result.is_ok() {
+            let inputs = rewind.inputs_since(self.cycles);
+            result = self.replay(target, inputs);
+            rewind.truncate_inputs(self.cycles);
+        }
+
+        self.rewind = Some(rewind);
+        result.map(|_| now.saturating_sub(self.frames()))
+    }
+
+    /// Makes the `run_*` methods stop before executing the instruction at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Makes the `run_*` methods stop after any instruction that accesses
+    /// memory the way `watchpoint` describes.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.memory.add_watchpoint(watchpoint);
     }
 
-    pub fn run(&mut self) {
+    /// Removes one watchpoint equal to `watchpoint`, returning whether
+    /// there was one.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        self.memory.remove_watchpoint(watchpoint)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.memory.watchpoints()
+    }
+
+    /// Takes the watchpoint hit made by the last instruction `step` ran,
+    /// for callers stepping the machine themselves.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    /// Runs until the emulated program hits something the emulator can't handle.
+    pub fn run(&mut self) -> Result<(), EmulatorError> {
         loop {
-            self.cpu.step(&mut self.memory);
+            self.step()?;
+        }
+    }
+
+    pub fn set_illegal_opcode_mode(&mut self, mode: IllegalOpcodeMode) {
+        self.cpu.set_illegal_opcode_mode(mode);
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.ppu.frames()
+    }
+
+    /// The picture as it would be displayed, as (width, height, 0x00RRGGBB
+    /// pixels). In SGB mode this is the 256x224 picture including the border.
+    pub fn screen(&mut self) -> (usize, usize, Vec<u32>) {
+        if let Some(sgb) = self.memory.sgb_mut() {
+            return (SGB_WIDTH, SGB_HEIGHT, sgb.output().to_vec());
+        }
+        let pixels = self.ppu.framebuffer().iter().map(|shade| DMG_SHADES[*shade as usize]).collect();
+        (SCREEN_WIDTH, SCREEN_HEIGHT, pixels)
+    }
+
+    /// The last frame as DMG shades, 0 (lightest) to 3 (darkest), one byte
+    /// per pixel in rows of `SCREEN_WIDTH`.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.ppu.framebuffer()
+    }
+
+    /// Starts logging every instruction to `trace`, replacing any trace
+    /// already running.
+    pub fn start_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
+    /// Stops the trace and writes out whatever is still buffered.
+    pub fn finish_trace(&mut self) -> io::Result<()> {
+        match self.trace.take() {
+            Some(mut trace) => trace.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls `hook` with the registers every time the program executes
+    /// LD B,B, which does nothing else and which test suites such as
+    /// mooneye's and debuggers such as BGB use as a signal. When `hook`
+    /// returns true the running `run_*` method stops with
+    /// `StopReason::LdBB`.
+    pub fn set_ld_b_b_hook<F>(&mut self, hook: F)
+        where F: FnMut(&Registers) -> bool + Send + 'static
+    {
+        self.ld_b_b_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_ld_b_b_hook(&mut self) {
+        self.ld_b_b_hook = None;
+    }
+
+    /// Starts keeping a shadow call stack to catch the program corrupting
+    /// its stack: returns that don't go back to where the routine was called
+    /// from or happen with SP moved, and, with a `region` for the stack,
+    /// SP leaving it.
+    pub fn check_stack(&mut self, region: Option<RangeInclusive<u16>>) {
+        self.cpu.set_call_stack(Some(CallStack::new(region)));
+    }
+
+    /// Has the CPU keep track of the routines it's in, without checking
+    /// the stack, unless it already keeps a call stack.
+    pub fn track_calls(&mut self) {
+        if self.cpu.call_stack().is_none() {
+            self.cpu.set_call_stack(Some(CallStack::tracking()));
+        }
+    }
+
+    /// The call stack kept since `check_stack` or `track_calls`.
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.cpu.call_stack()
+    }
+
+    /// How many routines the CPU is in, or 0 when it isn't tracking calls.
+    pub fn call_depth(&self) -> usize {
+        self.cpu.call_stack().map_or(0, |call_stack| call_stack.frames().len())
+    }
+
+    /// The stack problems found since the last call, or none when stack
+    /// checking is off.
+    pub fn take_stack_problems(&mut self) -> Vec<StackProblem> {
+        self.cpu.call_stack_mut().map(CallStack::take_problems).unwrap_or_default()
+    }
+
+    /// Starts counting where execution goes, and which routines it goes
+    /// through. Profiling slows emulation down while it's on.
+    pub fn start_profiler(&mut self, profiler: Profiler) {
+        self.track_calls();
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Stops profiling and hands back what was counted.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    /// Labels for frontends to look up, e.g. in a debugger.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = Some(symbols);
+    }
+
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_ref()
+    }
+
+    /// Reads a byte the way the CPU would see it, without firing watchpoints.
+    pub fn read_memory(&mut self, addr: u16) -> u8 {
+        self.memory.peek_u8(addr)
+    }
+
+    /// The ROM bank currently mapped at `addr`, 0 outside ROM.
+    pub fn bank_at(&self, addr: u16) -> usize {
+        self.memory.bank_at(addr)
+    }
+
+    /// Decodes the instruction at `addr` as the CPU currently sees it.
+    pub fn disassemble(&mut self, addr: u16) -> Instruction {
+        let bytes = [
+            self.memory.peek_u8(addr),
+            self.memory.peek_u8(addr.wrapping_add(1)),
+            self.memory.peek_u8(addr.wrapping_add(2)),
+        ];
+        disasm::decode(addr, bytes)
+    }
+
+    /// Writes a byte the way the CPU would, side effects included, without
+    /// firing watchpoints.
+    pub fn write_memory(&mut self, addr: u16, value: u8) {
+        self.memory.poke_u8(addr, value);
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.cpu.set_registers(registers);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.memory.set_button(button, pressed);
+        self.record_input();
+    }
+
+    /// Held buttons, one bit each in `Button` order starting from bit 0.
+    pub fn buttons(&self) -> u8 {
+        self.memory.buttons()
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.memory.set_buttons(buttons);
+        self.record_input();
+    }
+
+    /// Logs the buttons for `rewind` to replay.
+    fn record_input(&mut self) {
+        let (frame, cycles, buttons) = (self.frames(), self.cycles, self.memory.buttons());
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.record_input(frame, cycles, buttons);
+        }
+    }
+
+    /// Every byte the game has sent out over the link cable.
+    pub fn serial_output(&self) -> &[u8] {
+        self.memory.serial_output()
+    }
+
+    /// Writes a hex dump of every RAM and IO region, one line per 16 bytes.
+    pub fn dump_memory<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.memory.dump_to(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gameboy(code: &[u8], model: Option<Model>) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        rom[0x14d] = rom[0x134..0x14d].iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+        Gameboy::new(rom, None, model).unwrap()
+    }
+
+    #[test]
+    fn without_a_bootrom_the_machine_starts_where_the_bootrom_leaves_it() {
+        let gameboy = gameboy(&[], Some(Model::Sgb));
+        assert_eq!(gameboy.registers(), boot::post_boot_cpu(Model::Sgb, gameboy.header()).registers());
+        assert!(!gameboy.memory.bootrom_mapped());
+        for (offset, value) in [(0x04, 0x00), (0x26, 0xf0), (0x40, 0x91), (0x50, 0x01)] {
+            assert_eq!(gameboy.memory.io_register(offset), value, "{:#04x}", offset);
         }
     }
+
+    #[test]
+    fn stop_waits_for_a_selected_button() {
+        let mut gameboy = gameboy(&[0x10, 0x00, 0x00], Some(Model::Dmg));
+        gameboy.step().unwrap();
+        assert_eq!(gameboy.cpu_state(), CpuState::Stopped);
+
+        // Only lines selected in P1 can wake the CPU up
+        gameboy.write_memory(0xff00, 0x20);
+        gameboy.set_button(Button::A, true);
+        let cycles = gameboy.cycles();
+        for _ in 0..10 {
+            assert_eq!(gameboy.step().unwrap(), 4);
+        }
+        assert_eq!(gameboy.cycles(), cycles + 40);
+        assert_eq!((gameboy.cpu_state(), gameboy.registers().pc), (CpuState::Stopped, 0x0102));
+
+        gameboy.write_memory(0xff00, 0x10);
+        gameboy.step().unwrap();
+        assert_eq!(gameboy.cpu_state(), CpuState::Running);
+        gameboy.step().unwrap();
+        assert_eq!(gameboy.registers().pc, 0x0103);
+    }
+
+    #[test]
+    fn stop_with_key1_armed_switches_speed_on_cgb() {
+        // ld a,$01; ldh [$4d],a; stop; ldh [$4d],a; stop
+        let code = [0x3e, 0x01, 0xe0, 0x4d, 0x10, 0x00, 0xe0, 0x4d, 0x10, 0x00];
+        let mut gameboy = gameboy(&code, Some(Model::Cgb));
+        gameboy.step().unwrap();
+        gameboy.step().unwrap();
+        assert!(!gameboy.double_speed());
+
+        let cycles = gameboy.cycles();
+        assert_eq!(gameboy.step().unwrap(), 4 + SPEED_SWITCH_CYCLES);
+        assert_eq!(gameboy.cycles(), cycles + (4 + SPEED_SWITCH_CYCLES) as u64);
+        assert_eq!(gameboy.cpu_state(), CpuState::Running);
+        assert_eq!(gameboy.memory.io_register(KEY1), 0x80);
+        assert!(gameboy.double_speed());
+
+        gameboy.step().unwrap();
+        assert_eq!(gameboy.step().unwrap(), 4 + SPEED_SWITCH_CYCLES);
+        assert_eq!(gameboy.memory.io_register(KEY1), 0x00);
+        assert!(!gameboy.double_speed());
+    }
+
+    #[test]
+    fn key1_is_ignored_outside_cgb() {
+        let code = [0x3e, 0x01, 0xe0, 0x4d, 0x10, 0x00];
+        let mut gameboy = gameboy(&code, Some(Model::Dmg));
+        gameboy.step().unwrap();
+        gameboy.step().unwrap();
+        assert_eq!(gameboy.step().unwrap(), 4);
+        assert_eq!(gameboy.cpu_state(), CpuState::Stopped);
+        assert!(!gameboy.double_speed());
+    }
 }
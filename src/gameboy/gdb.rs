@@ -0,0 +1,469 @@
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::TcpStream;
+
+use super::cpu::{CpuState, Registers};
+use super::gameboy::Gameboy;
+use super::run::StopReason;
+use super::watchpoint::{WatchHit, WatchKind, Watchpoint};
+
+/// How long a continue runs between checks for an interrupt from the
+/// client, one frame's worth of cycles
+const POLL_CYCLES: u64 = 70224;
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// GDB has no SM83 support, so the registers are described to it as six
+/// 16 bit ones, which is also the order `g` and `G` use.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rust_boi.sm83">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+const REGISTER_COUNT: usize = 6;
+
+/// A watchpoint as GDB set it: Z2 for writes, Z3 for reads, Z4 for both.
+#[derive(Clone, Copy, PartialEq)]
+struct GdbWatch {
+    kind: u8,
+    addr: u16,
+    length: u16,
+}
+
+impl GdbWatch {
+    fn watchpoints(&self) -> Vec<Watchpoint> {
+        let addresses = self.addr..=self.addr.saturating_add(self.length.max(1) - 1);
+        let kinds: &[WatchKind] = match self.kind {
+            2 => &[WatchKind::Write],
+            3 => &[WatchKind::Read],
+            _ => &[WatchKind::Read, WatchKind::Write],
+        };
+        kinds.iter()
+            .map(|kind| Watchpoint { addresses: addresses.clone(), kind: *kind, value: None })
+            .collect()
+    }
+
+    fn covers(&self, hit: &WatchHit) -> bool {
+        let kind_matches = match self.kind {
+            2 => hit.kind != WatchKind::Read,
+            3 => hit.kind == WatchKind::Read,
+            _ => true,
+        };
+        kind_matches && self.watchpoints().iter().any(|watchpoint| watchpoint.addresses.contains(&hit.addr))
+    }
+
+    fn stop_name(&self) -> &'static str {
+        match self.kind {
+            2 => "watch",
+            3 => "rwatch",
+            _ => "awatch",
+        }
+    }
+}
+
+/// Serves the GDB remote serial protocol over one connection until the
+/// client detaches, kills the session or disconnects.
+///
+/// Memory reads and writes, register access, software (Z0) and hardware
+/// (Z1) breakpoints, write, read and access watchpoints (Z2-Z4), `s`, `c`
+/// and interrupting a continue with Ctrl-C are supported. Registers are
+/// af, bc, de, hl, sp and pc, described to the client through
+/// `qXfer:features:read`. The stub's breakpoints and watchpoints are
+/// removed from the machine when the session ends.
+pub fn serve(gameboy: &mut Gameboy, stream: TcpStream) -> io::Result<()> {
+    let mut stub = Stub {
+        gameboy,
+        reader: BufReader::new(stream.try_clone()?),
+        stream,
+        software_breakpoints: HashSet::new(),
+        hardware_breakpoints: HashSet::new(),
+        watches: Vec::new(),
+        last_packet: Vec::new(),
+    };
+    let result = stub.run();
+    stub.remove_all();
+    result
+}
+
+struct Stub<'a> {
+    gameboy: &'a mut Gameboy,
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+    software_breakpoints: HashSet<u16>,
+    hardware_breakpoints: HashSet<u16>,
+    watches: Vec<GdbWatch>,
+    last_packet: Vec<u8>,
+}
+
+impl<'a> Stub<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            debug!("gdb <- {}", packet);
+            let reply = match packet.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                Some(b'c') => self.resume(&packet[1..], false)?,
+                Some(b's') => self.resume(&packet[1..], true)?,
+                _ => self.handle(&packet).unwrap_or_else(|| "E01".to_string()),
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// Answers every packet that doesn't run the machine. `None` means the
+    /// packet was malformed.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let (command, args) = (packet.get(..1)?, packet.get(1..)?);
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => {
+                let registers = self.gameboy.registers();
+                register_values(&registers).iter().map(|value| hex_u16(*value)).collect()
+            }
+            "G" => {
+                let mut registers = self.gameboy.registers();
+                for n in 0..REGISTER_COUNT {
+                    let value = parse_u16_le(args.get(n * 4..n * 4 + 4)?)?;
+                    set_register(&mut registers, n, value);
+                }
+                self.gameboy.set_registers(&registers);
+                "OK".to_string()
+            }
+            "p" => {
+                let n = usize::from_str_radix(args, 16).ok()?;
+                hex_u16(*register_values(&self.gameboy.registers()).get(n)?)
+            }
+            "P" => {
+                let (n, value) = args.split_once('=')?;
+                let n = usize::from_str_radix(n, 16).ok().filter(|n| *n < REGISTER_COUNT)?;
+                let mut registers = self.gameboy.registers();
+                set_register(&mut registers, n, parse_u16_le(value)?);
+                self.gameboy.set_registers(&registers);
+                "OK".to_string()
+            }
+            "m" => {
+                let (addr, length) = parse_addr_length(args)?;
+                (0..length)
+                    .map(|i| format!("{:02x}", self.gameboy.read_memory(addr.wrapping_add(i))))
+                    .collect()
+            }
+            "M" => {
+                let (range, data) = args.split_once(':')?;
+                let (addr, length) = parse_addr_length(range)?;
+                let bytes = parse_hex_bytes(data)?;
+                if bytes.len() != length as usize {
+                    return None;
+                }
+                for (i, byte) in bytes.iter().enumerate() {
+                    self.gameboy.write_memory(addr.wrapping_add(i as u16), *byte);
+                }
+                "OK".to_string()
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args)?,
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "q" => self.query(args),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=1000;qXfer:features:read+;swbreak+;hwbreak+".to_string();
+        }
+        if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            return match annex.split_once(',') {
+                Some((offset, length)) => {
+                    let offset = usize::from_str_radix(offset, 16).unwrap_or(usize::MAX).min(TARGET_XML.len());
+                    let length = usize::from_str_radix(length, 16).unwrap_or(0);
+                    let end = offset.saturating_add(length).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[offset..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            "Symbol::" => "OK".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Handles `Z` (insert) and `z` (remove) packets: `TYPE,ADDR,KIND`.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut parts = args.split(',');
+        let kind = parts.next()?.parse::<u8>().ok()?;
+        let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let length = u16::from_str_radix(parts.next()?, 16).ok()?;
+        match kind {
+            0 | 1 => {
+                let set = if kind == 0 { &mut self.software_breakpoints } else { &mut self.hardware_breakpoints };
+                if insert { set.insert(addr); } else { set.remove(&addr); }
+                if self.software_breakpoints.contains(&addr) || self.hardware_breakpoints.contains(&addr) {
+                    self.gameboy.add_breakpoint(addr);
+                } else {
+                    self.gameboy.remove_breakpoint(addr);
+                }
+            }
+            2..=4 => {
+                let watch = GdbWatch { kind, addr, length };
+                if insert {
+                    watch.watchpoints().into_iter().for_each(|watchpoint| self.gameboy.add_watchpoint(watchpoint));
+                    self.watches.push(watch);
+                } else if let Some(position) = self.watches.iter().position(|existing| *existing == watch) {
+                    self.watches.remove(position);
+                    for watchpoint in watch.watchpoints() {
+                        self.gameboy.remove_watchpoint(&watchpoint);
+                    }
+                }
+            }
+            _ => return Some(String::new()),
+        }
+        Some("OK".to_string())
+    }
+
+    fn remove_all(&mut self) {
+        for addr in self.software_breakpoints.union(&self.hardware_breakpoints) {
+            self.gameboy.remove_breakpoint(*addr);
+        }
+        for watch in &self.watches {
+            for watchpoint in watch.watchpoints() {
+                self.gameboy.remove_watchpoint(&watchpoint);
+            }
+        }
+    }
+
+    /// Single steps or continues, optionally from a new address, and
+    /// returns the stop reply.
+    fn resume(&mut self, addr: &str, single_step: bool) -> io::Result<String> {
+        if !addr.is_empty() {
+            match u16::from_str_radix(addr, 16) {
+                Ok(pc) => {
+                    let mut registers = self.gameboy.registers();
+                    registers.pc = pc;
+                    self.gameboy.set_registers(&registers);
+                }
+                Err(_) => return Ok("E01".to_string()),
+            }
+        }
+
+        if single_step {
+            return Ok(match self.gameboy.step() {
+                Err(e) => self.error_stop(&e.to_string()),
+                Ok(_) => match self.gameboy.take_watch_hit() {
+                    Some(hit) => self.watch_stop(&hit),
+                    None => format!("S{:02x}", SIGTRAP),
+                },
+            });
+        }
+
+        let mut first = true;
+        loop {
+            // Runs after the first pick up where the last one left off, which
+            // `run_cycles` doesn't check for breakpoints
+            let pc = self.gameboy.registers().pc;
+            if !first && self.has_breakpoint(pc) {
+                return Ok(self.breakpoint_stop(pc));
+            }
+            first = false;
+
+            match self.gameboy.run_cycles(POLL_CYCLES).stop {
+                StopReason::Breakpoint(pc) => return Ok(self.breakpoint_stop(pc)),
+                StopReason::Watchpoint(hit) => return Ok(self.watch_stop(&hit)),
+                StopReason::Error(e) => return Ok(self.error_stop(&e.to_string())),
+                _ => {}
+            }
+            if let CpuState::Locked { pc, opcode } = self.gameboy.cpu_state() {
+                return Ok(self.error_stop(&format!("CPU locked up on illegal opcode {:#04x} at {:#06x}", opcode, pc)));
+            }
+            if self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn has_breakpoint(&self, pc: u16) -> bool {
+        self.software_breakpoints.contains(&pc) || self.hardware_breakpoints.contains(&pc)
+    }
+
+    fn breakpoint_stop(&self, pc: u16) -> String {
+        let kind = if self.software_breakpoints.contains(&pc) { "swbreak" } else { "hwbreak" };
+        format!("T{:02x}{}:;", SIGTRAP, kind)
+    }
+
+    fn watch_stop(&self, hit: &WatchHit) -> String {
+        match self.watches.iter().find(|watch| watch.covers(hit)) {
+            Some(watch) => format!("T{:02x}{}:{:04x};", SIGTRAP, watch.stop_name(), hit.addr),
+            None => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn error_stop(&self, message: &str) -> String {
+        warn!("Stopping for GDB: {}", message);
+        format!("S{:02x}", SIGILL)
+    }
+
+    /// Whether the client sent Ctrl-C, or went away, while the machine ran.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let pending = match self.reader.fill_buf() {
+            Ok(buffer) => Ok(buffer.first().map(|byte| *byte == INTERRUPT)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(Some(false)),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        match pending? {
+            Some(true) => {
+                self.reader.consume(1);
+                Ok(true)
+            }
+            Some(false) => Ok(false),
+            None => Ok(true),
+        }
+    }
+
+    /// Reads the next `$data#checksum` packet, acknowledging it. Returns
+    /// `None` once the client disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let mut byte = [0];
+            if self.reader.read_until(b'$', &mut Vec::new())? == 0 {
+                return Ok(None);
+            }
+            let mut data = Vec::new();
+            if self.reader.read_until(b'#', &mut data)? == 0 || data.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                if io::Read::read(&mut self.reader, &mut byte)? == 0 {
+                    return Ok(None);
+                }
+                *digit = byte[0];
+            }
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(unescape(&data)));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        debug!("gdb -> {}", reply);
+        let mut packet = Vec::with_capacity(reply.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(&escape(reply.as_bytes()));
+        let checksum = checksum_of(&packet[1..]);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        self.last_packet = packet;
+        self.stream.write_all(&self.last_packet)?;
+        self.stream.flush()?;
+
+        // Clients ask for a resend with '-'
+        loop {
+            let mut byte = [0];
+            if io::Read::read(&mut self.reader, &mut byte)? == 0 {
+                return Ok(());
+            }
+            match byte[0] {
+                b'+' => return Ok(()),
+                b'-' => self.stream.write_all(&self.last_packet)?,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn register_values(registers: &Registers) -> [u16; REGISTER_COUNT] {
+    [registers.af(), registers.bc(), registers.de(), registers.hl(), registers.sp, registers.pc]
+}
+
+fn set_register(registers: &mut Registers, n: usize, value: u16) {
+    match n {
+        0 => registers.set_af(value),
+        1 => registers.set_bc(value),
+        2 => registers.set_de(value),
+        3 => registers.set_hl(value),
+        4 => registers.sp = value,
+        _ => registers.pc = value,
+    }
+}
+
+/// Registers go over the wire in target byte order, little endian.
+fn hex_u16(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, value >> 8)
+}
+
+fn parse_u16_le(hex: &str) -> Option<u16> {
+    match parse_hex_bytes(hex)?.as_slice() {
+        [low, high] => Some(u16::from(*low) | (u16::from(*high) << 8)),
+        _ => None,
+    }
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_addr_length(args: &str) -> Option<(u16, u16)> {
+    let (addr, length) = args.split_once(',')?;
+    let addr = u16::from_str_radix(addr, 16).ok()?;
+    let length = u16::from_str_radix(length, 16).ok()?;
+    Some((addr, length))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// `#`, `$`, `}` and `*` are sent as `}` followed by the byte XOR 0x20.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        if b"#$}*".contains(byte) {
+            escaped.push(b'}');
+            escaped.push(byte ^ 0x20);
+        } else {
+            escaped.push(*byte);
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|escaped| escaped ^ 0x20)),
+            _ => unescaped.push(*byte),
+        }
+    }
+    unescaped
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use super::error::EmulatorError;
+use super::gameboy::Gameboy;
+use super::movie::{Movie, MovieError, Player, Recorder, DEFAULT_HASH_INTERVAL};
+use super::run::StopReason;
+use super::png;
+
+const CYCLES_PER_SECOND: f64 = 4_194_304.0;
+
+/// Exit statuses of the command-line emulator, 0 being success.
+pub const EXIT_CONDITION_FAILED: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_LOAD_ERROR: i32 = 3;
+pub const EXIT_EMULATION_ERROR: i32 = 4;
+
+/// How long a headless run lasts, in emulated time.
+#[derive(Clone, Copy, Debug)]
+pub enum RunLimit {
+    Frames(u64),
+    Cycles(u64),
+    Seconds(f64),
+}
+
+/// Something that has to hold once the run is over for it to count as a pass.
+#[derive(Clone, Debug)]
+pub enum ExitCondition {
+    SerialContains(String),
+    MemoryEquals(u16, u8),
+}
+
+impl ExitCondition {
+    fn holds(&self, gameboy: &mut Gameboy) -> bool {
+        match self {
+            ExitCondition::SerialContains(text) => {
+                String::from_utf8_lossy(gameboy.serial_output()).contains(text.as_str())
+            }
+            ExitCondition::MemoryEquals(addr, value) => gameboy.read_memory(*addr) == *value,
+        }
+    }
+}
+
+impl fmt::Display for ExitCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitCondition::SerialContains(text) => write!(f, "serial output contains {:?}", text),
+            ExitCondition::MemoryEquals(addr, value) => write!(f, "({:#06x}) == {:#04x}", addr, value),
+        }
+    }
+}
+
+pub struct HeadlessOptions {
+    pub limit: RunLimit,
+    pub screenshot: Option<PathBuf>,
+    pub serial_output: Option<PathBuf>,
+    pub memory_dump: Option<PathBuf>,
+    pub exit_conditions: Vec<ExitCondition>,
+    /// Where to write a movie of the run, which goes frame by frame
+    pub record_movie: Option<PathBuf>,
+    /// A movie to play instead of running until the limit
+    pub movie: Option<Movie>,
+}
+
+pub struct HeadlessReport {
+    pub frames: u64,
+    pub cycles: u64,
+    pub failed_conditions: Vec<ExitCondition>,
+    /// Set when emulation stopped early because of an error
+    pub error: Option<EmulatorError>,
+    /// Set when the movie being played didn't fit the machine or desynced
+    pub movie_error: Option<MovieError>,
+}
+
+impl HeadlessReport {
+    /// 0 when every exit condition held, `EXIT_CONDITION_FAILED` when one
+    /// failed or the movie desynced and `EXIT_EMULATION_ERROR` when
+    /// emulation stopped with an error.
+    pub fn exit_code(&self) -> i32 {
+        if self.error.is_some() {
+            EXIT_EMULATION_ERROR
+        } else if self.failed_conditions.is_empty() && self.movie_error.is_none() {
+            0
+        } else {
+            EXIT_CONDITION_FAILED
+        }
+    }
+}
+
+/// Runs `gameboy` without a display until `options.limit` is reached or an
+/// emulation error stops it, then writes out whichever of the screenshot,
+/// serial log, memory dump and movie were asked for and checks the exit
+/// conditions. A movie being played decides how long the run lasts instead.
+pub fn run_headless(gameboy: &mut Gameboy, options: &HeadlessOptions) -> io::Result<HeadlessReport> {
+    let (start_frames, start_cycles) = (gameboy.frames(), gameboy.cycles());
+    let finished = |gameboy: &Gameboy| match options.limit {
+        RunLimit::Frames(frames) => gameboy.frames() - start_frames >= frames,
+        RunLimit::Cycles(cycles) => gameboy.cycles() - start_cycles >= cycles,
+        RunLimit::Seconds(seconds) => gameboy.cycles() - start_cycles >= (seconds * CYCLES_PER_SECOND) as u64,
+    };
+    let mut recorder = options.record_movie.as_ref().map(|_| Recorder::new(gameboy, DEFAULT_HASH_INTERVAL));
+    let mut movie_error = None;
+    let error = if let Some(movie) = &options.movie {
+        match play(gameboy, movie) {
+            Ok(()) => None,
+            Err(MovieError::Emulator(e)) => Some(e),
+            Err(e) => {
+                movie_error = Some(e);
+                None
+            }
+        }
+    } else if let Some(recorder) = recorder.as_mut() {
+        loop {
+            if finished(gameboy) {
+                break None;
+            }
+            if let StopReason::Error(e) = recorder.run_frame(gameboy).stop {
+                break Some(e);
+            }
+        }
+    } else {
+        match gameboy.run_until(finished).stop {
+            StopReason::Error(e) => Some(e),
+            _ => None,
+        }
+    };
+
+    if let Some(path) = &options.screenshot {
+        let (width, height, pixels) = gameboy.screen();
+        let mut file = BufWriter::new(File::create(path)?);
+        png::write_rgb(&mut file, width, height, &pixels)?;
+        file.flush()?;
+    }
+    if let Some(path) = &options.serial_output {
+        File::create(path)?.write_all(gameboy.serial_output())?;
+    }
+    if let Some(path) = &options.memory_dump {
+        let mut file = BufWriter::new(File::create(path)?);
+        gameboy.dump_memory(&mut file)?;
+        file.flush()?;
+    }
+
+    if let (Some(path), Some(recorder)) = (&options.record_movie, recorder) {
+        let mut file = BufWriter::new(File::create(path)?);
+        recorder.finish().write_to(&mut file)?;
+        file.flush()?;
+    }
+
+    let failed_conditions = options.exit_conditions.iter()
+        .filter(|condition| !condition.holds(gameboy))
+        .cloned()
+        .collect();
+
+    Ok(HeadlessReport {
+        frames: gameboy.frames() - start_frames,
+        cycles: gameboy.cycles() - start_cycles,
+        failed_conditions,
+        error,
+        movie_error,
+    })
+}
+
+fn play(gameboy: &mut Gameboy, movie: &Movie) -> Result<(), MovieError> {
+    let mut player = Player::new(movie, gameboy)?;
+    while player.run_frame(gameboy)?.is_some() {}
+    Ok(())
+}
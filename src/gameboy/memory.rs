@@ -1,69 +1,415 @@
+use std::io::{self, Write};
+
+use super::error::EmulatorError;
+use super::savestate::{StateReader, StateWriter};
+use super::sgb::Sgb;
+use super::utils::bit_is_set;
+use super::watchpoint::{WatchKind, Watchpoint};
+
+/// Joypad buttons, in the order of their bits in P1's two button groups.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+/// Interrupt sources, in the order of their bits in IF and IE. Nothing
+/// raises the STAT and timer interrupts yet.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interrupt {
+    VBlank,
+    Stat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+#[derive(Clone)]
 pub struct Memory {
     main_ram: Box<[u8]>,
     video_ram: Box<[u8]>,
+    oam: Box<[u8]>,
     bootrom: Box<[u8]>,
+    bootrom_mapped: bool,
     mapped_io: Box<[u8]>,
     zero_page: Box<[u8]>,
     rom: Box<[u8]>,
+    sgb: Option<Sgb>,
+    serial: Vec<u8>,
+    buttons: u8,
+    watchpoints: Vec<Watchpoint>,
+    /// The first access to fire a watchpoint since it was last taken: its
+    /// kind, address and the byte before and after
+    watch_hit: Option<(WatchKind, u16, u8, u8)>,
 }
 
 const RAM_SIZE: usize = 8 * 1024;
+const OAM_SIZE: usize = 160;
 const ZERO_PAGE_SIZE: usize = 128;
 const MEMORY_MAPPED_IO: usize = 128;
+pub const DMG_BOOTROM_SIZE: usize = 0x100;
+pub const CGB_BOOTROM_SIZE: usize = 0x900;
 
 impl Memory {
-    pub fn new(rom: Vec<u8>, bootrom: Vec<u8>) -> Self {
-        Memory {
+    /// Without a bootrom the cartridge is visible from the start. DMG
+    /// bootroms are 256 bytes and CGB ones 2304, anything else is rejected.
+    pub fn new(rom: Vec<u8>, bootrom: Option<Vec<u8>>, sgb_mode: bool) -> Result<Self, EmulatorError> {
+        if let Some(bootrom) = &bootrom {
+            if bootrom.len() != DMG_BOOTROM_SIZE && bootrom.len() != CGB_BOOTROM_SIZE {
+                return Err(EmulatorError::InvalidBootrom(format!(
+                    "{} bytes, expected {} for a DMG bootrom or {} for a CGB one",
+                    bootrom.len(), DMG_BOOTROM_SIZE, CGB_BOOTROM_SIZE)));
+            }
+        }
+        let sgb = if sgb_mode { Some(Sgb::new()) } else { None };
+        Ok(Memory {
             main_ram: vec![0; RAM_SIZE].into_boxed_slice(),
             video_ram: vec![0; RAM_SIZE].into_boxed_slice(),
-            bootrom: bootrom.into_boxed_slice(),
+            oam: vec![0; OAM_SIZE].into_boxed_slice(),
+            bootrom_mapped: bootrom.is_some(),
+            bootrom: bootrom.unwrap_or_default().into_boxed_slice(),
             mapped_io: vec![0; MEMORY_MAPPED_IO].into_boxed_slice(),
             zero_page: vec![0; ZERO_PAGE_SIZE].into_boxed_slice(),
             rom: rom.into_boxed_slice(),
+            sgb,
+            serial: Vec::new(),
+            buttons: 0,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+        })
+    }
+
+    pub fn video_ram(&self) -> &[u8] {
+        &self.video_ram
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// Main RAM, video RAM, OAM, the IO registers and high RAM (with IE as
+    /// its last byte), for save state formats that lay memory out their own way.
+    pub fn ram(&self) -> [&[u8]; 5] {
+        [&self.main_ram, &self.video_ram, &self.oam, &self.mapped_io, &self.zero_page]
+    }
+
+    pub fn ram_mut(&mut self) -> [&mut [u8]; 5] {
+        [&mut self.main_ram, &mut self.video_ram, &mut self.oam, &mut self.mapped_io, &mut self.zero_page]
+    }
+
+    /// Maps or unmaps the bootrom. It can only be mapped when one was loaded.
+    pub fn bootrom_mapped(&self) -> bool {
+        self.bootrom_mapped
+    }
+
+    pub fn set_bootrom_mapped(&mut self, mapped: bool) {
+        self.bootrom_mapped = mapped && !self.bootrom.is_empty();
+    }
+
+    /// Reads an IO register (offset from 0xff00) without any side effects.
+    pub fn io_register(&self, offset: usize) -> u8 {
+        self.mapped_io[offset]
+    }
+
+    pub fn set_io_register(&mut self, offset: usize, value: u8) {
+        self.mapped_io[offset] = value;
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.mapped_io[0x000f] |= 1 << interrupt as u8;
+    }
+
+    /// Every byte the game has sent out over the link cable.
+    pub fn serial_output(&self) -> &[u8] {
+        &self.serial
+    }
+
+    /// Pressing a button requests the joypad interrupt.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let mask = 1 << button as u8;
+        if pressed {
+            if self.buttons & mask == 0 {
+                self.request_interrupt(Interrupt::Joypad);
+            }
+            self.buttons |= mask;
+        } else {
+            self.buttons &= !mask;
+        }
+    }
+
+    /// The ROM bank visible at `addr`. Without an MBC bank 1 is always
+    /// mapped at 0x4000-0x7fff, and anything outside ROM counts as bank 0.
+    pub fn bank_at(&self, addr: u16) -> usize {
+        match addr {
+            0x4000..=0x7fff => 1,
+            _ => 0,
+        }
+    }
+
+    /// Held buttons, one bit each in `Button` order.
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    /// Sets every button at once, requesting the joypad interrupt when
+    /// one of them gets pressed.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        if buttons & !self.buttons != 0 {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+        self.buttons = buttons;
+    }
+
+    pub fn sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
+    }
+
+    pub fn sgb_mut(&mut self) -> Option<&mut Sgb> {
+        self.sgb.as_mut()
+    }
+
+    /// Saves everything the game can change. The ROM and bootrom are left
+    /// out, as is the serial output which belongs to whoever is watching.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.main_ram);
+        state.bytes(&self.video_ram);
+        state.bytes(&self.oam);
+        state.bytes(&self.mapped_io);
+        state.bytes(&self.zero_page);
+        state.bool(self.bootrom_mapped);
+        state.u8(self.buttons);
+        state.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(state);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmulatorError> {
+        state.bytes_into(&mut self.main_ram)?;
+        state.bytes_into(&mut self.video_ram)?;
+        state.bytes_into(&mut self.oam)?;
+        state.bytes_into(&mut self.mapped_io)?;
+        state.bytes_into(&mut self.zero_page)?;
+        self.bootrom_mapped = state.bool()?;
+        if self.bootrom_mapped && self.bootrom.is_empty() {
+            return Err(state.error("the bootrom is still mapped but none was loaded"));
+        }
+        self.buttons = state.u8()?;
+        if state.bool()? != self.sgb.is_some() {
+            return Err(state.error("SGB mode doesn't match"));
+        }
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.load_state(state)?;
         }
+        Ok(())
     }
 
-    fn executed_bootloader(&mut self) -> bool {
-        self.mapped_io[0x0050] == 1
+    /// CGB bootroms leave a hole at 0x0100-0x01ff so the cartridge header
+    /// can be read while they're still mapped.
+    fn bootrom_overlays(&self, addr: u16) -> bool {
+        self.bootrom_mapped && match addr {
+            0x0000..=0x00ff => true,
+            0x0200..=0x08ff => self.bootrom.len() == CGB_BOOTROM_SIZE,
+            _ => false,
+        }
     }
 
-    fn get_memory_space_with_addr(&mut self, addr: u16) -> (&mut Box<[u8]>, u16) {
+    /// Finds the backing storage for `addr`. Returns `None` for addresses
+    /// with nothing behind them: ROM past the end of the image, cartridge
+    /// RAM (no MBC is emulated) and the unusable 0xfea0-0xfeff range.
+    /// Those read as 0xff and ignore writes.
+    fn get_memory_space_with_addr(&mut self, addr: u16) -> Option<(&mut Box<[u8]>, u16)> {
         match addr {
-            0x0000 ... 0x7fff => {
-                if !self.executed_bootloader() && addr < 0x0100 {
-                    return ((&mut self.bootrom), addr);
+            0x0000..=0x7fff => {
+                if self.bootrom_overlays(addr) {
+                    return Some((&mut self.bootrom, addr));
+                }
+                if addr as usize >= self.rom.len() {
+                    return None;
                 }
 
-                return ((&mut self.rom), addr);
+                Some((&mut self.rom, addr))
             }
-            0x8000 ... 0x9fff => {
-                return ((&mut self.video_ram), addr - 0x8000);
+            0x8000..=0x9fff => {
+                Some((&mut self.video_ram, addr - 0x8000))
             }
-            0xff00 ... 0xff7f => {
-                return ((&mut self.mapped_io), addr - 0xff00);
+            0xc000..=0xdfff => {
+                Some((&mut self.main_ram, addr - 0xc000))
             }
-            0xff80 ... 0xfffe => {
-                return ((&mut self.zero_page), addr - 0x0FF80);
+            0xe000..=0xfdff => { // Echo of 0xc000-0xddff
+                Some((&mut self.main_ram, addr - 0xe000))
             }
-            _ => {
-                panic!("Unknown memory region: {:#x}", addr);
+            0xfe00..=0xfe9f => {
+                Some((&mut self.oam, addr - 0xfe00))
+            }
+            0xff00..=0xff7f => {
+                Some((&mut self.mapped_io, addr - 0xff00))
             }
+            0xff80..=0xffff => { // Includes IE at 0xffff
+                Some((&mut self.zero_page, addr - 0x0FF80))
+            }
+            _ => None,
         }
     }
 
+    /// Reads a byte for the CPU, checking watchpoints.
+    #[inline]
     pub fn read_u8(&mut self, addr: u16) -> u8 {
-        let (memory_space, addr) = self.get_memory_space_with_addr(addr);
-        return memory_space[addr as usize];
+        let value = self.peek_u8(addr);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(WatchKind::Read, addr, value, value);
+        }
+        value
     }
 
+    /// Writes a byte for the CPU, checking watchpoints.
+    #[inline]
     pub fn write_u8(&mut self, addr: u16, value: u8) {
-        let (memory_space, addr) = self.get_memory_space_with_addr(addr);
-        return memory_space[addr as usize] = value;
+        if self.watchpoints.is_empty() {
+            return self.poke_u8(addr, value);
+        }
+        let old = self.peek_u8(addr);
+        self.poke_u8(addr, value);
+        let new = self.peek_u8(addr);
+        self.check_watchpoints(WatchKind::Write, addr, old, new);
+    }
+
+    /// Reads a byte without firing watchpoints.
+    pub fn peek_u8(&mut self, addr: u16) -> u8 {
+        if addr == 0xff00 {
+            return self.read_joypad();
+        }
+        match self.get_memory_space_with_addr(addr) {
+            Some((memory_space, addr)) => memory_space[addr as usize],
+            None => 0xff,
+        }
+    }
+
+    /// Writes a byte, side effects included, without firing watchpoints.
+    pub fn poke_u8(&mut self, addr: u16, value: u8) {
+        if addr <= 0x7fff {
+            return; // ROM only cartridges have no MBC to talk to
+        }
+        if addr == 0xff00 {
+            if let Some(sgb) = self.sgb.as_mut() {
+                sgb.write_joypad(value, &self.video_ram, self.mapped_io[0x0040]);
+            }
+        }
+        // The current speed in KEY1 only changes on a STOP speed switch
+        let value = if addr == 0xff4d { (value & 0x7f) | (self.mapped_io[0x004d] & 0x80) } else { value };
+        if addr == 0xff50 && value & 0x01 == 0x01 {
+            // Once unmapped the bootrom stays gone until the next reset
+            self.bootrom_mapped = false;
+        }
+        if addr == 0xff02 && value & 0x81 == 0x81 {
+            // Transfers on the internal clock complete straight away since
+            // nothing is ever plugged into the other end of the cable.
+            self.serial.push(self.mapped_io[0x0001]);
+            self.mapped_io[0x0002] = value & 0x7f;
+            self.request_interrupt(Interrupt::Serial);
+            return;
+        }
+        if let Some((memory_space, addr)) = self.get_memory_space_with_addr(addr) {
+            memory_space[addr as usize] = value;
+        }
     }
 
     pub fn read_u16(&mut self, addr: u16) -> u16 {
-        return ((self.read_u8(addr+1)   as u16) << 8) |
-               ((self.read_u8(addr) as u16));
+        ((self.read_u8(addr.wrapping_add(1)) as u16) << 8) |
+        (self.read_u8(addr) as u16)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Removes one watchpoint equal to `watchpoint`, returning whether
+    /// there was one.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        match self.watchpoints.iter().position(|existing| existing == watchpoint) {
+            Some(position) => {
+                self.watchpoints.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Removes every watchpoint, handing them back to be put back later
+    /// with `set_watchpoints`.
+    pub fn take_watchpoints(&mut self) -> Vec<Watchpoint> {
+        std::mem::take(&mut self.watchpoints)
+    }
+
+    pub fn set_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) {
+        self.watchpoints = watchpoints;
+    }
+
+    /// Takes the first access that fired a watchpoint since the last call.
+    pub fn take_watch_hit(&mut self) -> Option<(WatchKind, u16, u8, u8)> {
+        self.watch_hit.take()
+    }
+
+    // Kept out of line so the checks above stay a single branch when no
+    // watchpoints are set
+    #[cold]
+    #[inline(never)]
+    fn check_watchpoints(&mut self, kind: WatchKind, addr: u16, old: u8, new: u8) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|watchpoint| watchpoint.matches(kind, addr, old, new)) {
+            self.watch_hit = Some((kind, addr, old, new));
+        }
+    }
+
+    /// P1 reads back the selected button group in the low nibble, with a
+    /// pressed button pulling its line low.
+    fn read_joypad(&self) -> u8 {
+        let select = self.mapped_io[0x0000] & 0x30;
+        let lines = match self.sgb.as_ref() {
+            Some(sgb) if sgb.multiplayer() && select == 0x30 => sgb.joypad_id(),
+            _ => {
+                let mut pressed = 0;
+                if !bit_is_set(select, 4) {
+                    pressed |= self.buttons & 0x0f; // Right, Left, Up, Down
+                }
+                if !bit_is_set(select, 5) {
+                    pressed |= self.buttons >> 4; // A, B, Select, Start
+                }
+                !pressed & 0x0f
+            }
+        };
+        0xc0 | select | lines
+    }
+
+    /// Writes a hex dump of every RAM and IO region, one line per 16 bytes.
+    pub fn dump_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let regions: [(u16, &[u8]); 5] = [
+            (0x8000, &self.video_ram),
+            (0xc000, &self.main_ram),
+            (0xfe00, &self.oam),
+            (0xff00, &self.mapped_io),
+            (0xff80, &self.zero_page),
+        ];
+        for (start, region) in regions.iter() {
+            for (i, bytes) in region.chunks(16).enumerate() {
+                write!(out, "{:04x}:", *start as usize + i * 16)?;
+                for byte in bytes {
+                    write!(out, " {:02x}", byte)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        Ok(())
     }
 
     #[allow(dead_code)]
@@ -81,4 +427,108 @@ impl Memory {
             println!();
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::gameboy::Gameboy;
+
+    const IF: u16 = 0xff0f;
+
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14d] = rom[0x134..0x14d].iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+        rom
+    }
+
+    #[test]
+    fn interrupts_set_their_bit_in_if() {
+        let mut memory = Memory::new(rom(), None, false).unwrap();
+        memory.poke_u8(IF, 0);
+        for (interrupt, bit) in [(Interrupt::VBlank, 0), (Interrupt::Stat, 1), (Interrupt::Timer, 2),
+                                 (Interrupt::Serial, 3), (Interrupt::Joypad, 4)] {
+            memory.request_interrupt(interrupt);
+            assert_eq!(memory.peek_u8(IF) & 0x1f, (2 << bit) - 1, "{:?}", interrupt);
+        }
+    }
+
+    #[test]
+    fn pressing_a_button_requests_the_joypad_interrupt() {
+        let mut memory = Memory::new(rom(), None, false).unwrap();
+        memory.poke_u8(IF, 0);
+        memory.set_button(Button::A, true);
+        assert_eq!(memory.peek_u8(IF) & 0x1f, 0x10);
+
+        // Holding or releasing a button doesn't
+        memory.poke_u8(IF, 0);
+        memory.set_button(Button::A, true);
+        memory.set_button(Button::A, false);
+        memory.set_buttons(0);
+        assert_eq!(memory.peek_u8(IF) & 0x1f, 0);
+
+        memory.set_buttons(1 << Button::Start as u8);
+        assert_eq!(memory.peek_u8(IF) & 0x1f, 0x10);
+    }
+
+    #[test]
+    fn internal_clock_transfers_request_the_serial_interrupt() {
+        let mut memory = Memory::new(rom(), None, false).unwrap();
+        memory.poke_u8(IF, 0);
+        memory.poke_u8(0xff01, b'!');
+        // An external clock waits for the other end, which never clocks
+        memory.poke_u8(0xff02, 0x80);
+        assert_eq!(memory.peek_u8(IF) & 0x1f, 0);
+        memory.poke_u8(0xff02, 0x81);
+        assert_eq!(memory.peek_u8(IF) & 0x1f, 0x08);
+        assert_eq!(memory.peek_u8(0xff02) & 0x80, 0);
+        assert_eq!(memory.serial_output(), b"!");
+    }
+
+    #[test]
+    fn vblank_requests_its_interrupt() {
+        let mut gameboy = Gameboy::new(rom(), None, None).unwrap();
+        gameboy.write_memory(IF, 0);
+        gameboy.run_frame();
+        assert_eq!(gameboy.read_memory(IF) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn writing_bit_0_of_ff50_unmaps_the_bootrom_for_good() {
+        let mut memory = Memory::new(vec![0xcc; 0x8000], Some(vec![0xbb; DMG_BOOTROM_SIZE]), false).unwrap();
+        assert!(memory.bootrom_mapped());
+        assert_eq!((memory.peek_u8(0x0000), memory.peek_u8(0x00ff), memory.peek_u8(0x0100)), (0xbb, 0xbb, 0xcc));
+
+        memory.poke_u8(0xff50, 0xfe);
+        assert!(memory.bootrom_mapped());
+        memory.poke_u8(0xff50, 0x01);
+        assert!(!memory.bootrom_mapped());
+        assert_eq!(memory.peek_u8(0x0000), 0xcc);
+        memory.poke_u8(0xff50, 0x00);
+        assert!(!memory.bootrom_mapped());
+        assert_eq!(memory.peek_u8(0x0000), 0xcc);
+    }
+
+    #[test]
+    fn cgb_bootroms_leave_a_hole_for_the_header() {
+        let mut memory = Memory::new(vec![0xcc; 0x8000], Some(vec![0xbb; CGB_BOOTROM_SIZE]), false).unwrap();
+        for (addr, value) in [(0x0000, 0xbb), (0x00ff, 0xbb), (0x0100, 0xcc), (0x01ff, 0xcc),
+                              (0x0200, 0xbb), (0x08ff, 0xbb), (0x0900, 0xcc)] {
+            assert_eq!(memory.peek_u8(addr), value, "{:#06x}", addr);
+        }
+
+        let mut memory = Memory::new(vec![0xcc; 0x8000], Some(vec![0xbb; DMG_BOOTROM_SIZE]), false).unwrap();
+        assert_eq!((memory.peek_u8(0x00ff), memory.peek_u8(0x0200)), (0xbb, 0xcc));
+    }
+
+    #[test]
+    fn bootroms_of_another_size_are_rejected() {
+        for size in [0, 0xff, 0x200, 0x901] {
+            match Memory::new(rom(), Some(vec![0; size]), false) {
+                Err(EmulatorError::InvalidBootrom(_)) => {}
+                result => panic!("{} bytes: {:?}", size, result.map(|_| ())),
+            }
+        }
+        assert!(!Memory::new(rom(), None, false).unwrap().bootrom_mapped());
+    }
+}
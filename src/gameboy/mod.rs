@@ -1,6 +1,34 @@
-pub mod memory;
+mod memory;
+pub mod boot;
+mod bess;
+pub mod callstack;
+pub mod cartridge;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+#[allow(clippy::module_inception)]
 pub mod gameboy;
+pub mod gdb;
+pub mod headless;
+pub mod model;
+pub mod movie;
+mod ppu;
+pub mod profiler;
+pub mod rewind;
+pub mod run;
+mod savestate;
+mod sgb;
+pub mod trace;
+pub mod symbols;
+mod png;
 mod utils;
+pub mod watchpoint;
 
-pub use self::gameboy::Gameboy;
\ No newline at end of file
+pub use self::error::EmulatorError;
+pub use self::gameboy::Gameboy;
+pub use self::memory::Button;
+pub use self::model::Model;
+pub use self::run::{RunSummary, StopReason};
+pub use self::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+pub use self::sgb::{SGB_WIDTH, SGB_HEIGHT};
\ No newline at end of file
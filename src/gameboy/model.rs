@@ -0,0 +1,63 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::cartridge::Header;
+
+/// The hardware being emulated.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Cgb,
+    Sgb,
+    Sgb2,
+}
+
+impl Model {
+    /// Picks the model a cartridge was made for: CGB games get a CGB, SGB
+    /// enhanced games get an SGB and everything else runs on a DMG.
+    pub fn for_cartridge(header: &Header) -> Self {
+        if header.cgb_support {
+            Model::Cgb
+        } else if header.sgb_support {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    pub fn is_sgb(self) -> bool {
+        self == Model::Sgb || self == Model::Sgb2
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "cgb" => Ok(Model::Cgb),
+            "sgb" => Ok(Model::Sgb),
+            "sgb2" => Ok(Model::Sgb2),
+            _ => Err(format!("Unknown model '{}', expected one of DMG0, DMG, MGB, CGB, SGB, SGB2", s)),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Model::Dmg0 => "DMG0",
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Cgb => "CGB",
+            Model::Sgb => "SGB",
+            Model::Sgb2 => "SGB2",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,521 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+use super::error::EmulatorError;
+use super::gameboy::Gameboy;
+use super::memory::Button;
+use super::model::Model;
+use super::run::{RunSummary, StopReason};
+
+/// How often states are hashed while recording, in frames.
+pub const DEFAULT_HASH_INTERVAL: u64 = 60;
+
+const HEADER: &str = "rust_boi movie 1";
+
+/// Names BizHawk gives the buttons in a BK2 LogKey, in `MNEMONICS` order.
+const BK2_KEYS: [&str; 8] = ["Up", "Down", "Left", "Right", "Start", "Select", "B", "A"];
+
+/// Buttons in the order their mnemonics appear on an input line, which is
+/// the order BizHawk logs them in.
+const MNEMONICS: [(Button, char); 8] = [
+    (Button::Up, 'U'),
+    (Button::Down, 'D'),
+    (Button::Left, 'L'),
+    (Button::Right, 'R'),
+    (Button::Start, 'S'),
+    (Button::Select, 's'),
+    (Button::B, 'B'),
+    (Button::A, 'A'),
+];
+
+/// The buttons held during each frame of a session started at power on,
+/// and enough about the machine to replay it exactly.
+///
+/// Movies are stored as text:
+///
+/// ```text
+/// rust_boi movie 1
+/// rom 7b73f868          CRC-32 of the ROM, optional
+/// model DMG             optional
+/// bootrom none          'none' when boot was skipped, or the bootrom's CRC-32,
+///                       optional
+/// sram none             No cartridge RAM or RTC is emulated, so there's no
+/// rtc none              initial state to record yet
+/// hash-interval 60
+/// |U.....BA|            One line per frame in UDLRSsBA order, '.' when released
+/// hash 60 1a2b3c4d      CRC-32 of the state at the start of VBlank ending frame 60
+/// ```
+///
+/// Lines starting with '#' are comments. Frames end at the start of VBlank,
+/// so the first frame runs from power on to the first VBlank.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_crc: Option<u32>,
+    pub model: Option<Model>,
+    /// `Some(None)` when boot was skipped, and `None` when it isn't known,
+    /// as for imported movies
+    pub bootrom_crc: Option<Option<u32>>,
+    pub hash_interval: u64,
+    /// Held buttons for each frame, one bit each in `Button` order
+    pub inputs: Vec<u8>,
+    /// (frame, state hash) pairs, in frame order
+    pub hashes: Vec<(u64, u32)>,
+}
+
+#[derive(Debug)]
+pub enum MovieError {
+    Parse { line: usize, reason: String },
+    /// The movie was recorded on another ROM, model or bootrom, or the
+    /// machine isn't at power on
+    Mismatch(String),
+    /// The state hash after `frame` doesn't match the recorded one
+    Desync { frame: u64, expected: u32, actual: u32 },
+    Emulator(EmulatorError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Parse { line, reason } => write!(f, "Invalid movie on line {}: {}", line, reason),
+            MovieError::Mismatch(reason) => write!(f, "Movie doesn't fit this machine: {}", reason),
+            MovieError::Desync { frame, expected, actual } => {
+                write!(f, "Movie desynced at frame {}: state hash {:08x}, recorded {:08x}", frame, actual, expected)
+            }
+            MovieError::Emulator(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl Movie {
+    /// An empty movie for `gameboy`, which should be at power on.
+    pub fn new(gameboy: &Gameboy, hash_interval: u64) -> Self {
+        Movie {
+            rom_crc: Some(gameboy.rom_crc()),
+            model: Some(gameboy.model()),
+            bootrom_crc: Some(gameboy.bootrom_crc()),
+            hash_interval: hash_interval.max(1),
+            inputs: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    fn blank() -> Self {
+        Movie {
+            rom_crc: None,
+            model: None,
+            bootrom_crc: None,
+            hash_interval: DEFAULT_HASH_INTERVAL,
+            inputs: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Reads a movie in our own format, a VBM movie or a BK2 input log,
+    /// whichever `data` looks like.
+    pub fn load(data: &[u8]) -> Result<Self, MovieError> {
+        if data.starts_with(b"VBM\x1a") {
+            return Movie::import_vbm(data);
+        }
+        let text = String::from_utf8_lossy(data);
+        if !text.starts_with(HEADER) && (text.contains("LogKey:") || text.contains("[Input]")) {
+            return Movie::import_bk2(&text);
+        }
+        Movie::parse(&text)
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{}", HEADER)?;
+        if let Some(crc) = self.rom_crc {
+            writeln!(out, "rom {:08x}", crc)?;
+        }
+        if let Some(model) = self.model {
+            writeln!(out, "model {}", model)?;
+        }
+        match self.bootrom_crc {
+            Some(Some(crc)) => writeln!(out, "bootrom {:08x}", crc)?,
+            Some(None) => writeln!(out, "bootrom none")?,
+            None => {}
+        }
+        writeln!(out, "sram none")?;
+        writeln!(out, "rtc none")?;
+        writeln!(out, "hash-interval {}", self.hash_interval)?;
+
+        let mut hashes = self.hashes.iter().peekable();
+        for (frame, buttons) in self.inputs.iter().enumerate() {
+            let line: String = MNEMONICS.iter()
+                .map(|(button, mnemonic)| if buttons & (1 << *button as u8) != 0 { *mnemonic } else { '.' })
+                .collect();
+            writeln!(out, "|{}|", line)?;
+            while let Some((_, hash)) = hashes.next_if(|(hash_frame, _)| *hash_frame == frame as u64 + 1) {
+                writeln!(out, "hash {} {:08x}", frame + 1, hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MovieError> {
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line.trim()));
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            _ => return Err(MovieError::Parse { line: 1, reason: format!("expected '{}'", HEADER) }),
+        }
+
+        let mut movie = Movie::blank();
+        let columns: Vec<Option<Button>> = MNEMONICS.iter().map(|(button, _)| Some(*button)).collect();
+        for (line, text) in lines {
+            let error = |reason: String| MovieError::Parse { line, reason };
+            let hex = |value: &str| u32::from_str_radix(value, 16).map_err(|_| error(format!("invalid CRC '{}'", value)));
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            if text.starts_with('|') {
+                movie.inputs.push(parse_input(text, &columns).ok_or_else(|| error(format!("invalid input '{}'", text)))?);
+                continue;
+            }
+
+            let mut words = text.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("rom"), Some(crc), None) => movie.rom_crc = Some(hex(crc)?),
+                (Some("model"), Some(model), None) => movie.model = Some(model.parse().map_err(error)?),
+                (Some("bootrom"), Some("none"), None) => movie.bootrom_crc = Some(None),
+                (Some("bootrom"), Some(crc), None) => movie.bootrom_crc = Some(Some(hex(crc)?)),
+                (Some("sram"), Some("none"), None) | (Some("rtc"), Some("none"), None) => {}
+                (Some("sram"), _, _) | (Some("rtc"), _, _) => {
+                    return Err(error("cartridge RAM and RTC state aren't supported".to_string()));
+                }
+                (Some("hash-interval"), Some(interval), None) => {
+                    movie.hash_interval = interval.parse().ok().filter(|interval| *interval > 0)
+                        .ok_or_else(|| error(format!("invalid hash interval '{}'", interval)))?;
+                }
+                (Some("hash"), Some(frame), Some(hash)) => {
+                    let frame = frame.parse().map_err(|_| error(format!("invalid frame '{}'", frame)))?;
+                    movie.hashes.push((frame, hex(hash)?));
+                }
+                _ => return Err(error(format!("unexpected '{}'", text))),
+            }
+        }
+        Ok(movie)
+    }
+
+    /// Reads the input log of a BizHawk BK2 movie, the 'Input Log.txt' file
+    /// inside the archive. Only the first controller's buttons are used.
+    pub fn import_bk2(text: &str) -> Result<Self, MovieError> {
+        let mut movie = Movie::blank();
+        // Columns follow the LogKey line when there is one
+        let mut columns: Vec<Option<Button>> = MNEMONICS.iter().map(|(button, _)| Some(*button)).collect();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(keys) = line.strip_prefix("LogKey:") {
+                // Columns for anything else, such as Power, are read and ignored
+                columns = keys.split('|')
+                    .map(|key| key.trim_start_matches('#').trim_start_matches("P1 "))
+                    .filter(|key| !key.is_empty())
+                    .map(|key| BK2_KEYS.iter().position(|name| *name == key).map(|i| MNEMONICS[i].0))
+                    .collect();
+            } else if line.starts_with('|') {
+                let input = parse_input(line, &columns)
+                    .ok_or_else(|| MovieError::Parse { line: i + 1, reason: format!("invalid input '{}'", line) })?;
+                movie.inputs.push(input);
+            }
+        }
+        Ok(movie)
+    }
+
+    /// Reads a VisualBoyAdvance VBM movie. Only movies that start at power
+    /// on are supported, and only the first controller's buttons are used.
+    pub fn import_vbm(data: &[u8]) -> Result<Self, MovieError> {
+        let error = |reason: &str| MovieError::Parse { line: 0, reason: format!("VBM {}", reason) };
+        let u32_at = |offset: usize| data.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+            .ok_or_else(|| error("header is truncated"));
+        if !data.starts_with(b"VBM\x1a") {
+            return Err(error("signature is missing"));
+        }
+        let frames = u32_at(0x0c)?;
+        let (start, controllers, system) = match data.get(0x14..0x17) {
+            Some(&[start, controllers, system]) => (start, controllers, system),
+            _ => return Err(error("header is truncated")),
+        };
+        if start & 0x03 != 0 {
+            return Err(error("movies starting from a save state or SRAM aren't supported"));
+        }
+        if controllers & 0x01 == 0 {
+            return Err(error("movie doesn't use the first controller"));
+        }
+        let model = match system {
+            flags if flags & 0x01 != 0 => return Err(error("movie is for the Game Boy Advance")),
+            flags if flags & 0x02 != 0 => Some(Model::Cgb),
+            flags if flags & 0x08 != 0 => Some(Model::Sgb),
+            flags if flags & 0x04 != 0 => Some(Model::Dmg),
+            _ => None,
+        };
+        let frame_size = (controllers & 0x0f).count_ones() as usize * 2;
+        let offset = u32_at(0x3c)?;
+        let inputs = data.get(offset..offset + frames * frame_size)
+            .ok_or_else(|| error("input data is truncated"))?
+            .chunks(frame_size)
+            // VBM keeps A, B, Select, Start, Right, Left, Up, Down from bit 0
+            .map(|frame| frame[0].rotate_right(4))
+            .collect();
+
+        Ok(Movie { model, inputs, ..Movie::blank() })
+    }
+
+    /// Checks that `gameboy` is the machine the movie was recorded on and
+    /// that it's still at power on.
+    pub fn check(&self, gameboy: &Gameboy) -> Result<(), MovieError> {
+        if gameboy.cycles() != 0 {
+            return Err(MovieError::Mismatch("movies have to be played from power on".to_string()));
+        }
+        if let Some(crc) = self.rom_crc.filter(|crc| *crc != gameboy.rom_crc()) {
+            return Err(MovieError::Mismatch(format!(
+                "recorded on a ROM with CRC32 {:08x}, this one is {:08x}", crc, gameboy.rom_crc())));
+        }
+        if let Some(model) = self.model.filter(|model| *model != gameboy.model()) {
+            return Err(MovieError::Mismatch(format!("recorded on {}, this machine is a {}", model, gameboy.model())));
+        }
+        if let Some(crc) = self.bootrom_crc.filter(|crc| *crc != gameboy.bootrom_crc()) {
+            let describe = |crc: Option<u32>| crc.map_or("no bootrom".to_string(), |crc| format!("bootrom {:08x}", crc));
+            return Err(MovieError::Mismatch(format!(
+                "recorded with {}, this machine has {}", describe(crc), describe(gameboy.bootrom_crc()))));
+        }
+        Ok(())
+    }
+}
+
+/// Reads an input line such as `|U.....BA|`, where `columns` says which
+/// button each position stands for, if any. '.' or ' ' means released.
+fn parse_input(line: &str, columns: &[Option<Button>]) -> Option<u8> {
+    let fields = line.strip_prefix('|')?.strip_suffix('|')?;
+    let mut chars = fields.chars().filter(|c| *c != '|');
+    let mut buttons = 0;
+    for column in columns {
+        let c = chars.next()?;
+        if let Some(button) = column.filter(|_| c != '.' && c != ' ') {
+            buttons |= 1 << button as u8;
+        }
+    }
+    Some(buttons)
+}
+
+/// Runs until the frame in progress is complete, carrying on past
+/// breakpoints so that frames always line up with the movie.
+fn run_frame(gameboy: &mut Gameboy) -> RunSummary {
+    let (frame, mut cycles) = (gameboy.frames(), 0);
+    loop {
+        let summary = gameboy.run_frame();
+        cycles += summary.cycles;
+        if gameboy.frames() != frame || matches!(summary.stop, StopReason::Error(_)) {
+            return RunSummary { cycles, frames: gameboy.frames() - frame, stop: summary.stop };
+        }
+    }
+}
+
+/// Records the buttons held at the start of each frame, and the state hash
+/// every `hash_interval` frames.
+pub struct Recorder {
+    movie: Movie,
+}
+
+impl Recorder {
+    pub fn new(gameboy: &Gameboy, hash_interval: u64) -> Self {
+        Recorder { movie: Movie::new(gameboy, hash_interval) }
+    }
+
+    /// Runs one frame with whatever buttons are held right now.
+    pub fn run_frame(&mut self, gameboy: &mut Gameboy) -> RunSummary {
+        self.movie.inputs.push(gameboy.buttons());
+        let summary = run_frame(gameboy);
+        let frame = self.movie.inputs.len() as u64;
+        if let StopReason::Error(_) = summary.stop {
+            return summary;
+        }
+        if frame.is_multiple_of(self.movie.hash_interval) {
+            self.movie.hashes.push((frame, gameboy.state_hash()));
+        }
+        summary
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Feeds a movie's inputs to a machine frame by frame and compares state
+/// hashes wherever the movie has one.
+pub struct Player<'a> {
+    movie: &'a Movie,
+    frame: usize,
+    hashes: usize,
+}
+
+impl<'a> Player<'a> {
+    /// Fails unless `gameboy` is the machine the movie was recorded on.
+    pub fn new(movie: &'a Movie, gameboy: &Gameboy) -> Result<Self, MovieError> {
+        movie.check(gameboy)?;
+        Ok(Player { movie, frame: 0, hashes: 0 })
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame >= self.movie.inputs.len()
+    }
+
+    /// Plays the next frame. Returns `None` once the movie is over.
+    pub fn run_frame(&mut self, gameboy: &mut Gameboy) -> Result<Option<RunSummary>, MovieError> {
+        let buttons = match self.movie.inputs.get(self.frame) {
+            Some(buttons) => *buttons,
+            None => return Ok(None),
+        };
+        gameboy.set_buttons(buttons);
+        let summary = run_frame(gameboy);
+        if let StopReason::Error(e) = summary.stop {
+            return Err(MovieError::Emulator(e));
+        }
+        self.frame += 1;
+
+        let frame = self.frame as u64;
+        let hashes = &self.movie.hashes[self.hashes..];
+        if let Some(position) = hashes.iter().position(|(hash_frame, _)| *hash_frame == frame) {
+            self.hashes += position + 1;
+            let (expected, actual) = (hashes[position].1, gameboy.state_hash());
+            if expected != actual {
+                return Err(MovieError::Desync { frame, expected, actual });
+            }
+        }
+        Ok(Some(summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buttons(buttons: &[Button]) -> u8 {
+        buttons.iter().fold(0, |bits, button| bits | 1 << *button as u8)
+    }
+
+    /// A VBM header for a power on movie with the first controller, then
+    /// `inputs` as its input data.
+    fn vbm(system: u8, inputs: &[u16]) -> Vec<u8> {
+        let mut data = vec![0; 0x100];
+        data[..4].copy_from_slice(b"VBM\x1a");
+        data[0x0c..0x10].copy_from_slice(&(inputs.len() as u32).to_le_bytes());
+        data[0x15] = 0x01;
+        data[0x16] = system;
+        data[0x3c..0x40].copy_from_slice(&0x100u32.to_le_bytes());
+        for input in inputs {
+            data.extend_from_slice(&input.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let movie = Movie {
+            rom_crc: Some(0x7b73f868),
+            model: Some(Model::Cgb),
+            bootrom_crc: Some(None),
+            hash_interval: 2,
+            inputs: vec![0, buttons(&[Button::Up, Button::A]), buttons(&[Button::Start]), 0xff],
+            hashes: vec![(2, 0x1a2b3c4d), (4, 0xdeadbeef)],
+        };
+        let mut text = Vec::new();
+        movie.write_to(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("|U......A|\nhash 2 1a2b3c4d\n|....S...|\n"), "{}", text);
+        assert_eq!(Movie::load(text.as_bytes()).unwrap(), movie);
+    }
+
+    #[test]
+    fn parse_errors_give_the_line() {
+        match Movie::parse("rust_boi movie 1\nhash-interval 60\n|U.|\n") {
+            Err(MovieError::Parse { line: 3, .. }) => {}
+            result => panic!("{:?}", result),
+        }
+        assert!(Movie::parse("some other movie\n").is_err());
+        assert!(Movie::parse("rust_boi movie 1\nsram 1234\n").is_err());
+    }
+
+    #[test]
+    fn bk2_buttons_follow_the_log_key() {
+        let log = "[Input]\n\
+                   LogKey:#P1 Up|P1 Down|P1 Left|P1 Right|P1 Start|P1 Select|P1 B|P1 A|Power|\n\
+                   |U......A.|\n\
+                   |...R..B..|\n\
+                   [/Input]\n";
+        let movie = Movie::load(log.as_bytes()).unwrap();
+        assert_eq!(movie.inputs, vec![buttons(&[Button::Up, Button::A]), buttons(&[Button::Right, Button::B])]);
+
+        // Without a LogKey the columns are in the usual order
+        let movie = Movie::import_bk2("|....Ss..|\n").unwrap();
+        assert_eq!(movie.inputs, vec![buttons(&[Button::Start, Button::Select])]);
+        assert!(Movie::import_bk2("|U.|\n").is_err());
+    }
+
+    #[test]
+    fn vbm_buttons_are_mapped() {
+        // A, B, Select, Start, Right, Left, Up, Down from bit 0
+        let movie = Movie::load(&vbm(0x04, &[0x0001, 0x0012, 0x0088, 0x00ff])).unwrap();
+        assert_eq!(movie.model, Some(Model::Dmg));
+        assert_eq!(movie.inputs, vec![
+            buttons(&[Button::A]),
+            buttons(&[Button::B, Button::Right]),
+            buttons(&[Button::Start, Button::Down]),
+            0xff,
+        ]);
+        assert_eq!(Movie::import_vbm(&vbm(0x02, &[])).unwrap().model, Some(Model::Cgb));
+        assert!(Movie::import_vbm(&vbm(0x01, &[])).is_err());
+    }
+
+    #[test]
+    fn truncated_vbm_is_an_error() {
+        let data = vbm(0x04, &[0x0001, 0x0002]);
+        for length in [4, 0x10, 0x14, 0x16, 0x3e, data.len() - 1] {
+            match Movie::import_vbm(&data[..length]) {
+                Err(MovieError::Parse { reason, .. }) => assert!(reason.contains("truncated"), "{}", reason),
+                result => panic!("{} bytes: {:?}", length, result),
+            }
+        }
+    }
+
+    #[test]
+    fn only_a_known_bootrom_is_checked() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14d] = rom[0x134..0x14d].iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+        let booting = Gameboy::new(rom.clone(), Some(vec![0; 0x100]), Some(Model::Dmg)).unwrap();
+        let skipping = Gameboy::new(rom, None, Some(Model::Dmg)).unwrap();
+
+        // Imports can't say whether a bootrom ran
+        let imported = Movie::import_bk2("|U.......|\n").unwrap();
+        assert_eq!(imported.bootrom_crc, None);
+        assert!(imported.check(&booting).is_ok());
+        assert!(imported.check(&skipping).is_ok());
+        let mut text = Vec::new();
+        imported.write_to(&mut text).unwrap();
+        assert!(!String::from_utf8(text).unwrap().contains("bootrom"));
+        assert_eq!(Movie::import_vbm(&vbm(0x04, &[])).unwrap().bootrom_crc, None);
+
+        let skipped = Movie::new(&skipping, 60);
+        assert_eq!(skipped.bootrom_crc, Some(None));
+        assert!(skipped.check(&skipping).is_ok());
+        match skipped.check(&booting) {
+            Err(MovieError::Mismatch(reason)) => {
+                assert_eq!(reason, format!("recorded with no bootrom, this machine has bootrom {:08x}",
+                                           booting.bootrom_crc().unwrap()));
+            }
+            result => panic!("{:?}", result),
+        }
+        assert!(Movie::new(&booting, 60).check(&skipping).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use super::error::EmulatorError;
+use super::memory::{Interrupt, Memory};
+use super::savestate::{StateReader, StateWriter};
+use super::utils::bit_is_set;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const CYCLES_PER_LINE: u32 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const OAM_SEARCH_CYCLES: u32 = 80;
+const PIXEL_TRANSFER_CYCLES: u32 = 172;
+
+const LCDC: usize = 0x40;
+const STAT: usize = 0x41;
+const SCY: usize = 0x42;
+const SCX: usize = 0x43;
+const LY: usize = 0x44;
+const LYC: usize = 0x45;
+const BGP: usize = 0x47;
+const WY: usize = 0x4a;
+const WX: usize = 0x4b;
+
+/// Drives LY/STAT timing and draws the background and window one scanline
+/// at a time into a buffer of DMG shades (0 = lightest, 3 = darkest).
+#[derive(Clone)]
+pub struct Ppu {
+    line: u8,
+    line_cycles: u32,
+    frames: u64,
+    framebuffer: Box<[u8]>,
+    powered_down: bool,
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu {
+            line: 0,
+            line_cycles: 0,
+            frames: 0,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
+            powered_down: false,
+        }
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.line);
+        state.u32(self.line_cycles);
+        state.u64(self.frames);
+        state.bytes(&self.framebuffer);
+        state.bool(self.powered_down);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmulatorError> {
+        self.line = state.u8()?;
+        self.line_cycles = state.u32()?;
+        if self.line >= LINES_PER_FRAME || self.line_cycles >= CYCLES_PER_LINE {
+            return Err(state.error("beam position out of range"));
+        }
+        self.frames = state.u64()?;
+        state.bytes_into(&mut self.framebuffer)?;
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel &= 0x03;
+        }
+        self.powered_down = state.bool()?;
+        Ok(())
+    }
+
+    /// Moves the beam to the start of `line`, for save states that only
+    /// record LY.
+    pub fn set_line(&mut self, line: u8) {
+        self.line = line % LINES_PER_FRAME;
+        self.line_cycles = 0;
+    }
+
+    /// Advances by `cycles` clock cycles. Returns true when VBlank starts.
+    /// Frames keep being counted while the LCD is off so that frame based
+    /// limits still make progress, but nothing gets drawn.
+    pub fn step(&mut self, memory: &mut Memory, cycles: u32) -> bool {
+        self.powered_down = false;
+        let lcd_on = bit_is_set(memory.io_register(LCDC), 7);
+        let entered_vblank = self.advance(cycles, if lcd_on { Some(memory) } else { None });
+        if entered_vblank && lcd_on {
+            memory.request_interrupt(Interrupt::VBlank);
+        }
+
+        let (ly, mode) = if !lcd_on {
+            (0, 0)
+        } else if self.line as usize >= SCREEN_HEIGHT {
+            (self.line, 1)
+        } else if self.line_cycles < OAM_SEARCH_CYCLES {
+            (self.line, 2)
+        } else if self.line_cycles < OAM_SEARCH_CYCLES + PIXEL_TRANSFER_CYCLES {
+            (self.line, 3)
+        } else {
+            (self.line, 0)
+        };
+        let coincidence = ((ly == memory.io_register(LYC)) as u8) << 2;
+        let stat = (memory.io_register(STAT) & 0xf8) | coincidence | mode;
+        memory.set_io_register(LY, ly);
+        memory.set_io_register(STAT, stat);
+
+        entered_vblank
+    }
+
+    /// Advances time while the CPU is in STOP mode with the LCD powered
+    /// down: the screen goes blank and LY/STAT are left alone.
+    pub fn idle(&mut self, cycles: u32) {
+        if !self.powered_down {
+            self.powered_down = true;
+            for pixel in self.framebuffer.iter_mut() {
+                *pixel = 0;
+            }
+        }
+        self.advance(cycles, None);
+    }
+
+    /// Moves the beam forward, drawing each finished visible line when
+    /// given the memory to draw it from.
+    fn advance(&mut self, cycles: u32, memory: Option<&Memory>) -> bool {
+        let mut entered_vblank = false;
+
+        self.line_cycles += cycles;
+        while self.line_cycles >= CYCLES_PER_LINE {
+            self.line_cycles -= CYCLES_PER_LINE;
+            if let Some(memory) = memory {
+                if (self.line as usize) < SCREEN_HEIGHT {
+                    self.render_line(memory);
+                }
+            }
+            self.line = (self.line + 1) % LINES_PER_FRAME;
+            if self.line as usize == SCREEN_HEIGHT {
+                self.frames += 1;
+                entered_vblank = true;
+            }
+        }
+
+        entered_vblank
+    }
+
+    fn render_line(&mut self, memory: &Memory) {
+        let lcdc = memory.io_register(LCDC);
+        let bgp = memory.io_register(BGP);
+        let (scx, scy) = (memory.io_register(SCX), memory.io_register(SCY));
+        let (wx, wy) = (memory.io_register(WX), memory.io_register(WY));
+        let video_ram = memory.video_ram();
+        let line = self.line;
+
+        for x in 0..SCREEN_WIDTH as u8 {
+            let in_window = bit_is_set(lcdc, 5) && line >= wy && x as u16 + 7 >= wx as u16;
+            let color = if in_window {
+                let map = if bit_is_set(lcdc, 6) { 0x1c00 } else { 0x1800 };
+                let window_x = (x as u16 + 7 - wx as u16) as u8;
+                Self::tile_pixel(video_ram, lcdc, map, window_x, line - wy)
+            } else if bit_is_set(lcdc, 0) {
+                let map = if bit_is_set(lcdc, 3) { 0x1c00 } else { 0x1800 };
+                Self::tile_pixel(video_ram, lcdc, map, x.wrapping_add(scx), line.wrapping_add(scy))
+            } else {
+                0
+            };
+            let shade = (bgp >> (color * 2)) & 0x03;
+            self.framebuffer[line as usize * SCREEN_WIDTH + x as usize] = shade;
+        }
+    }
+
+    fn tile_pixel(video_ram: &[u8], lcdc: u8, map: usize, x: u8, y: u8) -> u8 {
+        let tile_index = video_ram[map + (y as usize / 8) * 32 + x as usize / 8];
+        let tile_addr = if bit_is_set(lcdc, 4) {
+            tile_index as usize * 16
+        } else {
+            (0x1000 + (tile_index as i8 as isize) * 16) as usize
+        };
+        let row = tile_addr + (y as usize % 8) * 2;
+        let bit = 7 - (x % 8);
+        (bit_is_set(video_ram[row], bit) as u8) | ((bit_is_set(video_ram[row + 1], bit) as u8) << 1)
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use super::symbols::Symbols;
+
+/// How many routines and addresses the report lists.
+const REPORT_LENGTH: usize = 20;
+/// Calls nested deeper than this are counted towards the routine they were
+/// made from, so code that never returns can't grow the call tree forever.
+const MAX_DEPTH: usize = 256;
+
+/// Instructions executed and the clock cycles they took.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Counts {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+/// How a frame's clock cycles were spent: running instructions and
+/// dispatching interrupts, or halted waiting for one.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct FrameUsage {
+    pub busy: u64,
+    pub idle: u64,
+}
+
+impl FrameUsage {
+    /// The share of the frame the CPU was busy, from 0 to 1.
+    pub fn utilization(&self) -> f64 {
+        match self.busy + self.idle {
+            0 => 0.0,
+            total => self.busy as f64 / total as f64,
+        }
+    }
+}
+
+/// A routine in the call tree: the one it was called from and where it
+/// was entered, `None` for code run outside any call seen.
+struct Node {
+    parent: usize,
+    routine: Option<(usize, u16)>,
+    depth: usize,
+    counts: Counts,
+}
+
+/// Counts the instructions executed and cycles spent at every bank and
+/// address, and in every chain of calls that got there, along with how busy
+/// the CPU was in each frame. `Gameboy` feeds it while one is attached with
+/// `start_profiler`.
+pub struct Profiler {
+    addresses: HashMap<(usize, u16), Counts>,
+    nodes: Vec<Node>,
+    children: HashMap<(usize, (usize, u16)), usize>,
+    current: usize,
+    /// Calls made past `MAX_DEPTH` that haven't returned yet
+    too_deep: usize,
+    idle: u64,
+    frame: Option<u64>,
+    usage: FrameUsage,
+    frames: Vec<FrameUsage>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            addresses: HashMap::new(),
+            nodes: vec![Node { parent: 0, routine: None, depth: 0, counts: Counts::default() }],
+            children: HashMap::new(),
+            current: 0,
+            too_deep: 0,
+            idle: 0,
+            frame: None,
+            usage: FrameUsage::default(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Moves on to `frame`, closing the one before if it changed.
+    pub fn start_frame(&mut self, frame: u64) {
+        if self.frame != Some(frame) {
+            if self.frame.is_some() {
+                self.frames.push(self.usage);
+            }
+            self.frame = Some(frame);
+            self.usage = FrameUsage::default();
+        }
+    }
+
+    /// Counts an instruction at `addr`, with `bank` the ROM bank mapped there.
+    pub fn instruction(&mut self, bank: usize, addr: u16, cycles: u32) {
+        let counts = self.addresses.entry((bank, addr)).or_default();
+        counts.instructions += 1;
+        counts.cycles += cycles as u64;
+        let node = &mut self.nodes[self.current].counts;
+        node.instructions += 1;
+        node.cycles += cycles as u64;
+        self.usage.busy += cycles as u64;
+    }
+
+    /// Counts an interrupt being dispatched to `vector`, which is entered
+    /// like a call.
+    pub fn interrupt(&mut self, vector: u16, cycles: u32) {
+        self.call(0, vector);
+        self.nodes[self.current].counts.cycles += cycles as u64;
+        self.usage.busy += cycles as u64;
+    }
+
+    /// Counts cycles spent halted or stopped.
+    pub fn idle(&mut self, cycles: u32) {
+        self.idle += cycles as u64;
+        self.usage.idle += cycles as u64;
+    }
+
+    /// Enters the routine at `addr`, with `bank` the ROM bank mapped there.
+    pub fn call(&mut self, bank: usize, addr: u16) {
+        let (parent, depth) = (self.current, self.nodes[self.current].depth + 1);
+        if depth > MAX_DEPTH {
+            self.too_deep += 1;
+            return;
+        }
+        let next = self.nodes.len();
+        self.current = *self.children.entry((parent, (bank, addr))).or_insert(next);
+        if self.current == next {
+            self.nodes.push(Node { parent, routine: Some((bank, addr)), depth, counts: Counts::default() });
+        }
+    }
+
+    /// Leaves the current routine for the one that called it.
+    pub fn ret(&mut self) {
+        if self.too_deep > 0 {
+            self.too_deep -= 1;
+        } else {
+            self.current = self.nodes[self.current].parent;
+        }
+    }
+
+    /// The counts for the instruction at `addr` in `bank`.
+    pub fn counts(&self, bank: usize, addr: u16) -> Counts {
+        self.addresses.get(&(bank, addr)).cloned().unwrap_or_default()
+    }
+
+    /// Every frame that has ended since profiling started.
+    pub fn frames(&self) -> &[FrameUsage] {
+        &self.frames
+    }
+
+    /// Lists the routines that took the most cycles, by the cycles spent in
+    /// their own code and including the routines they called, then the
+    /// busiest addresses and how busy the CPU was per frame.
+    pub fn write_report<W: Write>(&self, out: &mut W, symbols: Option<&Symbols>) -> io::Result<()> {
+        let busy: u64 = self.nodes.iter().map(|node| node.counts.cycles).sum();
+        let share = |cycles: u64| if busy == 0 { 0.0 } else { cycles as f64 * 100.0 / busy as f64 };
+        writeln!(out, "{} cycles busy, {} idle", busy, self.idle)?;
+
+        if !self.frames.is_empty() {
+            let utilization: Vec<f64> = self.frames.iter().map(|frame| frame.utilization() * 100.0).collect();
+            let average = utilization.iter().sum::<f64>() / utilization.len() as f64;
+            let min = utilization.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = utilization.iter().cloned().fold(0.0, f64::max);
+            let full = self.frames.iter().filter(|frame| frame.idle == 0).count();
+            writeln!(out, "CPU use over {} frames: {:.1}% average, {:.1}% min, {:.1}% max, {} never halted",
+                     self.frames.len(), average, min, max, full)?;
+        }
+
+        let mut routines: HashMap<Option<(usize, u16)>, (Counts, u64)> = HashMap::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let entry = routines.entry(node.routine).or_default();
+            entry.0.instructions += node.counts.instructions;
+            entry.0.cycles += node.counts.cycles;
+            // A routine recursing is only counted once towards its total
+            let mut seen = Vec::new();
+            for routine in self.path(index) {
+                if !seen.contains(&routine) {
+                    seen.push(routine);
+                    routines.entry(routine).or_default().1 += node.counts.cycles;
+                }
+            }
+        }
+        let mut routines: Vec<_> = routines.into_iter().collect();
+        routines.sort_by(|a, b| (b.1).0.cycles.cmp(&(a.1).0.cycles).then(a.0.cmp(&b.0)));
+        writeln!(out, "\nHottest routines:")?;
+        writeln!(out, "{:>12} {:>6} {:>12} {:>6} {:>12}  routine", "self", "%", "total", "%", "instructions")?;
+        for (routine, (counts, total)) in routines.iter().take(REPORT_LENGTH) {
+            writeln!(out, "{:>12} {:>5.1}% {:>12} {:>5.1}% {:>12}  {}", counts.cycles, share(counts.cycles),
+                     total, share(*total), counts.instructions, name(symbols, *routine))?;
+        }
+
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        writeln!(out, "\nHottest addresses:")?;
+        writeln!(out, "{:>12} {:>6} {:>12}  address", "cycles", "%", "instructions")?;
+        for ((bank, addr), counts) in addresses.into_iter().take(REPORT_LENGTH) {
+            write!(out, "{:>12} {:>5.1}% {:>12}  {:02x}:{:04x}", counts.cycles, share(counts.cycles), counts.instructions, bank, addr)?;
+            match symbols.and_then(|symbols| symbols.describe(*bank, *addr)) {
+                Some(label) => writeln!(out, " <{}>", label)?,
+                None => writeln!(out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the cycles spent in each chain of calls as a collapsed stack
+    /// file, one `outer;inner cycles` line per chain, which flamegraph.pl
+    /// and tools like it turn into a flame graph. Time spent halted shows
+    /// up as a `(halted)` stack of its own.
+    pub fn write_collapsed<W: Write>(&self, out: &mut W, symbols: Option<&Symbols>) -> io::Result<()> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.counts.cycles == 0 {
+                continue;
+            }
+            let mut path: Vec<String> = self.path(index).map(|routine| name(symbols, routine)).collect();
+            path.reverse();
+            writeln!(out, "{} {}", path.join(";"), node.counts.cycles)?;
+        }
+        if self.idle > 0 {
+            writeln!(out, "(halted) {}", self.idle)?;
+        }
+        Ok(())
+    }
+
+    /// The routines from the node at `index` out to the top level.
+    fn path(&self, index: usize) -> impl Iterator<Item = Option<(usize, u16)>> + '_ {
+        let mut next = Some(index);
+        std::iter::from_fn(move || {
+            let node = &self.nodes[next?];
+            next = if node.routine.is_some() { Some(node.parent) } else { None };
+            Some(node.routine)
+        })
+    }
+}
+
+/// Names a routine after its label, or its bank and address without one.
+fn name(symbols: Option<&Symbols>, routine: Option<(usize, u16)>) -> String {
+    match routine {
+        Some((bank, addr)) => match symbols.and_then(|symbols| symbols.describe(bank, addr)) {
+            Some(label) => label,
+            None => format!("{:02x}:{:04x}", bank, addr),
+        },
+        None => "(top level)".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(profiler: &Profiler, symbols: Option<&Symbols>) -> String {
+        let mut out = Vec::new();
+        profiler.write_report(&mut out, symbols).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// The self and total cycles the report gives `routine`.
+    fn routine_cycles(report: &str, routine: &str) -> (u64, u64) {
+        let line = report.lines().find(|line| line.ends_with(&format!("  {}", routine))).unwrap();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        (fields[0].parse().unwrap(), fields[2].parse().unwrap())
+    }
+
+    #[test]
+    fn report_lists_routines_addresses_and_frames() {
+        let mut profiler = Profiler::new();
+        profiler.start_frame(0);
+        profiler.instruction(0, 0x0100, 24);
+        profiler.call(0, 0x0150);
+        profiler.instruction(0, 0x0150, 4);
+        profiler.instruction(0, 0x0151, 16);
+        profiler.ret();
+        profiler.idle(40);
+        profiler.start_frame(1);
+        profiler.instruction(1, 0x4000, 8);
+        profiler.interrupt(0x0040, 20);
+        profiler.instruction(0, 0x0040, 4);
+        profiler.ret();
+        profiler.start_frame(2);
+
+        let symbols = Symbols::parse("00:0150 Routine\n").unwrap();
+        assert_eq!(report(&profiler, Some(&symbols)), "\
+76 cycles busy, 40 idle
+CPU use over 2 frames: 76.2% average, 52.4% min, 100.0% max, 1 never halted
+
+Hottest routines:
+        self      %        total      % instructions  routine
+          32  42.1%           76 100.0%            2  (top level)
+          24  31.6%           24  31.6%            1  00:0040
+          20  26.3%           20  26.3%            2  Routine
+
+Hottest addresses:
+      cycles      % instructions  address
+          24  31.6%            1  00:0100
+          16  21.1%            1  00:0151 <Routine+1>
+           8  10.5%            1  01:4000
+           4   5.3%            1  00:0040
+           4   5.3%            1  00:0150 <Routine>
+");
+        assert_eq!(profiler.counts(0, 0x0151), Counts { instructions: 1, cycles: 16 });
+        assert_eq!(profiler.counts(0, 0x0152), Counts::default());
+    }
+
+    #[test]
+    fn frames_record_busy_and_idle_cycles() {
+        let mut profiler = Profiler::new();
+        profiler.start_frame(5);
+        profiler.instruction(0, 0x0100, 12);
+        profiler.idle(36);
+        // Starting the same frame again carries on with it
+        profiler.start_frame(5);
+        profiler.instruction(0, 0x0100, 12);
+        profiler.start_frame(6);
+        profiler.start_frame(7);
+        assert_eq!(profiler.frames(), [FrameUsage { busy: 24, idle: 36 }, FrameUsage::default()]);
+        assert_eq!(profiler.frames()[0].utilization(), 0.4);
+        assert_eq!(profiler.frames()[1].utilization(), 0.0);
+        assert_eq!(FrameUsage { busy: 10, idle: 0 }.utilization(), 1.0);
+        assert!(!report(&Profiler::new(), None).contains("CPU use"));
+    }
+
+    #[test]
+    fn calls_past_max_depth_count_towards_the_deepest_routine() {
+        let mut profiler = Profiler::new();
+        for i in 0..MAX_DEPTH + 10 {
+            profiler.call(0, 0x1000 + i as u16);
+        }
+        assert_eq!(profiler.nodes.len(), MAX_DEPTH + 1);
+        profiler.instruction(0, 0x2000, 4);
+        let deepest = format!("00:{:04x}", 0x1000 + MAX_DEPTH - 1);
+        assert_eq!(routine_cycles(&report(&profiler, None), &deepest), (4, 4));
+
+        // The calls that weren't tracked return first
+        for _ in 0..10 {
+            profiler.ret();
+        }
+        profiler.instruction(0, 0x2000, 4);
+        assert_eq!(routine_cycles(&report(&profiler, None), &deepest), (8, 8));
+        for _ in 0..MAX_DEPTH {
+            profiler.ret();
+        }
+        profiler.instruction(0, 0x0100, 4);
+        assert_eq!(routine_cycles(&report(&profiler, None), "(top level)"), (4, 12));
+    }
+
+    #[test]
+    fn recursion_counts_once_towards_a_routines_total() {
+        let mut profiler = Profiler::new();
+        profiler.call(0, 0x0200);
+        profiler.instruction(0, 0x0200, 4);
+        profiler.call(0, 0x0200);
+        profiler.instruction(0, 0x0200, 8);
+        profiler.call(0, 0x0300);
+        profiler.instruction(0, 0x0300, 100);
+        profiler.ret();
+        profiler.call(0, 0x0200);
+        profiler.instruction(0, 0x0200, 16);
+
+        let report = report(&profiler, None);
+        assert_eq!(routine_cycles(&report, "00:0200"), (28, 128));
+        assert_eq!(routine_cycles(&report, "00:0300"), (100, 100));
+        assert_eq!(routine_cycles(&report, "(top level)"), (0, 128));
+
+        let mut collapsed = Vec::new();
+        profiler.write_collapsed(&mut collapsed, None).unwrap();
+        assert_eq!(String::from_utf8(collapsed).unwrap(), "\
+(top level);00:0200 4
+(top level);00:0200;00:0200 8
+(top level);00:0200;00:0200;00:0300 100
+(top level);00:0200;00:0200;00:0200 16
+");
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::VecDeque;
+
+/// Save states captured every few frames, newest last, kept within a memory
+/// budget by dropping the oldest ones.
+///
+/// Only the newest state is stored whole. Every older one is stored as the
+/// XOR of itself and the state after it, which is mostly zeros since little
+/// changes in a few frames, and then run length encoded. Stepping back
+/// undoes one delta at a time, and dropping the oldest state never breaks
+/// the chain because nothing depends on it.
+///
+/// Every change to the held buttons is logged too, by frame and clock
+/// cycle, so that the frames between a state and the point rewound to can
+/// be run again with the same input.
+pub struct Rewind {
+    interval: u64,
+    budget: usize,
+    latest: Option<(u64, Vec<u8>)>,
+    older: VecDeque<(u64, Vec<u8>)>,
+    used: usize,
+    inputs: VecDeque<Input>,
+}
+
+/// The buttons held from clock cycle `cycles`, during `frame`, on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Input {
+    pub frame: u64,
+    pub cycles: u64,
+    pub buttons: u8,
+}
+
+impl Rewind {
+    /// Keeps a state every `interval` frames in at most `budget` bytes.
+    /// The newest state is always kept, even if it's bigger than that.
+    pub fn new(interval: u64, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            latest: None,
+            older: VecDeque::new(),
+            used: 0,
+            inputs: VecDeque::new(),
+        }
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// How many states can be rewound to.
+    pub fn len(&self) -> usize {
+        self.older.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// Bytes taken up by the stored states.
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    /// Frame number of the oldest state still kept.
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.older.front().or(self.latest.as_ref()).map(|(frame, _)| *frame)
+    }
+
+    /// Stores `state`, taken at `frame`, as the newest one.
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        if let Some((latest_frame, latest)) = self.latest.take() {
+            let delta = encode_delta(&latest, &state);
+            self.used = self.used - latest.len() + delta.len();
+            self.older.push_back((latest_frame, delta));
+        }
+        self.used += state.len();
+        self.latest = Some((frame, state));
+
+        while self.used > self.budget {
+            match self.older.pop_front() {
+                Some((_, delta)) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+        // Input from before the oldest state can't be replayed any more
+        if let Some(oldest) = self.oldest_frame() {
+            while self.inputs.front().is_some_and(|input| input.frame < oldest) {
+                self.inputs.pop_front();
+            }
+        }
+    }
+
+    /// Logs the buttons changing to `buttons` at clock cycle `cycles`,
+    /// during `frame`. Input that doesn't change anything isn't kept.
+    pub fn record_input(&mut self, frame: u64, cycles: u64, buttons: u8) {
+        if self.inputs.back().is_none_or(|input| input.buttons != buttons) {
+            self.inputs.push_back(Input { frame, cycles, buttons });
+        }
+    }
+
+    /// The input logged from clock cycle `cycles` on, oldest first.
+    pub fn inputs_since(&self, cycles: u64) -> Vec<Input> {
+        self.inputs.iter().filter(|input| input.cycles >= cycles).cloned().collect()
+    }
+
+    /// Forgets the input logged after clock cycle `cycles`, which belongs
+    /// to a future that was rewound away.
+    pub fn truncate_inputs(&mut self, cycles: u64) {
+        while self.inputs.back().is_some_and(|input| input.cycles > cycles) {
+            self.inputs.pop_back();
+        }
+    }
+
+    /// Removes and returns the newest state along with its frame number.
+    pub fn pop(&mut self) -> Option<(u64, Vec<u8>)> {
+        let (frame, state) = self.latest.take()?;
+        self.used -= state.len();
+        if let Some((older_frame, delta)) = self.older.pop_back() {
+            let older = decode_delta(&delta, &state);
+            self.used = self.used - delta.len() + older.len();
+            self.latest = Some((older_frame, older));
+        }
+        Some((frame, state))
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.older.clear();
+        self.used = 0;
+        self.inputs.clear();
+    }
+}
+
+/// Encodes `older` against `newer`: its length as a little endian u32,
+/// then the XOR of the two, with the shorter one padded with zeros, as
+/// runs. A control byte with bit 7 set repeats the next byte
+/// `(control & 0x7f) + 1` times, otherwise `control + 1` literal bytes follow.
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let length = older.len().max(newer.len());
+    let xor: Vec<u8> = (0..length)
+        .map(|i| older.get(i).unwrap_or(&0) ^ newer.get(i).unwrap_or(&0))
+        .collect();
+
+    let mut out = (older.len() as u32).to_le_bytes().to_vec();
+    let mut i = 0;
+    while i < xor.len() {
+        let run = xor[i..].iter().take(128).take_while(|byte| **byte == xor[i]).count();
+        if run >= 3 {
+            out.push(0x80 | (run - 1) as u8);
+            out.push(xor[i]);
+            i += run;
+            continue;
+        }
+        // Literals go on until the next run worth encoding
+        let start = i;
+        while i < xor.len() && i - start < 128 {
+            if i + 2 < xor.len() && xor[i] == xor[i + 1] && xor[i] == xor[i + 2] {
+                break;
+            }
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&xor[start..i]);
+    }
+    out
+}
+
+fn decode_delta(delta: &[u8], newer: &[u8]) -> Vec<u8> {
+    let length = u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]) as usize;
+    let mut xor = Vec::with_capacity(length.max(newer.len()));
+    let mut i = 4;
+    while i < delta.len() {
+        let control = delta[i];
+        if control & 0x80 != 0 {
+            let run = (control & 0x7f) as usize + 1;
+            xor.extend(std::iter::repeat_n(delta[i + 1], run));
+            i += 2;
+        } else {
+            let count = control as usize + 1;
+            xor.extend_from_slice(&delta[i + 1..i + 1 + count]);
+            i += 1 + count;
+        }
+    }
+    (0..length).map(|i| xor[i] ^ newer.get(i).unwrap_or(&0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(older: &[u8], newer: &[u8]) {
+        let delta = encode_delta(older, newer);
+        assert_eq!(decode_delta(&delta, newer), older);
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        let newer: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mut older = newer.clone();
+        round_trip(&older, &newer);
+
+        // Scattered changes, a run longer than one control byte covers and
+        // more literals than one covers
+        older[10] ^= 0xff;
+        older[11] ^= 0x01;
+        for byte in &mut older[200..500] {
+            *byte = 0x55;
+        }
+        for (i, byte) in older[600..900].iter_mut().enumerate() {
+            *byte ^= i as u8 | 1;
+        }
+        round_trip(&older, &newer);
+
+        round_trip(&older[..700], &newer);
+        round_trip(&older, &newer[..300]);
+        round_trip(&[], &newer);
+        round_trip(&older, &[]);
+    }
+
+    #[test]
+    fn unchanged_state_encodes_small() {
+        let state = vec![0xab; 10000];
+        assert!(encode_delta(&state, &state).len() < 200);
+    }
+
+    #[test]
+    fn pop_returns_states_newest_first() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        let states: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 64 + i as usize]).collect();
+        for (frame, state) in states.iter().enumerate() {
+            rewind.push(frame as u64, state.clone());
+        }
+        assert_eq!(rewind.len(), 5);
+        for (frame, state) in states.iter().enumerate().rev() {
+            assert_eq!(rewind.pop(), Some((frame as u64, state.clone())));
+        }
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_used(), 0);
+    }
+
+    #[test]
+    fn budget_evicts_oldest_states() {
+        let budget = 1000;
+        let mut rewind = Rewind::new(1, budget);
+        for frame in 0..100u64 {
+            // Every state differs everywhere from the one before, so deltas
+            // don't shrink
+            let state: Vec<u8> = (0..200).map(|i| (i as u64 * 31 + frame * 17) as u8).collect();
+            rewind.push(frame, state);
+            assert!(rewind.memory_used() <= budget);
+        }
+        assert!(rewind.len() > 1 && rewind.len() < 100);
+        assert_eq!(rewind.oldest_frame(), Some(100 - rewind.len() as u64));
+        assert_eq!(rewind.pop().map(|(frame, _)| frame), Some(99));
+    }
+
+    #[test]
+    fn newest_state_is_kept_over_budget() {
+        let mut rewind = Rewind::new(1, 10);
+        rewind.push(0, vec![1; 100]);
+        rewind.push(1, vec![2; 100]);
+        assert_eq!(rewind.len(), 1);
+        assert_eq!(rewind.pop(), Some((1, vec![2; 100])));
+    }
+
+    #[test]
+    fn inputs_follow_the_states_kept() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        rewind.push(0, vec![0]);
+        rewind.record_input(0, 100, 0x10);
+        rewind.record_input(0, 150, 0x10);
+        rewind.record_input(1, 200, 0x00);
+        rewind.record_input(2, 300, 0x01);
+        assert_eq!(rewind.inputs_since(150).iter().map(|input| input.cycles).collect::<Vec<_>>(), vec![200, 300]);
+
+        rewind.truncate_inputs(250);
+        assert_eq!(rewind.inputs_since(0).len(), 2);
+
+        let mut small = Rewind::new(1, 0);
+        small.record_input(0, 0, 0x01);
+        small.record_input(1, 10, 0x02);
+        small.push(1, vec![0]);
+        assert_eq!(small.inputs_since(0), vec![Input { frame: 1, cycles: 10, buttons: 0x02 }]);
+    }
+}
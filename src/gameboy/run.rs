@@ -0,0 +1,29 @@
+use super::error::EmulatorError;
+use super::watchpoint::WatchHit;
+
+/// Why one of `Gameboy`'s `run_*` methods handed control back.
+#[derive(Debug)]
+pub enum StopReason {
+    /// `run_frame` reached the start of VBlank
+    FrameComplete,
+    /// `run_cycles` ran for the requested number of cycles
+    CyclesElapsed,
+    /// The predicate given to `run_until` returned true
+    Predicate,
+    /// The next instruction to execute sits on a breakpoint
+    Breakpoint(u16),
+    /// The last instruction made an access a watchpoint was waiting for
+    Watchpoint(WatchHit),
+    /// The hook set with `set_ld_b_b_hook` asked to stop after the LD B,B at
+    /// this address
+    LdBB(u16),
+    Error(EmulatorError),
+}
+
+/// What happened during a call to one of `Gameboy`'s `run_*` methods.
+#[derive(Debug)]
+pub struct RunSummary {
+    pub cycles: u64,
+    pub frames: u64,
+    pub stop: StopReason,
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+
+use super::error::EmulatorError;
+use super::model::Model;
+
+/// Save states start with this magic and the format version:
+///
+/// ```text
+/// "RBSS" version:u32 rom_crc32:u32 header_checksum:u8 model:u8
+/// section*
+/// ```
+///
+/// followed by sections of `tag:[u8; 4] length:u32 payload`. Every number
+/// is little endian. Each component owns one section, so a state can be
+/// read back regardless of the order they were written in, and sections
+/// a reader doesn't know about are skipped. The last section always holds
+/// a copy of the state in BESS format, whose footer has to end the file.
+///
+/// There's no cartridge RAM, MBC, APU, timer, DMA or RTC state because none
+/// of those are emulated yet; they'll get their own sections once they are.
+pub const MAGIC: &[u8; 4] = b"RBSS";
+pub const VERSION: u32 = 1;
+
+const MODELS: [Model; 6] = [Model::Dmg0, Model::Dmg, Model::Mgb, Model::Cgb, Model::Sgb, Model::Sgb2];
+
+/// Builds a save state one value at a time.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_crc: u32, header_checksum: u8, model: Model) -> Self {
+        let mut writer = StateWriter { data: Vec::new() };
+        writer.data.extend_from_slice(MAGIC);
+        writer.u32(VERSION);
+        writer.u32(rom_crc);
+        writer.u8(header_checksum);
+        writer.u8(MODELS.iter().position(|m| *m == model).unwrap_or(0) as u8);
+        writer
+    }
+
+    /// Writes a section whose payload is whatever `write` adds.
+    pub fn section<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], write: F) {
+        self.data.extend_from_slice(tag);
+        let length_at = self.data.len();
+        self.u32(0);
+        write(self);
+        let length = (self.data.len() - length_at - 4) as u32;
+        self.data[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Offset of the next byte from the start of the state.
+    pub fn position(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Writes bytes as they are, without a length.
+    pub fn raw(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes a length prefixed block of bytes.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// The parts of a save state that identify what it was taken from, and its
+/// sections by tag.
+pub struct SaveState<'a> {
+    pub rom_crc: u32,
+    pub header_checksum: u8,
+    pub model: Model,
+    sections: HashMap<[u8; 4], &'a [u8]>,
+}
+
+impl<'a> SaveState<'a> {
+    /// Checks the magic and version and splits `data` into its sections.
+    pub fn parse(data: &'a [u8]) -> Result<Self, EmulatorError> {
+        let mut header = StateReader::new("header", data);
+        let mut magic = [0; 4];
+        header.fill(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a save state".to_string()));
+        }
+        let version = header.u32()?;
+        if version != VERSION {
+            return Err(invalid(format!("version {} isn't supported, expected {}", version, VERSION)));
+        }
+        let rom_crc = header.u32()?;
+        let header_checksum = header.u8()?;
+        let model = *MODELS.get(header.u8()? as usize)
+            .ok_or_else(|| invalid("unknown model".to_string()))?;
+
+        let mut sections = HashMap::new();
+        while !header.is_empty() {
+            let mut tag = [0; 4];
+            header.fill(&mut tag)?;
+            let length = header.u32()? as usize;
+            sections.insert(tag, header.take(length)?);
+        }
+
+        Ok(SaveState { rom_crc, header_checksum, model, sections })
+    }
+
+    pub fn section(&self, tag: &'static [u8; 4]) -> Result<StateReader<'a>, EmulatorError> {
+        let name = std::str::from_utf8(tag).unwrap_or("?").trim_end();
+        match self.sections.get(tag) {
+            Some(data) => Ok(StateReader::new(name, data)),
+            None => Err(invalid(format!("missing {} section", name))),
+        }
+    }
+}
+
+/// Reads values back in the order a `StateWriter` wrote them.
+pub struct StateReader<'a> {
+    name: &'a str,
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(name: &'a str, data: &'a [u8]) -> Self {
+        StateReader { name, data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn take(&mut self, length: usize) -> Result<&'a [u8], EmulatorError> {
+        if self.data.len() < length {
+            return Err(invalid(format!("{} section is truncated", self.name)));
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn fill(&mut self, out: &mut [u8]) -> Result<(), EmulatorError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, EmulatorError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, EmulatorError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, EmulatorError> {
+        let mut bytes = [0; 2];
+        self.fill(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, EmulatorError> {
+        let mut bytes = [0; 4];
+        self.fill(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, EmulatorError> {
+        let mut bytes = [0; 8];
+        self.fill(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Reads a length prefixed block of bytes into `out`, which has to be
+    /// exactly as long as the block.
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> Result<(), EmulatorError> {
+        let length = self.u32()? as usize;
+        if length != out.len() {
+            return Err(invalid(format!("{} section has a block of {} bytes, expected {}",
+                                       self.name, length, out.len())));
+        }
+        self.fill(out)
+    }
+
+    /// Fails with a description of what's wrong with a value in this section.
+    pub fn error(&self, reason: &str) -> EmulatorError {
+        invalid(format!("{} section: {}", self.name, reason))
+    }
+}
+
+fn invalid(reason: String) -> EmulatorError {
+    EmulatorError::InvalidSaveState(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::gameboy::Gameboy;
+
+    /// A ROM that counts up in 0xc000 forever.
+    fn gameboy(model: Model, version: u8) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        // ld hl, $c000; inc [hl]; jr -3
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xc0, 0x34, 0x18, 0xfd]);
+        rom[0x14c] = version;
+        rom[0x14d] = rom[0x134..0x14d].iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+        Gameboy::new(rom, None, Some(model)).unwrap()
+    }
+
+    fn reason(result: Result<(), EmulatorError>) -> String {
+        match result {
+            Err(EmulatorError::InvalidSaveState(reason)) => reason,
+            result => panic!("{:?}", result),
+        }
+    }
+
+    /// Where the payload of the CPU section, which is written first, starts.
+    const CPU_PAYLOAD: usize = 14 + 8;
+
+    #[test]
+    fn states_round_trip() {
+        let mut gameboy = gameboy(Model::Dmg, 0);
+        gameboy.run_frame();
+        let state = gameboy.save_state();
+        let (hash, registers, counter) = (gameboy.state_hash(), gameboy.registers(), gameboy.read_memory(0xc000));
+
+        gameboy.run_frame();
+        assert_ne!(gameboy.state_hash(), hash);
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.state_hash(), hash);
+        assert_eq!(gameboy.registers().pc, registers.pc);
+        assert_eq!(gameboy.read_memory(0xc000), counter);
+        assert_eq!(gameboy.save_state(), state);
+    }
+
+    #[test]
+    fn states_from_another_rom_or_model_are_rejected() {
+        let state = gameboy(Model::Dmg, 0).save_state();
+
+        let mut other = gameboy(Model::Dmg, 1);
+        let hash = other.state_hash();
+        assert!(reason(other.load_state(&state)).starts_with("taken from another ROM"));
+        assert_eq!(other.state_hash(), hash);
+
+        let mut other = gameboy(Model::Mgb, 0);
+        assert_eq!(reason(other.load_state(&state)), "taken on DMG, this machine is a MGB");
+
+        assert_eq!(reason(other.load_state(b"RBSX")), "not a save state");
+        let mut newer = state.clone();
+        newer[4] = 2;
+        assert_eq!(reason(other.load_state(&newer)), "version 2 isn't supported, expected 1");
+    }
+
+    #[test]
+    fn broken_sections_are_rejected_and_change_nothing() {
+        let mut gameboy = gameboy(Model::Dmg, 0);
+        let state = gameboy.save_state();
+        assert_eq!(&state[14..18], b"CPU ");
+        gameboy.run_frame();
+        let hash = gameboy.state_hash();
+
+        // Registers, SP, PC and IME flags come before the CPU's state
+        let mut unknown = state.clone();
+        unknown[CPU_PAYLOAD + 14] = 9;
+        assert_eq!(reason(gameboy.load_state(&unknown)), "CPU section: unknown CPU state");
+
+        // A CPU section cut short, with its length still right
+        let mut truncated = state[..CPU_PAYLOAD + 10].to_vec();
+        truncated[18..22].copy_from_slice(&10u32.to_le_bytes());
+        let length = u32::from_le_bytes([state[18], state[19], state[20], state[21]]) as usize;
+        truncated.extend_from_slice(&state[CPU_PAYLOAD + length..]);
+        assert_eq!(reason(gameboy.load_state(&truncated)), "CPU section is truncated");
+
+        // A section running past the end of the state
+        assert_eq!(reason(gameboy.load_state(&state[..CPU_PAYLOAD + 4])), "header section is truncated");
+
+        let mut missing = state.clone();
+        missing[14..18].copy_from_slice(b"XPU ");
+        assert_eq!(reason(gameboy.load_state(&missing)), "missing CPU section");
+
+        assert_eq!(gameboy.state_hash(), hash);
+    }
+}
@@ -0,0 +1,699 @@
+use super::error::EmulatorError;
+use super::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use super::savestate::{StateReader, StateWriter};
+use super::utils::bit_is_set;
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+const CELLS_X: usize = SCREEN_WIDTH / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT / 8;
+const BORDER_TILES_SIZE: usize = 256 * 32;
+const BORDER_MAP_SIZE: usize = 32 * 32;
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+const TRANSFER_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mask {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Clone, Copy)]
+enum Transfer {
+    Idle,
+    Receiving,
+    StopBit,
+}
+
+/// Super Game Boy state: decodes command packets pulsed through P1 and
+/// keeps the palettes, attribute map and border used to build the final
+/// 256x224 picture.
+#[derive(Clone)]
+pub struct Sgb {
+    transfer: Transfer,
+    last_p1: u8,
+    bit_index: usize,
+    packet: [u8; PACKET_SIZE],
+    packets: Vec<[u8; PACKET_SIZE]>,
+
+    palettes: [[u16; 4]; 4],
+    attributes: [u8; CELLS_X * CELLS_Y],
+    mask: Mask,
+    output: Vec<u32>,
+
+    border_tiles: Box<[u8]>,
+    border_map: Box<[u16]>,
+    border_palettes: [[u16; 16]; 4],
+
+    players: u8,
+    current_player: u8,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            transfer: Transfer::Idle,
+            last_p1: 0x30,
+            bit_index: 0,
+            packet: [0; PACKET_SIZE],
+            packets: Vec::new(),
+            palettes: [[0x7fff, 0x5294, 0x294a, 0x0000]; 4],
+            attributes: [0; CELLS_X * CELLS_Y],
+            mask: Mask::Cancel,
+            output: vec![0; SGB_WIDTH * SGB_HEIGHT],
+            border_tiles: vec![0; BORDER_TILES_SIZE].into_boxed_slice(),
+            border_map: vec![0; BORDER_MAP_SIZE].into_boxed_slice(),
+            border_palettes: [[0; 16]; 4],
+            players: 1,
+            current_player: 0,
+        }
+    }
+
+    /// Handles a write to P1 (0xff00). Bits 4 and 5 are pulsed by the game
+    /// to shift packets in one bit at a time: both low resets the transfer,
+    /// P14 low sends a 0 and P15 low sends a 1. `video_ram` and `lcdc` are
+    /// needed for the *_TRN commands which copy the displayed tile data.
+    pub fn write_joypad(&mut self, value: u8, video_ram: &[u8], lcdc: u8) {
+        let lines = value & 0x30;
+        let previous = self.last_p1;
+        self.last_p1 = lines;
+
+        if lines == 0x30 && previous == 0x10 && self.players > 1 {
+            self.current_player = (self.current_player + 1) % self.players;
+        }
+
+        match lines {
+            0x00 => {
+                self.transfer = Transfer::Receiving;
+                self.bit_index = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x10 | 0x20 if previous == 0x30 => {
+                let bit = lines == 0x10;
+                self.receive_bit(bit, video_ram, lcdc);
+            }
+            _ => {}
+        }
+    }
+
+    /// Value of the low nibble of P1 when neither button group is selected.
+    /// With MLT_REQ active this reports the currently selected controller.
+    pub fn joypad_id(&self) -> u8 {
+        0x0f - self.current_player
+    }
+
+    pub fn multiplayer(&self) -> bool {
+        self.players > 1
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.transfer as u8);
+        state.u8(self.last_p1);
+        state.u8(self.bit_index as u8);
+        state.bytes(&self.packet);
+        state.u8(self.packets.len() as u8);
+        for packet in &self.packets {
+            state.bytes(packet);
+        }
+        for color in self.palettes.iter().flatten() {
+            state.u16(*color);
+        }
+        state.bytes(&self.attributes);
+        state.u8(self.mask as u8);
+        for pixel in &self.output {
+            state.u32(*pixel);
+        }
+        state.bytes(&self.border_tiles);
+        for entry in self.border_map.iter() {
+            state.u16(*entry);
+        }
+        for color in self.border_palettes.iter().flatten() {
+            state.u16(*color);
+        }
+        state.u8(self.players);
+        state.u8(self.current_player);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmulatorError> {
+        self.transfer = match state.u8()? {
+            0 => Transfer::Idle,
+            1 => Transfer::Receiving,
+            2 => Transfer::StopBit,
+            _ => return Err(state.error("unknown SGB transfer state")),
+        };
+        self.last_p1 = state.u8()? & 0x30;
+        self.bit_index = state.u8()? as usize;
+        if self.bit_index > PACKET_BITS {
+            return Err(state.error("SGB packet bit out of range"));
+        }
+        state.bytes_into(&mut self.packet)?;
+        self.packets.clear();
+        let count = state.u8()?;
+        if count > 7 {
+            return Err(state.error("too many queued SGB packets"));
+        }
+        for _ in 0..count {
+            let mut packet = [0; PACKET_SIZE];
+            state.bytes_into(&mut packet)?;
+            self.packets.push(packet);
+        }
+        for color in self.palettes.iter_mut().flatten() {
+            *color = state.u16()?;
+        }
+        state.bytes_into(&mut self.attributes)?;
+        for attribute in self.attributes.iter_mut() {
+            *attribute &= 0x03;
+        }
+        self.mask = match state.u8()? {
+            0 => Mask::Cancel,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            _ => return Err(state.error("unknown SGB mask")),
+        };
+        for pixel in self.output.iter_mut() {
+            *pixel = state.u32()?;
+        }
+        state.bytes_into(&mut self.border_tiles)?;
+        for entry in self.border_map.iter_mut() {
+            *entry = state.u16()?;
+        }
+        for color in self.border_palettes.iter_mut().flatten() {
+            *color = state.u16()?;
+        }
+        self.players = state.u8()?;
+        self.current_player = state.u8()?;
+        if ![1, 2, 4].contains(&self.players) || self.current_player >= self.players {
+            return Err(state.error("SGB player count out of range"));
+        }
+        Ok(())
+    }
+
+    /// The border tiles, border tilemap, border palettes, colorization
+    /// palettes and attribute map, laid out the way BESS stores them.
+    pub fn bess_buffers(&self) -> [Vec<u8>; 5] {
+        [
+            self.border_tiles.to_vec(),
+            words_to_bytes(self.border_map.iter()),
+            words_to_bytes(self.border_palettes.iter().flatten()),
+            words_to_bytes(self.palettes.iter().flatten()),
+            self.attributes.to_vec(),
+        ]
+    }
+
+    /// Player count in the high nibble and the current player in the low one.
+    pub fn bess_multiplayer(&self) -> u8 {
+        (self.players << 4) | self.current_player
+    }
+
+    /// Restores what `bess_buffers` and `bess_multiplayer` describe. Any
+    /// packet that was being received is dropped since BESS doesn't keep it.
+    pub fn load_bess(&mut self, buffers: [&[u8]; 5], multiplayer: u8) -> Result<(), String> {
+        let [tiles, map, border_palettes, palettes, attributes] = buffers;
+        let expected = [BORDER_TILES_SIZE, BORDER_MAP_SIZE * 2, 4 * 16 * 2, 4 * 4 * 2, CELLS_X * CELLS_Y];
+        for (buffer, size) in buffers.iter().zip(expected.iter()) {
+            if buffer.len() != *size {
+                return Err(format!("SGB buffer of {} bytes, expected {}", buffer.len(), size));
+            }
+        }
+        let (players, current_player) = (multiplayer >> 4, multiplayer & 0x0f);
+        if ![1, 2, 4].contains(&players) || current_player >= players {
+            return Err(format!("unsupported SGB multiplayer status {:#04x}", multiplayer));
+        }
+
+        let word = |bytes: &[u8]| u16::from(bytes[0]) | (u16::from(bytes[1]) << 8);
+        self.border_tiles.copy_from_slice(tiles);
+        for (entry, bytes) in self.border_map.iter_mut().zip(map.chunks(2)) {
+            *entry = word(bytes);
+        }
+        for (color, bytes) in self.border_palettes.iter_mut().flatten().zip(border_palettes.chunks(2)) {
+            *color = word(bytes);
+        }
+        for (color, bytes) in self.palettes.iter_mut().flatten().zip(palettes.chunks(2)) {
+            *color = word(bytes);
+        }
+        for (attribute, value) in self.attributes.iter_mut().zip(attributes) {
+            *attribute = value & 0x03;
+        }
+        self.players = players;
+        self.current_player = current_player;
+        self.transfer = Transfer::Idle;
+        self.packets.clear();
+        Ok(())
+    }
+
+    fn receive_bit(&mut self, bit: bool, video_ram: &[u8], lcdc: u8) {
+        match self.transfer {
+            Transfer::Idle => {}
+            Transfer::Receiving => {
+                if bit {
+                    self.packet[self.bit_index / 8] |= 1 << (self.bit_index % 8);
+                }
+                self.bit_index += 1;
+                if self.bit_index == PACKET_BITS {
+                    self.transfer = Transfer::StopBit;
+                }
+            }
+            Transfer::StopBit => {
+                self.transfer = Transfer::Idle;
+                if bit {
+                    debug!("SGB packet missing stop bit, dropping it");
+                    self.packets.clear();
+                    return;
+                }
+                self.packets.push(self.packet);
+                let expected = (self.packets[0][0] & 0x07).max(1) as usize;
+                if self.packets.len() >= expected {
+                    let data: Vec<u8> = self.packets.drain(..).flat_map(|p| p.to_vec()).collect();
+                    self.execute(&data, video_ram, lcdc);
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, data: &[u8], video_ram: &[u8], lcdc: u8) {
+        let command = data[0] >> 3;
+        debug!("SGB command: {:#x}", command);
+        match command {
+            0x00 => self.set_palettes(data, 0, 1), // PAL01
+            0x01 => self.set_palettes(data, 2, 3), // PAL23
+            0x02 => self.set_palettes(data, 0, 3), // PAL03
+            0x03 => self.set_palettes(data, 1, 2), // PAL12
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x11 => { // MLT_REQ
+                self.players = match data[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            0x13 => { // CHR_TRN
+                let transfer = Self::transfer_data(video_ram, lcdc);
+                let offset = (data[1] & 0x01) as usize * TRANSFER_SIZE;
+                self.border_tiles[offset..offset + TRANSFER_SIZE].copy_from_slice(&transfer);
+            }
+            0x14 => { // PCT_TRN
+                let transfer = Self::transfer_data(video_ram, lcdc);
+                for (entry, bytes) in self.border_map.iter_mut().zip(transfer.chunks(2)) {
+                    *entry = u16::from(bytes[0]) | (u16::from(bytes[1]) << 8);
+                }
+                let palettes = &transfer[0x800..0x880];
+                for (i, bytes) in palettes.chunks(2).enumerate() {
+                    self.border_palettes[i / 16][i % 16] = u16::from(bytes[0]) | (u16::from(bytes[1]) << 8);
+                }
+            }
+            0x17 => { // MASK_EN
+                self.mask = match data[1] & 0x03 {
+                    0x01 => Mask::Freeze,
+                    0x02 => Mask::Black,
+                    0x03 => Mask::Color0,
+                    _ => Mask::Cancel,
+                };
+            }
+            _ => debug!("Unsupported SGB command: {:#x}", command),
+        }
+    }
+
+    fn transfer_data(video_ram: &[u8], lcdc: u8) -> Vec<u8> {
+        // The SGB samples the picture as drawn, which for *_TRN is 4KB of
+        // tiles laid out in order starting at the BG tile data base.
+        let base = if bit_is_set(lcdc, 4) { 0x0000 } else { 0x0800 };
+        video_ram[base..base + TRANSFER_SIZE].to_vec()
+    }
+
+    fn set_palettes(&mut self, data: &[u8], first: usize, second: usize) {
+        let color = |i: usize| u16::from(data[1 + i * 2]) | (u16::from(data[2 + i * 2]) << 8);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < CELLS_X && y < CELLS_Y {
+            self.attributes[y * CELLS_X + x] = palette & 0x03;
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] & 0x1f) as usize;
+        for set in data[2..].chunks(6).take(count) {
+            if set.len() < 6 {
+                break;
+            }
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let border = (set[1] >> 2) & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // With only one of inside/outside set the border takes its palette
+            let border = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => border,
+            };
+            let change_border = control & 0x02 != 0 || control == 0x01 || control == 0x04;
+            let (x1, y1) = ((set[2] & 0x1f) as usize, (set[3] & 0x1f) as usize);
+            let (x2, y2) = ((set[4] & 0x1f) as usize, (set[5] & 0x1f) as usize);
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let in_box = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_edge = in_box && (x == x1 || x == x2 || y == y1 || y == y2);
+                    if on_edge && change_border {
+                        self.set_attribute(x, y, border);
+                    } else if in_box && !on_edge && control & 0x01 != 0 {
+                        self.set_attribute(x, y, inside);
+                    } else if !in_box && control & 0x04 != 0 {
+                        self.set_attribute(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let index = (line & 0x1f) as usize;
+            let palette = (line >> 5) & 0x03;
+            if bit_is_set(line, 7) {
+                for x in 0..CELLS_X {
+                    self.set_attribute(x, index, palette);
+                }
+            } else {
+                for y in 0..CELLS_Y {
+                    self.set_attribute(index, y, palette);
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = bit_is_set(data[1], 6);
+        let coordinate = (data[2] & 0x1f) as usize;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                let palette = if position < coordinate {
+                    before
+                } else if position == coordinate {
+                    on_line
+                } else {
+                    after
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] & 0x1f) as usize, (data[2] & 0x1f) as usize);
+        let count = (u16::from(data[3]) | (u16::from(data[4]) << 8)) as usize;
+        let vertical = data[5] & 0x01 == 0x01;
+        let palettes = data[6..].iter()
+            .flat_map(|byte| (0..4).rev().map(move |i| (byte >> (i * 2)) & 0x03))
+            .take(count.min(CELLS_X * CELLS_Y));
+        for palette in palettes {
+            self.set_attribute(x, y, palette);
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x = (x + 1) % CELLS_X;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y = (y + 1) % CELLS_Y;
+                }
+            }
+        }
+    }
+
+    /// The last composited 256x224 picture. Pixels are 0x00RRGGBB.
+    pub fn output(&self) -> &[u32] {
+        &self.output
+    }
+
+    /// Builds the SGB picture from a 160x144 buffer of DMG shades (0-3,
+    /// after BGP is applied). While the mask is frozen the old picture stays.
+    pub fn composite(&mut self, screen: &[u8]) {
+        if self.mask == Mask::Freeze {
+            return;
+        }
+
+        let backdrop = rgb555_to_rgb888(self.palettes[0][0]);
+        let mut output = vec![backdrop; SGB_WIDTH * SGB_HEIGHT];
+
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let color = match self.mask {
+                    Mask::Black => 0x000000,
+                    Mask::Color0 => backdrop,
+                    _ => {
+                        let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                        let shade = (screen[y * SCREEN_WIDTH + x] & 0x03) as usize;
+                        rgb555_to_rgb888(self.palettes[palette][shade])
+                    }
+                };
+                output[(y + SCREEN_Y) * SGB_WIDTH + x + SCREEN_X] = color;
+            }
+        }
+
+        self.draw_border(&mut output);
+        self.output = output;
+    }
+
+    fn draw_border(&self, output: &mut [u32]) {
+        for tile_y in 0..SGB_HEIGHT / 8 {
+            for tile_x in 0..SGB_WIDTH / 8 {
+                let entry = self.border_map[tile_y * 32 + tile_x];
+                let tile = (entry & 0xff) as usize;
+                // Border palettes are numbered 4-7, so the low two bits index them
+                let palette = ((entry >> 10) & 0x03) as usize;
+                let flip_x = entry & 0x4000 != 0;
+                let flip_y = entry & 0x8000 != 0;
+                let tile_data = &self.border_tiles[tile * 32..tile * 32 + 32];
+                for row in 0..8 {
+                    let src_row = if flip_y { 7 - row } else { row };
+                    let planes = [
+                        tile_data[src_row * 2],
+                        tile_data[src_row * 2 + 1],
+                        tile_data[16 + src_row * 2],
+                        tile_data[16 + src_row * 2 + 1],
+                    ];
+                    for col in 0..8 {
+                        let bit = if flip_x { col } else { 7 - col } as u8;
+                        let color = planes.iter().enumerate()
+                            .fold(0, |acc, (i, plane)| acc | ((bit_is_set(*plane, bit) as usize) << i));
+                        // Color 0 is transparent and lets the game screen show through
+                        if color == 0 {
+                            continue;
+                        }
+                        let (x, y) = (tile_x * 8 + col, tile_y * 8 + row);
+                        output[y * SGB_WIDTH + x] = rgb555_to_rgb888(self.border_palettes[palette][color]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn words_to_bytes<'a, I: Iterator<Item = &'a u16>>(words: I) -> Vec<u8> {
+    words.flat_map(|word| word.to_le_bytes().to_vec()).collect()
+}
+
+fn rgb555_to_rgb888(color: u16) -> u32 {
+    let expand = |c: u16| {
+        let c = u32::from(c & 0x1f);
+        (c << 3) | (c >> 2)
+    };
+    (expand(color) << 16) | (expand(color >> 5) << 8) | expand(color >> 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::Model;
+
+    const VIDEO_RAM: [u8; 0x2000] = [0; 0x2000];
+
+    /// Pulses P1 the way the SGB expects: a reset, one bit per pulse with
+    /// P1 going back to 0x30 between them, then a 0 stop bit.
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE], stop_bit: bool) {
+        sgb.write_joypad(0x00, &VIDEO_RAM, 0);
+        sgb.write_joypad(0x30, &VIDEO_RAM, 0);
+        let bits = (0..PACKET_BITS).map(|i| bit_is_set(packet[i / 8], (i % 8) as u8));
+        for bit in bits.chain(std::iter::once(stop_bit)) {
+            sgb.write_joypad(if bit { 0x10 } else { 0x20 }, &VIDEO_RAM, 0);
+            sgb.write_joypad(0x30, &VIDEO_RAM, 0);
+        }
+    }
+
+    /// Sends `data` as a command, in as many packets as it takes.
+    fn send(sgb: &mut Sgb, data: &[u8]) {
+        for chunk in data.chunks(PACKET_SIZE) {
+            let mut packet = [0; PACKET_SIZE];
+            packet[..chunk.len()].copy_from_slice(chunk);
+            send_packet(sgb, &packet, false);
+        }
+    }
+
+    fn pal01(colors: [u16; 7]) -> Vec<u8> {
+        let mut data = vec![0x01]; // PAL01, one packet
+        data.extend(colors.iter().flat_map(|color| color.to_le_bytes()));
+        data
+    }
+
+    fn attribute(sgb: &Sgb, x: usize, y: usize) -> u8 {
+        sgb.attributes[y * CELLS_X + x]
+    }
+
+    #[test]
+    fn pal01_sets_palettes_0_and_1_and_the_shared_color_0() {
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &pal01([0x001f, 0x03e0, 0x7c00, 0x1234, 0x0001, 0x0002, 0x0003]));
+        assert_eq!(sgb.palettes[0], [0x001f, 0x03e0, 0x7c00, 0x1234]);
+        assert_eq!(sgb.palettes[1], [0x001f, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[2], [0x001f, 0x5294, 0x294a, 0x0000]);
+        assert_eq!(sgb.palettes[3][0], 0x001f);
+    }
+
+    #[test]
+    fn packets_need_a_reset_pulse_and_a_zero_stop_bit() {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..5].copy_from_slice(&pal01([0x7fff, 0x0123, 0, 0, 0, 0, 0])[..5]);
+
+        // Bits pulsed without a reset first are ignored
+        let mut sgb = Sgb::new();
+        for _ in 0..=PACKET_BITS {
+            sgb.write_joypad(0x10, &VIDEO_RAM, 0);
+            sgb.write_joypad(0x30, &VIDEO_RAM, 0);
+        }
+        assert_eq!(sgb.palettes[0][1], 0x5294);
+
+        // Holding a line low sends a single bit, not one per write
+        sgb.write_joypad(0x00, &VIDEO_RAM, 0);
+        sgb.write_joypad(0x30, &VIDEO_RAM, 0);
+        sgb.write_joypad(0x10, &VIDEO_RAM, 0);
+        sgb.write_joypad(0x10, &VIDEO_RAM, 0);
+        sgb.write_joypad(0x30, &VIDEO_RAM, 0);
+        assert_eq!(sgb.bit_index, 1);
+
+        send_packet(&mut sgb, &packet, true);
+        assert_eq!(sgb.palettes[0][1], 0x5294);
+        assert!(sgb.packets.is_empty());
+
+        send_packet(&mut sgb, &packet, false);
+        assert_eq!(sgb.palettes[0][..2], [0x7fff, 0x0123]);
+    }
+
+    #[test]
+    fn multi_packet_commands_run_once_every_packet_is_in() {
+        // Three ATTR_BLK sets don't fit in one packet
+        let mut data = vec![0x04 << 3 | 2, 3];
+        data.extend_from_slice(&[0x01, 0x01, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0x01, 0x02, 1, 1, 1, 1]);
+        data.extend_from_slice(&[0x01, 0x03, 2, 2, 2, 2]);
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &data[..PACKET_SIZE]);
+        assert_eq!(sgb.packets.len(), 1);
+        assert_eq!(attribute(&sgb, 0, 0), 0);
+
+        send(&mut sgb, &data[PACKET_SIZE..]);
+        assert!(sgb.packets.is_empty());
+        assert_eq!((attribute(&sgb, 0, 0), attribute(&sgb, 1, 1), attribute(&sgb, 2, 2)), (1, 2, 3));
+    }
+
+    #[test]
+    fn attr_blk_sets_inside_border_and_outside() {
+        let attr_blk = |control: u8| vec![0x04 << 3 | 1, 1, control, 0x39, 2, 3, 6, 8];
+        let cells = |sgb: &Sgb| [(4, 5), (2, 3), (6, 8), (4, 3), (2, 6), (0, 0), (7, 5), (4, 9)]
+            .map(|(x, y)| attribute(sgb, x, y));
+
+        // Inside palette 1, border 2 and outside 3 on a box from (2, 3) to (6, 8)
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &attr_blk(0x07));
+        assert_eq!(cells(&sgb), [1, 2, 2, 2, 2, 3, 3, 3]);
+
+        // With only inside or outside the border goes along with it
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &attr_blk(0x01));
+        assert_eq!(cells(&sgb), [1, 1, 1, 1, 1, 0, 0, 0]);
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &attr_blk(0x04));
+        assert_eq!(cells(&sgb), [0, 3, 3, 3, 3, 3, 3, 3]);
+
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &attr_blk(0x02));
+        assert_eq!(cells(&sgb), [0, 2, 2, 2, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn mask_en_freezes_blanks_or_clears_the_picture() {
+        let pixel = SCREEN_Y * SGB_WIDTH + SCREEN_X;
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &pal01([0x001f, 0x7c00, 0, 0, 0, 0, 0]));
+        let mut screen = vec![1; SCREEN_WIDTH * SCREEN_HEIGHT];
+        sgb.composite(&screen);
+        assert_eq!(sgb.output()[pixel], 0x0000ff);
+        assert_eq!(sgb.output()[0], 0xff0000);
+
+        send(&mut sgb, &[0x17 << 3 | 1, 0x01]);
+        assert_eq!(sgb.mask, Mask::Freeze);
+        screen.fill(0);
+        sgb.composite(&screen);
+        assert_eq!(sgb.output()[pixel], 0x0000ff);
+
+        send(&mut sgb, &[0x17 << 3 | 1, 0x02]);
+        sgb.composite(&screen);
+        assert_eq!(sgb.output()[pixel], 0x000000);
+
+        send(&mut sgb, &[0x17 << 3 | 1, 0x03]);
+        screen.fill(1);
+        sgb.composite(&screen);
+        assert_eq!(sgb.output()[pixel], 0xff0000);
+
+        send(&mut sgb, &[0x17 << 3 | 1, 0x00]);
+        assert_eq!(sgb.mask, Mask::Cancel);
+        sgb.composite(&screen);
+        assert_eq!(sgb.output()[pixel], 0x0000ff);
+    }
+
+    #[test]
+    fn states_with_too_many_queued_packets_are_rejected() {
+        let mut writer = StateWriter::new(0, 0, Model::Sgb);
+        let start = writer.position();
+        Sgb::new().save_state(&mut writer);
+        let mut data = writer.finish();
+        // Transfer, P1 and bit index, then the packet being received
+        let count_at = start + 3 + 4 + PACKET_SIZE;
+
+        assert!(Sgb::new().load_state(&mut StateReader::new("SGB", &data[start..])).is_ok());
+        for count in [8, 0xff] {
+            data[count_at] = count;
+            match Sgb::new().load_state(&mut StateReader::new("SGB", &data[start..])) {
+                Err(EmulatorError::InvalidSaveState(reason)) => {
+                    assert_eq!(reason, "SGB section: too many queued SGB packets");
+                }
+                result => panic!("{} packets: {:?}", count, result.err()),
+            }
+        }
+    }
+}
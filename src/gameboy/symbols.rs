@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Labels read from a symbol file, looked up by bank and address or by name.
+///
+/// RGBDS `.sym` files and the No$gmb and BGB ones look the same: a
+/// `BANK:ADDRESS NAME` line per label, in hex, with `;` starting a comment.
+/// No$gmb files put theirs under a `[labels]` heading and other sections
+/// are skipped. Banks only tell ROM labels apart, so labels elsewhere in
+/// memory are kept whatever bank they're in.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    by_addr: BTreeMap<(usize, u16), String>,
+    by_name: HashMap<String, (usize, u16)>,
+}
+
+/// The address ranges labels are kept within when describing an address
+/// as an offset from one.
+fn region(addr: u16) -> u8 {
+    match addr {
+        0x0000..=0x3fff => 0,
+        0x4000..=0x7fff => 1,
+        0x8000..=0x9fff => 2,
+        0xa000..=0xbfff => 3,
+        0xc000..=0xfdff => 4,
+        0xfe00..=0xff7f => 5,
+        _ => 6,
+    }
+}
+
+fn key(bank: usize, addr: u16) -> (usize, u16) {
+    if addr < 0x8000 { (bank, addr) } else { (0, addr) }
+}
+
+impl Symbols {
+    /// Parses a symbol file. Errors name the line that couldn't be read.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::default();
+        let mut in_labels = true;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_labels = line.eq_ignore_ascii_case("[labels]");
+                continue;
+            }
+            if !in_labels {
+                continue;
+            }
+
+            let error = |reason: &str| format!("line {}: {}", number + 1, reason);
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => return Err(error("expected BANK:ADDRESS NAME")),
+            };
+            let (bank, addr) = match location.split_once(':') {
+                Some((bank, addr)) => (usize::from_str_radix(bank, 16), u16::from_str_radix(addr, 16)),
+                None => (Ok(0), u16::from_str_radix(location, 16)),
+            };
+            match (bank, addr) {
+                (Ok(bank), Ok(addr)) => symbols.insert(bank, addr, name),
+                _ => return Err(error(&format!("invalid address '{}'", location))),
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Adds a label. The first label at an address is the one shown for it.
+    pub fn insert(&mut self, bank: usize, addr: u16, name: &str) {
+        self.by_addr.entry(key(bank, addr)).or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), (bank, addr));
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// The bank and address of the label `name`.
+    pub fn lookup(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// The label at exactly `addr`, with `bank` the ROM bank mapped there.
+    pub fn name_at(&self, bank: usize, addr: u16) -> Option<&str> {
+        self.by_addr.get(&key(bank, addr)).map(String::as_str)
+    }
+
+    /// Names `addr` after the closest label at or before it in the same
+    /// bank and region of memory, e.g. `Main.loop` or `Main.loop+3`.
+    pub fn describe(&self, bank: usize, addr: u16) -> Option<String> {
+        let (bank, _) = key(bank, addr);
+        let ((label_bank, label_addr), name) = self.by_addr.range(..=key(bank, addr)).next_back()?;
+        if *label_bank != bank || region(*label_addr) != region(addr) {
+            return None;
+        }
+        match addr - label_addr {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+{}", name, offset)),
+        }
+    }
+
+    /// Replaces the `$xxxx` addresses in an instruction's text with the
+    /// labels at them, if any. `bank` is the ROM bank to use for addresses
+    /// in 0x4000-0x7fff.
+    pub fn symbolize(&self, text: &str, bank: usize) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            let digits = &rest[start + 1..];
+            let length = digits.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(digits.len());
+            let name = match (length, u16::from_str_radix(&digits[..length], 16)) {
+                (4, Ok(addr)) => self.name_at(if addr < 0x4000 { 0 } else { bank }, addr),
+                _ => None,
+            };
+            match name {
+                Some(name) => out.push_str(name),
+                None => out.push_str(&rest[start..start + 1 + length]),
+            }
+            rest = &digits[length..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGBDS: &str = "; File generated by rgblink
+00:0150 Main
+00:0158 Main.loop
+01:4000 Bank1Routine
+02:4000 Bank2Routine
+00:c000 wCounter
+00:ff80 hStack
+";
+
+    #[test]
+    fn rgbds() {
+        let symbols = Symbols::parse(RGBDS).unwrap();
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(symbols.lookup("Main.loop"), Some((0, 0x0158)));
+        assert_eq!(symbols.lookup("Bank2Routine"), Some((2, 0x4000)));
+        assert_eq!(symbols.name_at(0, 0x0150), Some("Main"));
+        assert_eq!(symbols.name_at(1, 0x4000), Some("Bank1Routine"));
+        assert_eq!(symbols.name_at(2, 0x4000), Some("Bank2Routine"));
+        assert_eq!(symbols.name_at(3, 0x4000), None);
+        // Banks don't matter outside ROM
+        assert_eq!(symbols.name_at(1, 0xc000), Some("wCounter"));
+    }
+
+    #[test]
+    fn nogmb_labels_section() {
+        let text = "[config]\nsome setting\n\n[labels]\n0000:0150 Start ; entry\n0001:4100 Far\n0c000 wRam\n[definitions]\n1234 NotALabel\n";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.lookup("Start"), Some((0, 0x0150)));
+        assert_eq!(symbols.lookup("Far"), Some((1, 0x4100)));
+        assert_eq!(symbols.lookup("wRam"), Some((0, 0xc000)));
+        assert_eq!(symbols.lookup("NotALabel"), None);
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        assert_eq!(Symbols::parse("00:0150 Main\n00:0160\n").unwrap_err(), "line 2: expected BANK:ADDRESS NAME");
+        assert_eq!(Symbols::parse("zz:0150 Main\n").unwrap_err(), "line 1: invalid address 'zz:0150'");
+    }
+
+    #[test]
+    fn describe() {
+        let symbols = Symbols::parse(RGBDS).unwrap();
+        assert_eq!(symbols.describe(0, 0x0150).as_deref(), Some("Main"));
+        assert_eq!(symbols.describe(0, 0x015b).as_deref(), Some("Main.loop+3"));
+        assert_eq!(symbols.describe(0, 0x0100), None);
+        // Bank 0 labels don't reach into switchable ROM
+        assert_eq!(symbols.describe(0, 0x3fff).as_deref(), Some("Main.loop+16039"));
+        assert_eq!(symbols.describe(1, 0x4000).as_deref(), Some("Bank1Routine"));
+        assert_eq!(symbols.describe(2, 0x4010).as_deref(), Some("Bank2Routine+16"));
+        assert_eq!(symbols.describe(3, 0x4010), None);
+        // Nor do ROM labels reach into RAM, where the bank is ignored
+        assert_eq!(symbols.describe(0, 0x8000), None);
+        assert_eq!(symbols.describe(2, 0xc002).as_deref(), Some("wCounter+2"));
+        assert_eq!(symbols.describe(0, 0xff81).as_deref(), Some("hStack+1"));
+    }
+
+    #[test]
+    fn symbolize() {
+        let symbols = Symbols::parse(RGBDS).unwrap();
+        assert_eq!(symbols.symbolize("call $4000", 2), "call Bank2Routine");
+        assert_eq!(symbols.symbolize("jr nz, $0158", 2), "jr nz, Main.loop");
+        assert_eq!(symbols.symbolize("ld [$c000], a", 1), "ld [wCounter], a");
+        assert_eq!(symbols.symbolize("ld a, $01", 1), "ld a, $01");
+        assert_eq!(symbols.symbolize("jp $0159", 1), "jp $0159");
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{self, BufWriter, Write};
+use std::ops::{Range, RangeInclusive};
+
+use super::cpu::Registers;
+use super::symbols::Symbols;
+
+/// Logs the registers before every instruction in the format Gameboy Doctor
+/// and a few reference emulators use, so two traces can be diffed:
+///
+/// ```text
+/// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+/// ```
+///
+/// `PCMEM` is the four bytes from PC on. Logging starts when the cartridge
+/// is entered at 0x0100 with the bootrom unmapped, so a trace with and one
+/// without a bootrom line up, and can be narrowed to instructions in
+/// `pc_range` and to the `cycles` window of clock cycles since power on.
+///
+/// With symbols, lines end in the label PC is at, which other emulators'
+/// traces don't have:
+///
+/// ```text
+/// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0153 PCMEM:00,C3,13,02 ; Main.loop+3
+/// ```
+pub struct Trace {
+    out: BufWriter<Box<dyn Write + Send>>,
+    pc_range: Option<RangeInclusive<u16>>,
+    cycles: Option<Range<u64>>,
+    symbols: Option<Symbols>,
+    started: bool,
+}
+
+impl Trace {
+    pub fn new<W: Write + Send + 'static>(out: W, pc_range: Option<RangeInclusive<u16>>, cycles: Option<Range<u64>>) -> Self {
+        Trace {
+            out: BufWriter::new(Box::new(out)),
+            pc_range,
+            cycles,
+            symbols: None,
+            started: false,
+        }
+    }
+
+    /// Labels each line with where PC is in `symbols`.
+    pub fn with_symbols(self, symbols: Symbols) -> Self {
+        Trace { symbols: Some(symbols), ..self }
+    }
+
+    /// Whether the cycle window has been left behind, so nothing more
+    /// will be logged.
+    pub fn is_done(&self, cycles: u64) -> bool {
+        self.cycles.as_ref().is_some_and(|window| cycles >= window.end)
+    }
+
+    /// Logs the instruction about to run at `registers.pc` in ROM `bank`,
+    /// whose first bytes are `pc_memory`, if it passes the filters.
+    pub fn log(&mut self, registers: &Registers, pc_memory: [u8; 4], bank: usize, cycles: u64,
+               bootrom_mapped: bool) -> io::Result<()> {
+        if !self.started {
+            if registers.pc != 0x0100 || bootrom_mapped {
+                return Ok(());
+            }
+            self.started = true;
+        }
+        if self.pc_range.as_ref().is_some_and(|range| !range.contains(&registers.pc))
+            || self.cycles.as_ref().is_some_and(|window| !window.contains(&cycles)) {
+            return Ok(());
+        }
+        write!(self.out, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+               registers.a, registers.f, registers.b, registers.c, registers.d, registers.e, registers.h, registers.l,
+               registers.sp, registers.pc, pc_memory[0], pc_memory[1], pc_memory[2], pc_memory[3])?;
+        match self.symbols.as_ref().and_then(|symbols| symbols.describe(bank, registers.pc)) {
+            Some(name) => writeln!(self.out, " ; {}", name),
+            None => writeln!(self.out),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer whose output the test can still read once the trace owns it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn registers(pc: u16) -> Registers {
+        let mut registers = Registers::default();
+        registers.set_af(0x01b0);
+        registers.set_bc(0x0013);
+        registers.set_de(0x00d8);
+        registers.set_hl(0x014d);
+        registers.sp = 0xfffe;
+        registers.pc = pc;
+        registers
+    }
+
+    /// Logs an instruction at each of `pcs`, one cycle apart, with ROM bank 1
+    /// mapped.
+    fn trace(mut trace: Trace, out: &Shared, pcs: &[u16]) -> String {
+        for (cycles, pc) in pcs.iter().enumerate() {
+            let bank = if (0x4000..0x8000).contains(pc) { 1 } else { 0 };
+            trace.log(&registers(*pc), [0x00, 0xc3, 0x13, 0x02], bank, cycles as u64, false).unwrap();
+        }
+        trace.flush().unwrap();
+        out.text()
+    }
+
+    #[test]
+    fn lines_match_gameboy_doctor() {
+        let out = Shared::default();
+        assert_eq!(trace(Trace::new(out.clone(), None, None), &out, &[0x100]),
+                   "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02\n");
+    }
+
+    #[test]
+    fn symbols_label_lines_when_asked() {
+        let symbols = Symbols::parse("00:0150 Main\n01:4000 Banked\n02:4000 Other\n").unwrap();
+        let out = Shared::default();
+        let text = trace(Trace::new(out.clone(), None, None).with_symbols(symbols), &out, &[0x100, 0x153, 0x4002]);
+        assert_eq!(text, "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02\n\
+                          A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0153 PCMEM:00,C3,13,02 ; Main+3\n\
+                          A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:4002 PCMEM:00,C3,13,02 ; Banked+2\n");
+    }
+
+    #[test]
+    fn logging_starts_at_the_cartridge_entry_point() {
+        let out = Shared::default();
+        let mut trace = Trace::new(out.clone(), None, None);
+        trace.log(&registers(0x0000), [0; 4], 1, 0, true).unwrap();
+        trace.log(&registers(0x0100), [0; 4], 1, 1, true).unwrap();
+        trace.log(&registers(0x0150), [0; 4], 1, 2, false).unwrap();
+        trace.flush().unwrap();
+        assert_eq!(out.text(), "");
+
+        trace.log(&registers(0x0100), [0; 4], 1, 3, false).unwrap();
+        trace.log(&registers(0x0000), [0; 4], 1, 4, false).unwrap();
+        trace.flush().unwrap();
+        let text = out.text();
+        let pcs: Vec<&str> = text.lines().map(|line| &line[48..55]).collect();
+        assert_eq!(pcs, ["PC:0100", "PC:0000"]);
+    }
+
+    #[test]
+    fn pc_range_and_cycles_narrow_the_trace() {
+        let pcs = [0x100, 0x101, 0x150, 0x151, 0x152, 0x200, 0x151];
+        let logged = |text: String| text.lines().map(|line| line[51..55].to_string()).collect::<Vec<_>>();
+
+        let out = Shared::default();
+        assert_eq!(logged(trace(Trace::new(out.clone(), Some(0x150..=0x151), None), &out, &pcs)),
+                   ["0150", "0151", "0151"]);
+
+        // Cycles count from 0 at the first instruction, one per instruction
+        let out = Shared::default();
+        assert_eq!(logged(trace(Trace::new(out.clone(), None, Some(2..5)), &out, &pcs)), ["0150", "0151", "0152"]);
+
+        let out = Shared::default();
+        assert_eq!(logged(trace(Trace::new(out.clone(), Some(0x150..=0x151), Some(3..7)), &out, &pcs)),
+                   ["0151", "0151"]);
+
+        let trace = Trace::new(Shared::default(), None, Some(2..5));
+        assert!(!trace.is_done(4));
+        assert!(trace.is_done(5));
+        assert!(!Trace::new(Shared::default(), None, None).is_done(u64::MAX));
+    }
+}
@@ -1,3 +1,16 @@
 pub fn bit_is_set(value: u8, bit_number: u8) -> bool {
     ((value >> bit_number) & 0x01) == 0x01
-}
\ No newline at end of file
+}
+
+/// The CRC-32 used by PNG and zlib.
+pub fn crc32<'a, I: Iterator<Item = &'a u8>>(bytes: I) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
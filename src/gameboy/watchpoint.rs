@@ -0,0 +1,129 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// The kind of memory access a watchpoint reacts to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    /// Any write, even one that leaves the byte as it was
+    Write,
+    /// A write that changes the byte
+    Change,
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Change => "change",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Stops a run after an instruction accesses memory in `addresses`. With
+/// `value` set it only fires when the byte read, or the byte left behind
+/// by a write, is that value.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub addresses: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    /// Whether an access of this kind to `addr` fires the watchpoint. For
+    /// reads `old` and `new` are both the byte read; for writes they're the
+    /// byte before and after, which is the byte written unless the address
+    /// is read only or an IO register that changes it.
+    pub fn matches(&self, kind: WatchKind, addr: u16, old: u8, new: u8) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Read => kind == WatchKind::Read,
+            WatchKind::Write => kind == WatchKind::Write,
+            WatchKind::Change => kind == WatchKind::Write && old != new,
+        };
+        kind_matches && self.addresses.contains(&addr) && self.value.is_none_or(|value| value == new)
+    }
+}
+
+/// A watchpoint firing: where it happened and what the byte held before
+/// and after. `pc` is the start of the instruction that made the access,
+/// or the instruction interrupted when the access was an interrupt pushing
+/// its return address.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WatchHit {
+    pub kind: WatchKind,
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+    pub pc: u16,
+    pub bank: usize,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            WatchKind::Read => write!(f, "{:02x}:{:04x} read {:#04x} from {:#06x}",
+                                      self.bank, self.pc, self.new, self.addr),
+            _ => write!(f, "{:02x}:{:04x} wrote {:#04x} to {:#06x}, which held {:#04x}",
+                        self.bank, self.pc, self.new, self.addr, self.old),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch(kind: WatchKind, value: Option<u8>) -> Watchpoint {
+        Watchpoint { addresses: 0xc000..=0xc00f, kind, value }
+    }
+
+    #[test]
+    fn kinds_match_their_accesses() {
+        let read = watch(WatchKind::Read, None);
+        assert!(read.matches(WatchKind::Read, 0xc000, 0x12, 0x12));
+        assert!(!read.matches(WatchKind::Write, 0xc000, 0x12, 0x34));
+
+        let write = watch(WatchKind::Write, None);
+        assert!(write.matches(WatchKind::Write, 0xc00f, 0x12, 0x34));
+        assert!(write.matches(WatchKind::Write, 0xc00f, 0x12, 0x12));
+        assert!(!write.matches(WatchKind::Read, 0xc00f, 0x12, 0x12));
+
+        let change = watch(WatchKind::Change, None);
+        assert!(change.matches(WatchKind::Write, 0xc008, 0x12, 0x34));
+        assert!(!change.matches(WatchKind::Write, 0xc008, 0x12, 0x12));
+        assert!(!change.matches(WatchKind::Read, 0xc008, 0x12, 0x34));
+
+        for watchpoint in [read, write, change] {
+            assert!(!watchpoint.matches(watchpoint.kind, 0xbfff, 0x12, 0x34));
+            assert!(!watchpoint.matches(watchpoint.kind, 0xc010, 0x12, 0x34));
+        }
+    }
+
+    #[test]
+    fn values_are_compared_with_the_byte_read_or_left_behind() {
+        let read = watch(WatchKind::Read, Some(0x42));
+        assert!(read.matches(WatchKind::Read, 0xc000, 0x42, 0x42));
+        assert!(!read.matches(WatchKind::Read, 0xc000, 0x41, 0x41));
+
+        let write = watch(WatchKind::Write, Some(0x42));
+        assert!(write.matches(WatchKind::Write, 0xc000, 0x00, 0x42));
+        assert!(write.matches(WatchKind::Write, 0xc000, 0x42, 0x42));
+        assert!(!write.matches(WatchKind::Write, 0xc000, 0x42, 0x00));
+
+        let change = watch(WatchKind::Change, Some(0x42));
+        assert!(change.matches(WatchKind::Write, 0xc000, 0x00, 0x42));
+        assert!(!change.matches(WatchKind::Write, 0xc000, 0x42, 0x42));
+        assert!(!change.matches(WatchKind::Write, 0xc000, 0x42, 0x00));
+    }
+
+    #[test]
+    fn hits_describe_the_access() {
+        let hit = WatchHit { kind: WatchKind::Read, addr: 0xc000, old: 0x12, new: 0x12, pc: 0x4003, bank: 1 };
+        assert_eq!(hit.to_string(), "01:4003 read 0x12 from 0xc000");
+        let hit = WatchHit { kind: WatchKind::Change, new: 0x13, ..hit };
+        assert_eq!(hit.to_string(), "01:4003 wrote 0x13 to 0xc000, which held 0x12");
+    }
+}
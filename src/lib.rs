@@ -0,0 +1,29 @@
+//! Game Boy emulator core.
+//!
+//! Build a [`Gameboy`] from a ROM image, then drive it one instruction at a
+//! time with [`Gameboy::step`], or hand it control with
+//! [`Gameboy::run_frame`], [`Gameboy::run_cycles`] and [`Gameboy::run_until`]
+//! which return a [`RunSummary`] once they stop. The rest of its methods read
+//! back the picture, peek and poke memory and registers, and press buttons.
+
+#[macro_use]
+extern crate log;
+
+mod gameboy;
+
+pub use gameboy::{Button, EmulatorError, Gameboy, Model, RunSummary, StopReason};
+pub use gameboy::{SCREEN_WIDTH, SCREEN_HEIGHT, SGB_WIDTH, SGB_HEIGHT};
+pub use gameboy::boot::open_bootrom;
+pub use gameboy::cartridge::Header;
+pub use gameboy::cpu::{CpuState, IllegalOpcodeMode, Registers};
+pub use gameboy::callstack;
+pub use gameboy::debugger;
+pub use gameboy::disasm;
+pub use gameboy::gdb;
+pub use gameboy::headless;
+pub use gameboy::movie;
+pub use gameboy::profiler::Profiler;
+pub use gameboy::rewind::Rewind;
+pub use gameboy::symbols::Symbols;
+pub use gameboy::trace::Trace;
+pub use gameboy::watchpoint::{WatchHit, WatchKind, Watchpoint};
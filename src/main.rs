@@ -1,23 +1,282 @@
 #[macro_use]
 extern crate log;
 
-mod gameboy;
+mod cli;
 
-use std::fs::File;
-use std::io::Read;
-use gameboy::Gameboy;
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use cli::{Boot, Command, DisassembleOptions, Options, USAGE};
+use rust_boi::{debugger, disasm, gdb, headless, open_bootrom, CpuState, Gameboy, Profiler, Symbols, Trace};
+use rust_boi::headless::{EXIT_LOAD_ERROR, EXIT_USAGE};
+use rust_boi::movie::Movie;
 
+fn read_file(kind: &str, path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Could not read {} '{}': {}", kind, path.display(), e))
+}
+
+/// Reads the symbol file given, or the one next to the ROM if there is one.
+/// A broken file sitting next to the ROM is only warned about.
+fn load_symbols(rom: &Path, path: Option<&PathBuf>) -> Result<Option<Symbols>, String> {
+    let (path, given) = match path {
+        Some(path) => (path.clone(), true),
+        None => (rom.with_extension("sym"), false),
+    };
+    if !given && !path.is_file() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(&path)
+        .map_err(|e| format!("Could not read symbol file '{}': {}", path.display(), e))?;
+    match Symbols::parse(&text) {
+        Ok(symbols) => Ok(Some(symbols)),
+        Err(e) if given => Err(format!("Could not load '{}': {}", path.display(), e)),
+        Err(e) => {
+            eprintln!("warning: Ignoring symbol file '{}': {}", path.display(), e);
+            Ok(None)
+        }
+    }
+}
+
+fn load(options: &mut Options) -> Result<Gameboy, String> {
+    if let Some(save_dir) = &options.save_dir {
+        if !save_dir.is_dir() {
+            return Err(format!("Save directory '{}' does not exist", save_dir.display()));
+        }
+    }
+    let rom = read_file("ROM", &options.rom)?;
+    let bootrom = match &options.boot {
+        Boot::Skip => None,
+        Boot::Open => Some(open_bootrom()),
+        Boot::File(path) => Some(read_file("bootrom", path)?),
+    };
+    let mut gameboy = Gameboy::new(rom, bootrom, options.model)
+        .map_err(|e| format!("Could not load '{}': {}", options.rom.display(), e))?;
+    if let Some(symbols) = load_symbols(&options.rom, options.symbols.as_ref())? {
+        info!("Loaded {} symbols", symbols.len());
+        gameboy.set_symbols(symbols);
+    }
+    if let Some(path) = &options.load_state {
+        let state = read_file("save state", path)?;
+        gameboy.load_state(&state)
+            .map_err(|e| format!("Could not load '{}': {}", path.display(), e))?;
+    }
+    if let Some(path) = &options.play_movie {
+        let movie = Movie::load(&read_file("movie", path)?)
+            .and_then(|movie| movie.check(&gameboy).map(|_| movie))
+            .map_err(|e| format!("Could not load '{}': {}", path.display(), e))?;
+        options.run.movie = Some(movie);
+    }
+    if let Some(path) = &options.trace {
+        let file = fs::File::create(path)
+            .map_err(|e| format!("Could not create trace '{}': {}", path.display(), e))?;
+        let trace = Trace::new(file, options.trace_pc.clone(), options.trace_cycles.clone());
+        match gameboy.symbols().filter(|_| options.trace_symbols) {
+            Some(symbols) => gameboy.start_trace(trace.with_symbols(symbols.clone())),
+            None => gameboy.start_trace(trace),
+        }
+    }
+    if options.check_stack {
+        gameboy.check_stack(options.stack_region.clone());
+    }
+    if options.profile.is_some() || options.profile_stacks.is_some() {
+        gameboy.start_profiler(Profiler::new());
+    }
+    Ok(gameboy)
+}
+
+/// Writes `contents` to a new file at `path`.
+fn write_file<F>(kind: &str, path: &Path, contents: F) -> Result<(), String>
+    where F: FnOnce(&mut BufWriter<fs::File>) -> io::Result<()>
+{
+    let error = |e: io::Error| format!("Could not write {} '{}': {}", kind, path.display(), e);
+    let mut out = BufWriter::new(fs::File::create(path).map_err(error)?);
+    contents(&mut out).and_then(|_| out.flush()).map_err(error)
+}
+
+/// Writes out the save state, trace and profile, if asked for, and lists
+/// any stack problems once emulation ends.
+fn finish(gameboy: &mut Gameboy, options: &Options) {
+    let dropped = gameboy.call_stack().map_or(0, |call_stack| call_stack.dropped());
+    for problem in gameboy.take_stack_problems() {
+        eprintln!("stack: {}", problem);
+    }
+    if dropped > 0 {
+        eprintln!("stack: {} more problems not shown", dropped);
+    }
+    if let Some(path) = &options.save_state {
+        if let Err(e) = fs::write(path, gameboy.save_state()) {
+            eprintln!("error: Could not write save state '{}': {}", path.display(), e);
+        }
+    }
+    if let Err(e) = gameboy.finish_trace() {
+        eprintln!("error: Could not write the trace: {}", e);
+    }
+    if let Some(profiler) = gameboy.take_profiler() {
+        let symbols = gameboy.symbols();
+        if let Some(path) = &options.profile {
+            if let Err(e) = write_file("profile", path, |out| profiler.write_report(out, symbols)) {
+                eprintln!("error: {}", e);
+            }
+        }
+        if let Some(path) = &options.profile_stacks {
+            if let Err(e) = write_file("profile stacks", path, |out| profiler.write_collapsed(out, symbols)) {
+                eprintln!("error: {}", e);
+            }
+        }
+    }
+}
+
+/// Lists the requested banks, or every bank when none is given. An address
+/// range without a bank picks the bank it falls in, counting 0x4000-0x7fff
+/// as bank 1.
+fn disassemble(options: &DisassembleOptions) -> Result<(), String> {
+    let rom = read_file("ROM", &options.rom)?;
+    let symbols = load_symbols(&options.rom, options.symbols.as_ref())?;
+    let banks = match (options.bank, options.start) {
+        (Some(bank), _) if bank >= disasm::bank_count(&rom) => {
+            return Err(format!("Bank {} doesn't exist, the ROM has {}", bank, disasm::bank_count(&rom)));
+        }
+        (Some(bank), _) => bank..bank + 1,
+        (None, Some(start)) => {
+            let bank = (start as usize / disasm::BANK_SIZE).min(1);
+            bank..bank + 1
+        }
+        (None, None) => 0..disasm::bank_count(&rom),
+    };
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let result = banks.into_iter().try_for_each(|bank| {
+        let (bank_start, bank_end) = disasm::bank_addresses(bank);
+        writeln!(out, "; ROM bank {}", bank)?;
+        let (start, end) = (options.start.unwrap_or(bank_start), options.end.unwrap_or(bank_end));
+        disasm::write_listing(&mut out, &rom, bank, start, end, symbols.as_ref())
+    });
+    result.and_then(|_| out.flush()).map_err(|e| format!("Could not write the listing: {}", e))
+}
+
+/// Waits for one GDB client on the loopback interface and serves it until
+/// it detaches.
+fn serve_gdb(gameboy: &mut Gameboy, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+    let (stream, client) = listener.accept()?;
+    info!("GDB client connected from {}", client);
+    gdb::serve(gameboy, stream)
+}
+
+/// Has Ctrl-C set `flag` instead of killing the process, so the debugger
+/// can pause and the state, trace and profile still get written at the end.
+#[cfg(unix)]
+fn pause_on_ctrl_c(flag: Arc<AtomicBool>) {
+    use std::sync::OnceLock;
+    use std::sync::atomic::Ordering;
+
+    static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    extern "C" fn handle(_: libc::c_int) {
+        if let Some(flag) = FLAG.get() {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+    if FLAG.set(flag).is_ok() {
+        // Only an atomic store happens in the handler, which is safe there
+        unsafe {
+            libc::signal(libc::SIGINT, handle as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn pause_on_ctrl_c(_: Arc<AtomicBool>) {}
+
+fn init_logging() {
+    let mut builder = env_logger::Builder::new();
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    builder.init();
+}
 
 fn main() {
-    env_logger::init();
+    let mut options = match cli::parse(env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Disassemble(options)) => {
+            if let Err(message) = disassemble(&options) {
+                eprintln!("error: {}", message);
+                process::exit(EXIT_LOAD_ERROR);
+            }
+            return;
+        }
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    init_logging();
+
+    let mut gameboy = match load(&mut options) {
+        Ok(gameboy) => gameboy,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            process::exit(EXIT_LOAD_ERROR);
+        }
+    };
+    gameboy.set_illegal_opcode_mode(options.illegal_opcodes);
+    let header = gameboy.header();
+    info!("Loaded '{}' (cartridge type {:#04x}, header checksum {:#04x}) on {}",
+          header.title, header.cartridge_type, header.checksum, gameboy.model());
+
+    if let Some(port) = options.gdb_port {
+        if let Err(e) = serve_gdb(&mut gameboy, port) {
+            finish(&mut gameboy, &options);
+            eprintln!("error: GDB connection failed: {}", e);
+            process::exit(EXIT_LOAD_ERROR);
+        }
+        finish(&mut gameboy, &options);
+        return;
+    }
 
-    let mut rom_bytes: Vec<u8> = Vec::new();
-    let mut bootrom_bytes: Vec<u8> = Vec::new();
-    let mut rom = File::open("roms/tetris.gb").expect("Could not find Tetris rom");
-    rom.read_to_end(&mut rom_bytes).unwrap();
-    let mut bootrom = File::open("roms/bootrom.gb").unwrap();
-    bootrom.read_to_end(&mut bootrom_bytes).expect("Could not find bootrom");
+    if options.debug {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        let interrupt = Arc::new(AtomicBool::new(false));
+        pause_on_ctrl_c(interrupt.clone());
+        if let Err(e) = debugger::run_repl(&mut gameboy, stdin.lock(), &mut stdout.lock(), interrupt) {
+            eprintln!("error: Debugger I/O failed: {}", e);
+        }
+        finish(&mut gameboy, &options);
+        return;
+    }
 
-    let mut gameboy = Gameboy::new(rom_bytes, bootrom_bytes);
-    gameboy.run();
+    let report = match headless::run_headless(&mut gameboy, &options.run) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("error: Could not write headless output: {}", e);
+            process::exit(EXIT_LOAD_ERROR);
+        }
+    };
+    info!("Ran {} frames ({} cycles)", report.frames, report.cycles);
+    finish(&mut gameboy, &options);
+    if let Some(e) = &report.error {
+        eprintln!("error: {}", e);
+    }
+    if let CpuState::Locked { pc, opcode } = gameboy.cpu_state() {
+        eprintln!("CPU locked up on illegal opcode {:#04x} at {:#06x}", opcode, pc);
+    }
+    if let Some(e) = &report.movie_error {
+        eprintln!("error: {}", e);
+    }
+    for condition in &report.failed_conditions {
+        eprintln!("Exit condition failed: {}", condition);
+    }
+    process::exit(report.exit_code());
 }
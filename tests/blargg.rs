@@ -0,0 +1,63 @@
+mod common;
+
+use std::path::Path;
+
+use rust_boi::Model;
+
+use common::Outcome;
+
+/// Where the ROMs are looked for, unless `BLARGG_ROMS` says otherwise. The
+/// ROMs aren't distributed with the emulator, so without them the test
+/// passes without running anything.
+const DEFAULT_DIR: &str = "tests/roms/blargg";
+/// Emulated seconds a ROM gets to report before it counts as failed. The
+/// full cpu_instrs run takes about a minute.
+const TIMEOUT_SECONDS: u64 = 120;
+
+/// Runs a ROM until it says whether it passed over the serial port, or runs
+/// out of time. ROMs that report in cartridge RAM instead need an MBC, which
+/// makes them unsupported for now.
+fn run(path: &Path) -> Outcome {
+    let mut gameboy = match common::load_rom(path, Some(Model::Dmg)) {
+        Ok(gameboy) => gameboy,
+        Err(outcome) => return outcome,
+    };
+    common::run_until(&mut gameboy, TIMEOUT_SECONDS, |gameboy, _| {
+        let serial = String::from_utf8_lossy(gameboy.serial_output()).into_owned();
+        if serial.contains("Passed") {
+            Some(Outcome::Passed)
+        } else if serial.contains("Failed") {
+            Some(Outcome::Failed(serial))
+        } else {
+            None
+        }
+    })
+}
+
+#[test]
+fn blargg_roms() {
+    let dir = common::rom_dir("BLARGG_ROMS", DEFAULT_DIR);
+    let roms = common::find_roms(&dir);
+    if roms.is_empty() {
+        eprintln!("No Blargg ROMs in '{}', skipping. Set BLARGG_ROMS to where they are.", dir.display());
+        return;
+    }
+
+    let (mut failures, mut unsupported) = (0, 0);
+    for path in &roms {
+        let name = path.strip_prefix(&dir).unwrap_or(path).display();
+        let (result, details) = run(path).report();
+        match result {
+            "pass" => {}
+            "SKIP" => unsupported += 1,
+            _ => failures += 1,
+        }
+        println!("{:<8} {}", result, name);
+        for line in details.lines().filter(|line| !line.trim().is_empty()) {
+            println!("         {}", line.trim());
+        }
+    }
+    let run = roms.len() - unsupported;
+    println!("{} of {} Blargg ROMs passed, {} skipped as unsupported", run - failures, run, unsupported);
+    assert_eq!(failures, 0, "{} of {} Blargg ROMs failed", failures, run);
+}
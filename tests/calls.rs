@@ -0,0 +1,96 @@
+mod common;
+
+use rust_boi::debugger::Debugger;
+use rust_boi::{Gameboy, Profiler};
+
+/// A ROM that calls the same routine over and over:
+///
+/// ```text
+/// 0100  call $0150
+/// 0103  jr $0100
+/// 0150  nop
+/// 0151  ret
+/// ```
+fn test_rom() -> Vec<u8> {
+    common::rom_with(&[(0x100, &[0xcd, 0x50, 0x01, 0x18, 0xfb]), (0x150, &[0x00, 0xc9])])
+}
+
+/// A ROM whose routine is entered with RST and left with a conditional
+/// return:
+///
+/// ```text
+/// 0008  xor a             0100  rst $08
+/// 0009  ret nz            0101  jr $0100
+/// 000a  ret z
+/// ```
+fn rst_rom() -> Vec<u8> {
+    common::rom_with(&[(0x08, &[0xaf, 0xc0, 0xc8]), (0x100, &[0xcf, 0x18, 0xfd])])
+}
+
+fn execute(debugger: &mut Debugger, gameboy: &mut Gameboy, line: &str) -> String {
+    let mut out = Vec::new();
+    debugger.execute(gameboy, line, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn debugger_follows_the_cpu_call_stack() {
+    let mut gameboy = Gameboy::new(test_rom(), None, None).unwrap();
+    let mut debugger = Debugger::new();
+
+    execute(&mut debugger, &mut gameboy, "step");
+    assert_eq!(gameboy.registers().pc, 0x150);
+    assert_eq!(gameboy.call_depth(), 1);
+    let backtrace = execute(&mut debugger, &mut gameboy, "bt");
+    assert!(backtrace.contains("#1  0100  called 0150, returns to 0103"), "{}", backtrace);
+
+    execute(&mut debugger, &mut gameboy, "finish");
+    assert_eq!(gameboy.registers().pc, 0x103);
+    assert_eq!(gameboy.call_depth(), 0);
+
+    execute(&mut debugger, &mut gameboy, "step");
+    execute(&mut debugger, &mut gameboy, "next");
+    assert_eq!(gameboy.registers().pc, 0x103);
+}
+
+#[test]
+fn profiler_follows_the_cpu_call_stack() {
+    let mut gameboy = Gameboy::new(test_rom(), None, None).unwrap();
+    gameboy.start_profiler(Profiler::new());
+    gameboy.run_frame();
+
+    let profiler = gameboy.take_profiler().unwrap();
+    let mut collapsed = Vec::new();
+    profiler.write_collapsed(&mut collapsed, None).unwrap();
+    let collapsed = String::from_utf8(collapsed).unwrap();
+    let stacks: Vec<&str> = collapsed.lines().map(|line| line.rsplit_once(' ').unwrap().0).collect();
+    assert_eq!(stacks, ["(top level)", "(top level);00:0150"]);
+}
+
+#[test]
+fn rst_and_conditional_returns_are_tracked() {
+    let mut gameboy = Gameboy::new(rst_rom(), None, None).unwrap();
+    gameboy.check_stack(None);
+    let mut debugger = Debugger::new();
+
+    execute(&mut debugger, &mut gameboy, "step");
+    assert_eq!(gameboy.registers().pc, 0x08);
+    assert_eq!(gameboy.call_depth(), 1);
+    let backtrace = execute(&mut debugger, &mut gameboy, "bt");
+    assert!(backtrace.contains("#1  0100  called 0008, returns to 0101"), "{}", backtrace);
+
+    // RET NZ isn't taken once XOR A has set Z
+    execute(&mut debugger, &mut gameboy, "step 2");
+    assert_eq!(gameboy.registers().pc, 0x0a);
+    assert_eq!(gameboy.call_depth(), 1);
+    let cycles = gameboy.cycles();
+    execute(&mut debugger, &mut gameboy, "step");
+    assert_eq!(gameboy.registers().pc, 0x101);
+    assert_eq!(gameboy.call_depth(), 0);
+    assert_eq!(gameboy.cycles() - cycles, 20);
+
+    execute(&mut debugger, &mut gameboy, "step");
+    execute(&mut debugger, &mut gameboy, "next");
+    assert_eq!(gameboy.registers().pc, 0x101);
+    assert!(gameboy.take_stack_problems().is_empty());
+}
@@ -0,0 +1,96 @@
+// Each test binary only uses some of these helpers
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rust_boi::{EmulatorError, Gameboy, Model, StopReason};
+
+pub const CYCLES_PER_SECOND: u64 = 4_194_304;
+
+/// How running a test ROM went, with whatever it reported.
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut(String),
+    Error(String),
+    /// The ROM needs a cartridge type the emulator doesn't support yet
+    Unsupported(String),
+}
+
+impl Outcome {
+    /// The word to list the ROM under, and the details to print below it.
+    pub fn report(self) -> (&'static str, String) {
+        match self {
+            Outcome::Passed => ("pass", String::new()),
+            Outcome::Failed(details) => ("FAIL", details),
+            Outcome::TimedOut(details) => ("TIMEOUT", details),
+            Outcome::Error(e) => ("ERROR", e),
+            Outcome::Unsupported(reason) => ("SKIP", reason),
+        }
+    }
+}
+
+/// The directory named by the environment variable `var`, or `default`.
+pub fn rom_dir(var: &str, default: &str) -> PathBuf {
+    env::var_os(var).map_or_else(|| PathBuf::from(default), PathBuf::from)
+}
+
+/// Every `.gb` file under `dir`, in a stable order, or none when `dir`
+/// doesn't exist.
+pub fn find_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect(),
+        Err(_) => return roms,
+    };
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            roms.extend(find_roms(&path));
+        } else if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("gb")) {
+            roms.push(path);
+        }
+    }
+    roms
+}
+
+/// A 32KB ROM only cartridge with each `(address, code)` pair copied in
+/// and a valid header checksum.
+pub fn rom_with(code: &[(usize, &[u8])]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    for (addr, bytes) in code {
+        rom[*addr..*addr + bytes.len()].copy_from_slice(bytes);
+    }
+    rom[0x14d] = rom[0x134..0x14d].iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+    rom
+}
+
+/// Reads the ROM at `path` into a new machine, or says why it can't run.
+pub fn load_rom(path: &Path, model: Option<Model>) -> Result<Gameboy, Outcome> {
+    let rom = fs::read(path).map_err(|e| Outcome::Error(e.to_string()))?;
+    match Gameboy::new(rom, None, model) {
+        Ok(gameboy) => Ok(gameboy),
+        Err(e @ EmulatorError::UnsupportedCartridge(_)) => Err(Outcome::Unsupported(e.to_string())),
+        Err(e) => Err(Outcome::Error(e.to_string())),
+    }
+}
+
+/// Runs frames until `finished` gives an outcome for how the last one
+/// stopped, emulation fails, or `timeout_seconds` of emulated time pass.
+/// A timeout comes with the serial output so far.
+pub fn run_until<F>(gameboy: &mut Gameboy, timeout_seconds: u64, mut finished: F) -> Outcome
+    where F: FnMut(&Gameboy, &StopReason) -> Option<Outcome>
+{
+    while gameboy.cycles() < timeout_seconds * CYCLES_PER_SECOND {
+        let stop = gameboy.run_frame().stop;
+        if let StopReason::Error(e) = stop {
+            return Outcome::Error(e.to_string());
+        }
+        if let Some(outcome) = finished(gameboy, &stop) {
+            return outcome;
+        }
+    }
+    Outcome::TimedOut(String::from_utf8_lossy(gameboy.serial_output()).into_owned())
+}
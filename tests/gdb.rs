@@ -0,0 +1,119 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use rust_boi::{gdb, Gameboy};
+
+/// A ROM that calls a routine which bumps A, stores it at 0xc000 and reads
+/// it back, forever:
+///
+/// ```text
+/// 0100  call $0200        0200  call $0210        0210  inc a
+/// 0103  jr $0100          0203  ret               0211  ld [$c000], a
+///                                                 0214  ld hl, $c000
+///                                                 0217  ld a, [hl]
+///                                                 0218  ret
+/// ```
+fn test_rom() -> Vec<u8> {
+    common::rom_with(&[
+        (0x100, &[0xcd, 0x00, 0x02, 0x18, 0xfb]),
+        (0x200, &[0xcd, 0x10, 0x02, 0xc9]),
+        (0x210, &[0x3c, 0xea, 0x00, 0xc0, 0x21, 0x00, 0xc0, 0x7e, 0xc9]),
+    ])
+}
+
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(port: u16) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Client { stream, reader }
+    }
+
+    fn command(&mut self, packet: &str) -> String {
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", packet, checksum).unwrap();
+        let mut ack = [0];
+        self.reader.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], b'+', "packet '{}' wasn't acknowledged", packet);
+        self.reply()
+    }
+
+    fn reply(&mut self) -> String {
+        let mut skipped = Vec::new();
+        self.reader.read_until(b'$', &mut skipped).unwrap();
+        let mut data = Vec::new();
+        self.reader.read_until(b'#', &mut data).unwrap();
+        data.pop();
+        let mut checksum = [0; 2];
+        self.reader.read_exact(&mut checksum).unwrap();
+        let expected = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+        assert_eq!(expected, data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(data).unwrap()
+    }
+}
+
+#[test]
+fn scripted_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let mut gameboy = Gameboy::new(test_rom(), None, None).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        gdb::serve(&mut gameboy, stream)
+    });
+
+    let mut client = Client::connect(port);
+    assert!(client.command("qSupported:swbreak+;hwbreak+").contains("swbreak+"));
+    assert!(client.command("qXfer:features:read:target.xml:0,1000").contains(r#"<reg name="pc""#));
+    assert_eq!(client.command("?"), "S05");
+
+    // af bc de hl sp pc, each little endian
+    let registers = client.command("g");
+    assert_eq!(registers.len(), 24);
+    assert_eq!(&registers[16..], "feff0001");
+
+    assert_eq!(client.command("m0100,3"), "cd0002");
+    assert_eq!(client.command("Mc100,2:abcd"), "OK");
+    assert_eq!(client.command("mc100,2"), "abcd");
+    assert_eq!(client.command("P3=3412"), "OK");
+    assert_eq!(client.command("p3"), "3412");
+
+    assert_eq!(client.command("Z0,0210,1"), "OK");
+    assert_eq!(client.command("c"), "T05swbreak:;");
+    assert_eq!(client.command("p5"), "1002");
+    assert_eq!(client.command("s"), "S05");
+    assert_eq!(client.command("p5"), "1102");
+    assert_eq!(client.command("z0,0210,1"), "OK");
+
+    assert_eq!(client.command("Z1,0203,1"), "OK");
+    assert_eq!(client.command("c"), "T05hwbreak:;");
+    assert_eq!(client.command("z1,0203,1"), "OK");
+
+    assert_eq!(client.command("Z2,c000,1"), "OK");
+    assert_eq!(client.command("c"), "T05watch:c000;");
+    assert_eq!(client.command("p5"), "1402");
+    assert_eq!(client.command("z2,c000,1"), "OK");
+
+    assert_eq!(client.command("Z3,c000,1"), "OK");
+    assert_eq!(client.command("c"), "T05rwatch:c000;");
+    assert_eq!(client.command("p5"), "1802");
+    assert_eq!(client.command("z3,c000,1"), "OK");
+
+    // With nothing left to stop on, only Ctrl-C brings it back
+    write!(client.stream, "$c#63").unwrap();
+    let mut ack = [0];
+    client.reader.read_exact(&mut ack).unwrap();
+    client.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(client.reply(), "S02");
+
+    assert_eq!(client.command("D"), "OK");
+    server.join().unwrap().unwrap();
+}
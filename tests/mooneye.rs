@@ -0,0 +1,175 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rust_boi::{Gameboy, Model, Registers, StopReason};
+
+use common::Outcome;
+
+/// Where the ROMs are looked for, unless `MOONEYE_ROMS` says otherwise. The
+/// ROMs aren't distributed with the emulator, so without them the test
+/// passes without running anything.
+const DEFAULT_DIR: &str = "tests/roms/mooneye";
+/// Emulated seconds a ROM gets to finish before it counts as failed.
+const TIMEOUT_SECONDS: u64 = 20;
+/// B, C, D, E, H and L hold the start of the Fibonacci sequence when a test
+/// passes; a failing one fills them with 0x42.
+const PASS_SIGNATURE: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+/// Sorts a ROM by the directory it's in, e.g. `acceptance/timer/tima_reload.gb`
+/// comes under timer.
+fn category(name: &Path) -> &'static str {
+    let parts: Vec<String> = name.iter().map(|part| part.to_string_lossy().to_lowercase()).collect();
+    let has = |part: &str| parts.iter().any(|p| p == part);
+    if has("timer") {
+        "timer"
+    } else if has("ppu") {
+        "PPU"
+    } else if parts.iter().any(|part| part.starts_with("mbc")) {
+        "MBC"
+    } else if has("emulator-only") {
+        "emulator-only"
+    } else if has("acceptance") {
+        "acceptance"
+    } else {
+        "other"
+    }
+}
+
+/// The model a test is meant for, from the suffix of its name such as
+/// `-dmgABC`, `-cgb0` or `-GS`, where G, S and C stand for the DMG, SGB and
+/// CGB families, or `None` to go by the cartridge header.
+fn model(name: &Path) -> Option<Model> {
+    let stem = name.file_stem()?.to_string_lossy().into_owned();
+    let (_, suffix) = stem.rsplit_once('-')?;
+    if !suffix.is_empty() && suffix.chars().all(|c| matches!(c, 'G' | 'S' | 'C')) {
+        return match suffix.chars().next() {
+            Some('G') => Some(Model::Dmg),
+            Some('S') => Some(Model::Sgb),
+            _ => Some(Model::Cgb),
+        };
+    }
+    if suffix.starts_with("dmg0") {
+        Some(Model::Dmg0)
+    } else if suffix.starts_with("dmg") {
+        Some(Model::Dmg)
+    } else if suffix.starts_with("mgb") {
+        Some(Model::Mgb)
+    } else if suffix.starts_with("sgb2") {
+        Some(Model::Sgb2)
+    } else if suffix.starts_with("sgb") {
+        Some(Model::Sgb)
+    } else if suffix.starts_with("cgb") {
+        Some(Model::Cgb)
+    } else {
+        None
+    }
+}
+
+/// Runs a ROM until it executes LD B,B, which mooneye's tests do once
+/// they're done, and checks the registers for the pass signature. Every
+/// MBC test needs a cartridge type that isn't supported yet.
+fn run(path: &Path, model: Option<Model>) -> Outcome {
+    match common::load_rom(path, model) {
+        Ok(mut gameboy) => run_to_ld_b_b(&mut gameboy),
+        Err(outcome) => outcome,
+    }
+}
+
+fn run_to_ld_b_b(gameboy: &mut Gameboy) -> Outcome {
+    gameboy.set_ld_b_b_hook(|_| true);
+    common::run_until(gameboy, TIMEOUT_SECONDS, |gameboy, stop| match stop {
+        StopReason::LdBB(_) => {
+            let Registers { b, c, d, e, h, l, .. } = gameboy.registers();
+            Some(match [b, c, d, e, h, l] {
+                PASS_SIGNATURE => Outcome::Passed,
+                registers => Outcome::Failed(format!("b c d e h l = {:02x?}", registers)),
+            })
+        }
+        _ => None,
+    })
+}
+
+#[test]
+fn mooneye_roms() {
+    let dir = common::rom_dir("MOONEYE_ROMS", DEFAULT_DIR);
+    // Manual tests have to be checked by looking at the screen
+    let roms: Vec<_> = common::find_roms(&dir).into_iter()
+        .filter(|path| !path.iter().any(|part| part == "manual-only"))
+        .collect();
+    if roms.is_empty() {
+        eprintln!("No mooneye ROMs in '{}', skipping. Set MOONEYE_ROMS to where they are.", dir.display());
+        return;
+    }
+
+    // Passed, failed and skipped ROMs per category
+    let mut summary: BTreeMap<&str, [usize; 3]> = BTreeMap::new();
+    for path in &roms {
+        let name = path.strip_prefix(&dir).unwrap_or(path);
+        let (result, details) = run(path, model(name)).report();
+        let counts = summary.entry(category(name)).or_default();
+        match result {
+            "pass" => counts[0] += 1,
+            "SKIP" => counts[2] += 1,
+            _ => counts[1] += 1,
+        }
+        println!("{:<8} {}", result, name.display());
+        if !details.is_empty() {
+            println!("         {}", details);
+        }
+    }
+
+    println!("\n{:<14} {:>6} {:>6} {:>7}", "category", "passed", "failed", "skipped");
+    let mut all = [0; 3];
+    for (category, counts) in &summary {
+        println!("{:<14} {:>6} {:>6} {:>7}", category, counts[0], counts[1], counts[2]);
+        for (all, count) in all.iter_mut().zip(counts) {
+            *all += count;
+        }
+    }
+    println!("{:<14} {:>6} {:>6} {:>7}", "all", all[0], all[1], all[2]);
+    let run = roms.len() - all[2];
+    assert_eq!(all[1], 0, "{} of {} mooneye ROMs failed", all[1], run);
+}
+
+/// Signals the end of a test the way mooneye's do:
+///
+/// ```text
+/// 0100  ld bc, ..         0109  ld b, b
+/// 0103  ld de, ..         010a  jr $010a
+/// 0106  ld hl, ..
+/// ```
+fn signature_rom(registers: [u8; 6]) -> Vec<u8> {
+    let [b, c, d, e, h, l] = registers;
+    common::rom_with(&[(0x100, &[0x01, c, b, 0x11, e, d, 0x21, l, h, 0x40, 0x18, 0xfe])])
+}
+
+#[test]
+fn ld_b_b_hook_stops_the_run() {
+    let mut gameboy = Gameboy::new(signature_rom(PASS_SIGNATURE), None, None).unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let hook_seen = seen.clone();
+    gameboy.set_ld_b_b_hook(move |registers| {
+        hook_seen.lock().unwrap().push(registers.pc);
+        true
+    });
+    let summary = gameboy.run_frame();
+    assert!(matches!(summary.stop, StopReason::LdBB(0x0109)), "{:?}", summary.stop);
+    assert_eq!(gameboy.registers().pc, 0x010a);
+    assert_eq!(*seen.lock().unwrap(), [0x010a]);
+
+    // A hook that returns false lets the frame run on
+    gameboy.set_ld_b_b_hook(|_| false);
+    gameboy.set_registers(&Registers { pc: 0x0100, ..gameboy.registers() });
+    assert!(!matches!(gameboy.run_frame().stop, StopReason::LdBB(_)));
+
+    let mut gameboy = Gameboy::new(signature_rom(PASS_SIGNATURE), None, None).unwrap();
+    assert!(matches!(run_to_ld_b_b(&mut gameboy), Outcome::Passed));
+    let mut gameboy = Gameboy::new(signature_rom([0x42; 6]), None, None).unwrap();
+    match run_to_ld_b_b(&mut gameboy).report() {
+        ("FAIL", details) => assert_eq!(details, "b c d e h l = [42, 42, 42, 42, 42, 42]"),
+        (result, details) => panic!("{} {}", result, details),
+    }
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use rust_boi::{Button, Gameboy};
+
+/// A ROM that reads the action buttons into 0xc000 over and over:
+///
+/// ```text
+/// 0100  ld a, $10
+/// 0102  ldh [$00], a
+/// 0104  ldh a, [$00]
+/// 0106  ld [$c000], a
+/// 0109  jr $0100
+/// ```
+fn test_rom() -> Vec<u8> {
+    common::rom_with(&[(0x100, &[0x3e, 0x10, 0xe0, 0x00, 0xf0, 0x00, 0xea, 0x00, 0xc0, 0x18, 0xf5])])
+}
+
+#[test]
+fn rewind_replays_input() {
+    let mut gameboy = Gameboy::new(test_rom(), None, None).unwrap();
+    gameboy.enable_rewind(10, 1 << 20);
+    let mut hashes = Vec::new();
+    for frame in 0..30 {
+        // Pressed and released partway through frames the replay has to
+        // run again
+        if frame == 21 || frame == 23 {
+            gameboy.run_cycles(1000);
+            gameboy.set_button(Button::A, frame == 21);
+        }
+        gameboy.run_frame();
+        hashes.push((gameboy.frames(), gameboy.state_hash(), gameboy.read_memory(0xc000)));
+    }
+
+    let (frames, hash, _) = hashes[hashes.len() - 1];
+    assert_eq!(gameboy.rewind(8).unwrap(), 8);
+    let expected = hashes.iter().find(|(at, _, _)| *at == frames - 8).unwrap();
+    assert_eq!((gameboy.frames(), gameboy.state_hash(), gameboy.read_memory(0xc000)), *expected);
+    assert_ne!(hash, expected.1);
+    // A is held at the frame rewound to
+    assert_eq!(gameboy.read_memory(0xc000) & 0x01, 0);
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use rust_boi::{Gameboy, StopReason, WatchHit, WatchKind, Watchpoint};
+
+/// A ROM that calls a routine in bank 1 which bumps the byte at 0xc000 and
+/// writes it back a second time, forever:
+///
+/// ```text
+/// 0100  call $4000        4000  ld hl, $c000
+/// 0103  jr $0100          4003  ld a, [hl]
+///                         4004  inc a
+///                         4005  ld [hl], a
+///                         4006  ld [hl], a
+///                         4007  ret
+/// ```
+fn gameboy() -> Gameboy {
+    let rom = common::rom_with(&[
+        (0x100, &[0xcd, 0x00, 0x40, 0x18, 0xfb]),
+        (0x4000, &[0x21, 0x00, 0xc0, 0x7e, 0x3c, 0x77, 0x77, 0xc9]),
+    ]);
+    let mut gameboy = Gameboy::new(rom, None, None).unwrap();
+    gameboy.write_memory(0xc000, 0x10);
+    gameboy
+}
+
+fn watch(kind: WatchKind, value: Option<u8>) -> Watchpoint {
+    Watchpoint { addresses: 0xc000..=0xc000, kind, value }
+}
+
+/// Runs until the next watchpoint hit, which has to come well within a frame.
+fn next_hit(gameboy: &mut Gameboy) -> WatchHit {
+    match gameboy.run_cycles(10_000).stop {
+        StopReason::Watchpoint(hit) => hit,
+        stop => panic!("{:?}", stop),
+    }
+}
+
+fn hit(kind: WatchKind, pc: u16, old: u8, new: u8) -> WatchHit {
+    WatchHit { kind, addr: 0xc000, old, new, pc, bank: 1 }
+}
+
+#[test]
+fn runs_stop_after_the_instruction_that_hits() {
+    let mut gameboy = gameboy();
+    gameboy.add_watchpoint(watch(WatchKind::Read, None));
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Read, 0x4003, 0x10, 0x10));
+    assert_eq!(gameboy.registers().pc, 0x4004);
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Read, 0x4003, 0x11, 0x11));
+}
+
+#[test]
+fn change_watchpoints_skip_writes_of_the_same_value() {
+    let mut gameboy = gameboy();
+    gameboy.add_watchpoint(watch(WatchKind::Change, None));
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Write, 0x4005, 0x10, 0x11));
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Write, 0x4005, 0x11, 0x12));
+
+    let mut gameboy = self::gameboy();
+    gameboy.add_watchpoint(watch(WatchKind::Write, None));
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Write, 0x4005, 0x10, 0x11));
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Write, 0x4006, 0x11, 0x11));
+}
+
+#[test]
+fn values_narrow_down_hits() {
+    let mut gameboy = gameboy();
+    gameboy.add_watchpoint(watch(WatchKind::Write, Some(0x13)));
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Write, 0x4005, 0x12, 0x13));
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Write, 0x4006, 0x13, 0x13));
+    assert!(gameboy.remove_watchpoint(&watch(WatchKind::Write, Some(0x13))));
+    assert!(!matches!(gameboy.run_cycles(10_000).stop, StopReason::Watchpoint(_)));
+}
+
+#[test]
+fn peeks_and_pokes_dont_hit() {
+    let mut gameboy = gameboy();
+    gameboy.add_watchpoint(watch(WatchKind::Read, None));
+    gameboy.add_watchpoint(watch(WatchKind::Write, None));
+    assert_eq!(gameboy.read_memory(0xc000), 0x10);
+    gameboy.write_memory(0xc000, 0x20);
+    assert_eq!(gameboy.take_watch_hit(), None);
+    assert_eq!(next_hit(&mut gameboy), hit(WatchKind::Read, 0x4003, 0x20, 0x20));
+}